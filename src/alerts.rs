@@ -0,0 +1,107 @@
+use crate::config::alert_webhook_url;
+use crate::error::Error;
+use crate::processing::anomaly::Anomaly;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something an operator should hear about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Alert {
+    DeviceStale {
+        serial_number: String,
+        location: String,
+        last_heartbeat: Option<DateTime<Utc>>,
+    },
+    DeviceRecovered {
+        serial_number: String,
+        location: String,
+        last_heartbeat: DateTime<Utc>,
+    },
+    Anomaly(Anomaly),
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::DeviceStale {
+                serial_number,
+                location,
+                last_heartbeat: Some(last_heartbeat),
+            } => write!(
+                f,
+                "Device {serial_number} at {location} has not sent a heartbeat since {last_heartbeat}"
+            ),
+            Alert::DeviceStale {
+                serial_number,
+                location,
+                last_heartbeat: None,
+            } => write!(
+                f,
+                "Device {serial_number} at {location} has never sent a heartbeat"
+            ),
+            Alert::DeviceRecovered {
+                serial_number,
+                location,
+                ..
+            } => write!(f, "Device {serial_number} at {location} is sending heartbeats again"),
+            Alert::Anomaly(anomaly) => write!(
+                f,
+                "{:?} {:?} anomaly at {}: {} servings in the bucket from {}, against a usual {}",
+                anomaly.severity,
+                anomaly.granularity,
+                anomaly.location,
+                anomaly.count,
+                anomaly.local_start,
+                anomaly.baseline
+            ),
+        }
+    }
+}
+
+/// The alert with a `text` line, which chat webhooks such as Slack's display.
+#[derive(Serialize)]
+struct Payload<'a> {
+    text: String,
+    #[serde(flatten)]
+    alert: &'a Alert,
+}
+
+/// Posts alerts as JSON to the URL in `ALERT_WEBHOOK_URL`.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        Ok(Webhook { client, url })
+    }
+
+    /// The configured webhook, `None` if alerts are off.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        alert_webhook_url().map(Webhook::new).transpose()
+    }
+
+    pub async fn notify(&self, alert: &Alert) -> Result<(), Error> {
+        let payload = Payload {
+            text: alert.to_string(),
+            alert,
+        };
+        self.client
+            .post(&self.url)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
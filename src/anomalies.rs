@@ -0,0 +1,104 @@
+use crate::alerts::{Alert, Webhook};
+use crate::config::{anomaly_settings, location_hours};
+use crate::error::Error;
+use crate::firestore::anomalies::{fetch_anomalies, save_anomaly};
+use crate::firestore::client::fetch_readings_since;
+use crate::processing::anomaly::{detect_anomalies, served_series, Granularity};
+use chrono::{Duration, Utc};
+use firestore::FirestoreDb;
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Buckets this far back are scored again on every run, so readings that
+/// arrive late still count before a bucket is judged for good.
+const DETECTION_WINDOW: Duration = Duration::days(2);
+
+#[derive(Debug, Default, Serialize)]
+pub struct AnomalyReport {
+    pub detected: usize,
+    /// Anomalies not recorded by an earlier run.
+    pub new: usize,
+    /// Recorded anomalies that are no longer far enough from the baseline.
+    pub resolved: usize,
+    pub alerts_sent: usize,
+    pub alerts_failed: usize,
+}
+
+/// Scores the recent hourly and daily `Served` counts of every location against
+/// their seasonal baseline, records anomalies and posts them to the configured
+/// webhook until one is delivered. Anomalies recorded earlier are updated with
+/// the new score, or resolved when they are not detected again.
+#[tracing::instrument(name = "anomaly_detection", skip_all)]
+pub async fn detect(db: &FirestoreDb) -> Result<AnomalyReport, Error> {
+    let settings = anomaly_settings();
+    let hours = location_hours();
+    let webhook = Webhook::from_env()?;
+    let now = Utc::now();
+    let since = now - DETECTION_WINDOW;
+    // A day extra for buckets that start earlier in local time than in UTC
+    let history = since - Duration::weeks(settings.baseline_weeks as i64) - Duration::days(1);
+    let served = fetch_readings_since(db, &Action::Served, history)
+        .await?
+        .into_iter()
+        .map(LibraData::from)
+        .collect::<Vec<_>>();
+
+    // Daily buckets scored again can start up to a day before `since`
+    let mut stored = fetch_anomalies(db, since - Duration::days(1))
+        .await?
+        .into_iter()
+        .map(|anomaly| (anomaly.key(), anomaly))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = AnomalyReport::default();
+    for granularity in [Granularity::Hourly, Granularity::Daily] {
+        let series = served_series(&served, &hours, granularity);
+        for mut anomaly in detect_anomalies(&series, &hours, granularity, settings, since, now) {
+            report.detected += 1;
+            let previous = stored.remove(&anomaly.key());
+            match &previous {
+                Some(previous) => anomaly.alerted = previous.alerted,
+                None => {
+                    report.new += 1;
+                    tracing::warn!("{}", Alert::Anomaly(anomaly.clone()));
+                }
+            }
+            if let Some(webhook) = webhook.as_ref().filter(|_| !anomaly.alerted) {
+                match webhook.notify(&Alert::Anomaly(anomaly.clone())).await {
+                    Ok(()) => {
+                        anomaly.alerted = true;
+                        report.alerts_sent += 1;
+                    }
+                    Err(e) => {
+                        tracing::error!(error = ?e, "Failed to send alert, retrying on the next run");
+                        report.alerts_failed += 1;
+                    }
+                }
+            }
+            if previous.as_ref() != Some(&anomaly) {
+                save_anomaly(db, &anomaly).await?;
+            }
+        }
+    }
+
+    // Buckets wholly inside the window were all scored again
+    for mut anomaly in stored
+        .into_values()
+        .filter(|anomaly| !anomaly.resolved && anomaly.start >= since)
+    {
+        anomaly.resolved = true;
+        save_anomaly(db, &anomaly).await?;
+        report.resolved += 1;
+    }
+    tracing::info!(
+        detected = report.detected,
+        new = report.new,
+        resolved = report.resolved,
+        alerts_sent = report.alerts_sent,
+        alerts_failed = report.alerts_failed,
+        "Checked served volume for anomalies"
+    );
+    Ok(report)
+}
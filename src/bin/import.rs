@@ -0,0 +1,112 @@
+use chrono_tz::Tz;
+use data_aggregation::config::ingest_max_future_skew;
+use data_aggregation::error::Error;
+use data_aggregation::import::{import, read_rows, ColumnMapping, Format, ImportOptions};
+use data_aggregation::migrate::MAX_BATCH_SIZE;
+use data_aggregation::telemetry;
+use dotenv::dotenv;
+use firestore::FirestoreDb;
+use std::env;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: import [--dry-run] [--format csv|jsonl] [--timezone TZ] [--column FIELD=COLUMN]... [--batch-size N] FILE
+
+Imports historical readings from a CSV or JSON-lines file into libra and backfills
+the aggregates for them.
+
+  --format        defaults to the file extension
+  --timezone      IANA time zone of timestamps without a UTC offset, defaults to UTC
+  --column        reads a field from a differently named column; fields are
+                  serialNumber, model, location, ingredient, dataAction, amount and timestamp";
+
+struct Args {
+    path: PathBuf,
+    format: Format,
+    options: ImportOptions,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut format = None;
+    let mut options = ImportOptions {
+        mapping: ColumnMapping::default(),
+        timezone: Tz::UTC,
+        batch_size: MAX_BATCH_SIZE,
+        dry_run: false,
+        max_future_skew: ingest_max_future_skew(),
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{arg} takes a value"));
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--format" => format = Some(value()?.parse()?),
+            "--timezone" => {
+                let timezone = value()?;
+                options.timezone = timezone
+                    .parse()
+                    .map_err(|_| format!("Unknown time zone {timezone}"))?;
+            }
+            "--column" => options.mapping.set(&value()?)?,
+            "--batch-size" => {
+                options.batch_size = value()?
+                    .parse()
+                    .ok()
+                    .filter(|size| (1..=MAX_BATCH_SIZE).contains(size))
+                    .ok_or(format!(
+                        "--batch-size takes a number from 1 to {MAX_BATCH_SIZE}"
+                    ))?;
+            }
+            other if other.starts_with("--") => return Err(format!("Unknown argument {other}")),
+            file => path = Some(PathBuf::from(file)),
+        }
+    }
+    let path = path.ok_or("Missing FILE")?;
+    let format = format
+        .or_else(|| Format::from_path(&path))
+        .ok_or("Cannot tell the format from the file extension, pass --format")?;
+    Ok(Args {
+        path,
+        format,
+        options,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Error> {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Ok(ExitCode::from(2));
+        }
+    };
+
+    dotenv().ok();
+    let telemetry = telemetry::init()?;
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let rows = read_rows(args.format, File::open(&args.path)?)?;
+    let project = env::var("GOOGLE_CLOUD_PROJECT")?;
+    let db = FirestoreDb::new(&project).await?;
+    let report = import(&db, rows, &args.options).await?;
+    for rejected in &report.rejected {
+        tracing::warn!(line = rejected.line, error = %rejected.error, "Rejected row");
+    }
+    tracing::info!(
+        dry_run = args.options.dry_run,
+        rows = report.rows,
+        imported = report.imported,
+        duplicates = report.duplicates,
+        rejected = report.rejected.len(),
+        backfilled = report.backfilled,
+        "Import finished"
+    );
+    telemetry.shutdown();
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,72 @@
+use data_aggregation::error::Error;
+use data_aggregation::migrate::{migrate_libra, MAX_BATCH_SIZE};
+use data_aggregation::telemetry;
+use dotenv::dotenv;
+use firestore::FirestoreDb;
+use std::env;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: migrate [--dry-run] [--batch-size N]
+
+Rewrites libra documents written in older schema versions to the current layout.";
+
+struct Args {
+    dry_run: bool,
+    batch_size: usize,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        dry_run: false,
+        batch_size: MAX_BATCH_SIZE,
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--dry-run" => args.dry_run = true,
+            "--batch-size" => {
+                args.batch_size = argv
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|size| (1..=MAX_BATCH_SIZE).contains(size))
+                    .ok_or(format!(
+                        "--batch-size takes a number from 1 to {MAX_BATCH_SIZE}"
+                    ))?;
+            }
+            other => return Err(format!("Unknown argument {other}")),
+        }
+    }
+    Ok(args)
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Error> {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Ok(ExitCode::from(2));
+        }
+    };
+
+    dotenv().ok();
+    let telemetry = telemetry::init()?;
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let project = env::var("GOOGLE_CLOUD_PROJECT")?;
+    let db = FirestoreDb::new(&project).await?;
+    let report = migrate_libra(&db, args.batch_size, args.dry_run).await?;
+    tracing::info!(
+        dry_run = args.dry_run,
+        scanned = report.scanned,
+        current = report.current,
+        migrated = report.migrated,
+        invalid = report.invalid,
+        "Migration finished"
+    );
+    telemetry.shutdown();
+
+    Ok(ExitCode::SUCCESS)
+}
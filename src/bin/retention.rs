@@ -0,0 +1,51 @@
+use data_aggregation::config::{archive_path, retention_policy};
+use data_aggregation::error::Error;
+use data_aggregation::retention::apply_retention;
+use data_aggregation::telemetry;
+use dotenv::dotenv;
+use firestore::FirestoreDb;
+use std::env;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: retention [--dry-run]
+
+Archives libra readings older than their action's retention (RETENTION_DAYS) to
+gzipped JSON lines under ARCHIVE_PATH, then deletes them. Readings newer than the
+aggregation watermark are always kept.";
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Error> {
+    let mut dry_run = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            other => {
+                eprintln!("Unknown argument {other}\n\n{USAGE}");
+                return Ok(ExitCode::from(2));
+            }
+        }
+    }
+
+    dotenv().ok();
+    let telemetry = telemetry::init()?;
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let project = env::var("GOOGLE_CLOUD_PROJECT")?;
+    let db = FirestoreDb::new(&project).await?;
+    let report = apply_retention(&db, &retention_policy(), &archive_path(), dry_run).await?;
+    for expired in &report {
+        tracing::info!(
+            dry_run,
+            action = ?expired.action,
+            cutoff = %expired.cutoff,
+            expired = expired.expired,
+            archives = expired.archives.len(),
+            "Applied retention"
+        );
+    }
+    telemetry.shutdown();
+
+    Ok(ExitCode::SUCCESS)
+}
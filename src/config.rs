@@ -1,8 +1,221 @@
 use crate::error::Error;
+use crate::processing::anomaly::DetectionSettings;
+use crate::processing::compliance::PortionTargets;
+use crate::processing::hours::LocationHours;
+use crate::processing::portion::PortionBins;
+use crate::retention::RetentionPolicy;
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READINESS_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_INGEST_MAX_BATCH: usize = 500;
+const DEFAULT_INGEST_MAX_FUTURE_SECS: i64 = 300;
+const DEFAULT_RETENTION_DAYS: &str = "Heartbeat=30,Served=730";
+const DEFAULT_ARCHIVE_PATH: &str = "archive";
+const DEFAULT_CLOCK_SKEW_THRESHOLD_SECS: i64 = 300;
+const DEFAULT_INVENTORY_LOW_FRACTION: f64 = 0.2;
+const DEFAULT_FORECAST_LOOKBACK_DAYS: i64 = 14;
+const DEFAULT_HEARTBEAT_TIMEOUT_SECS: i64 = 900;
+const DEFAULT_STALE_DEVICE_THRESHOLD_SECS: i64 = 1800;
+const DEFAULT_DEVICE_CHECK_INTERVAL_SECS: u64 = 300;
+const DEFAULT_ANOMALY_BASELINE_WEEKS: usize = 4;
+const DEFAULT_ANOMALY_THRESHOLD: f64 = 3.5;
+const DEFAULT_PORTION_BINS: &str = "25,50,75,100,150,200,300";
 
 pub fn config_env_vars() -> Result<(String, String), Error> {
     let project_id = env::var("PROJECT_ID")?;
     let collection_name = env::var("COLLECTION_NAME")?;
     Ok((project_id, collection_name))
 }
+
+/// How long in-flight requests get to finish after SIGTERM, from `SHUTDOWN_TIMEOUT_SECS`.
+/// Cloud Run sends SIGKILL 10 seconds after SIGTERM, so keep this at or below that.
+pub fn shutdown_timeout() -> Duration {
+    let secs = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Base URL of the OTLP/HTTP collector traces are exported to, from the standard
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`. Trace export is off when unset.
+pub fn otlp_endpoint() -> Option<String> {
+    env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// How long `/ready` waits for Firestore before reporting it unavailable, from `READINESS_TIMEOUT_MS`.
+pub fn readiness_timeout() -> Duration {
+    let millis = env::var("READINESS_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_READINESS_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+/// Most readings accepted by one `/ingest` request, from `INGEST_MAX_BATCH`.
+pub fn ingest_max_batch() -> usize {
+    env::var("INGEST_MAX_BATCH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INGEST_MAX_BATCH)
+}
+
+/// How far ahead of the server clock a reading's timestamp may be before it is
+/// taken to come from a fast device clock, from `INGEST_MAX_FUTURE_SECS`. Such
+/// readings are corrected by aggregation runs when `CLOCK_SKEW_CORRECTION` is on,
+/// or rejected when imported without an arrival time.
+pub fn ingest_max_future_skew() -> chrono::Duration {
+    let secs = env::var("INGEST_MAX_FUTURE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INGEST_MAX_FUTURE_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+/// How long readings of each action are kept, from `RETENTION_DAYS` as
+/// `Action=days,...`. Falls back to the default if it does not parse.
+pub fn retention_policy() -> RetentionPolicy {
+    env::var("RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| DEFAULT_RETENTION_DAYS.parse().unwrap())
+}
+
+/// Directory expired readings are archived to before deletion, from `ARCHIVE_PATH`.
+/// Point it at a mounted Cloud Storage bucket to archive to a bucket.
+pub fn archive_path() -> PathBuf {
+    env::var("ARCHIVE_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or(DEFAULT_ARCHIVE_PATH.to_string())
+        .into()
+}
+
+/// How far a device clock may be off before the device is flagged as skewed, from
+/// `CLOCK_SKEW_THRESHOLD_SECS`.
+pub fn clock_skew_threshold() -> chrono::Duration {
+    let secs = env::var("CLOCK_SKEW_THRESHOLD_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CLOCK_SKEW_THRESHOLD_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+/// Whether aggregation runs shift timestamps of skewed devices by their clock
+/// offset, from `CLOCK_SKEW_CORRECTION`. Off by default.
+pub fn clock_skew_correction() -> bool {
+    env::var("CLOCK_SKEW_CORRECTION")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(false)
+}
+
+/// Share of the last refill at or below which an ingredient counts as running low,
+/// from `INVENTORY_LOW_FRACTION`.
+pub fn inventory_low_fraction() -> f64 {
+    env::var("INVENTORY_LOW_FRACTION")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INVENTORY_LOW_FRACTION)
+}
+
+/// How far back run-out forecasts look for consumption, from `FORECAST_LOOKBACK_DAYS`.
+pub fn forecast_lookback() -> chrono::Duration {
+    let days = env::var("FORECAST_LOOKBACK_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_FORECAST_LOOKBACK_DAYS);
+    chrono::Duration::days(days)
+}
+
+/// How long a device may go without sending anything before it counts as
+/// offline, from `HEARTBEAT_TIMEOUT_SECS`.
+pub fn heartbeat_timeout() -> chrono::Duration {
+    let secs = env::var("HEARTBEAT_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+/// How long a registered device may go without a heartbeat before it is marked
+/// stale, from `STALE_DEVICE_THRESHOLD_SECS`.
+pub fn stale_device_threshold() -> chrono::Duration {
+    let secs = env::var("STALE_DEVICE_THRESHOLD_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_STALE_DEVICE_THRESHOLD_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+/// How often the service checks for stale devices on its own, from
+/// `DEVICE_CHECK_INTERVAL_SECS`. `0` turns the checks off, leaving them to
+/// aggregation jobs and `POST /devices/check`.
+pub fn device_check_interval() -> Option<Duration> {
+    let secs = env::var("DEVICE_CHECK_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_DEVICE_CHECK_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// URL alerts are posted to, from `ALERT_WEBHOOK_URL`. Alerts are off when unset.
+pub fn alert_webhook_url() -> Option<String> {
+    env::var("ALERT_WEBHOOK_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+/// Timezone and opening hours of each location, from `LOCATION_HOURS` as
+/// `Location=Timezone HH:MM-HH:MM;...`. Locations not listed are always open and
+/// report in UTC, as are all of them if it does not parse.
+pub fn location_hours() -> LocationHours {
+    env::var("LOCATION_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// How served volume anomalies are scored, from `ANOMALY_BASELINE_WEEKS` and
+/// `ANOMALY_THRESHOLD`.
+pub fn anomaly_settings() -> DetectionSettings {
+    DetectionSettings {
+        baseline_weeks: env::var("ANOMALY_BASELINE_WEEKS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|weeks| *weeks > 0)
+            .unwrap_or(DEFAULT_ANOMALY_BASELINE_WEEKS),
+        threshold: env::var("ANOMALY_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|threshold| *threshold > 0.0)
+            .unwrap_or(DEFAULT_ANOMALY_THRESHOLD),
+    }
+}
+
+/// Upper edges in grams of the portion histogram bins, from `PORTION_BINS` as a
+/// comma separated list. Falls back to the default if it does not parse.
+pub fn portion_bins() -> PortionBins {
+    env::var("PORTION_BINS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| DEFAULT_PORTION_BINS.parse().unwrap())
+}
+
+/// Target portion and tolerance per ingredient, optionally per location, from
+/// `PORTION_TARGETS` as `Ingredient[@Location]=grams:tolerance;...`. No servings
+/// are checked if it is unset or does not parse.
+pub fn portion_targets() -> PortionTargets {
+    env::var("PORTION_TARGETS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,198 @@
+use crate::alerts::{Alert, Webhook};
+use crate::config::stale_device_threshold;
+use crate::error::Error;
+use crate::firestore::client::{fetch_last_heartbeat, read_locations};
+use crate::firestore::devices::{fetch_device_statuses, save_device_status, DeviceStatus};
+use crate::shutdown::Shutdown;
+use chrono::{DateTime, Duration, Utc};
+use firestore::FirestoreDb;
+use serde::Serialize;
+
+#[derive(Debug, Default, Serialize)]
+pub struct StaleCheckReport {
+    pub devices: usize,
+    pub stale: usize,
+    pub alerts_sent: usize,
+    /// Alerts the webhook did not accept. The devices keep their previous
+    /// status so the next check tries again.
+    pub alerts_failed: usize,
+}
+
+/// A device's status given its last heartbeat, and the alert to send if it
+/// went stale or recovered since the `previous` check.
+pub fn evaluate(
+    previous: Option<&DeviceStatus>,
+    serial_number: &str,
+    location: &str,
+    last_heartbeat: Option<DateTime<Utc>>,
+    threshold: Duration,
+    now: DateTime<Utc>,
+) -> (DeviceStatus, Option<Alert>) {
+    let stale = last_heartbeat.is_none_or(|heartbeat| now - heartbeat > threshold);
+    let was_stale = previous.is_some_and(|status| status.stale);
+    let alert = match (stale, was_stale, last_heartbeat) {
+        (true, false, _) => Some(Alert::DeviceStale {
+            serial_number: serial_number.to_string(),
+            location: location.to_string(),
+            last_heartbeat,
+        }),
+        (false, true, Some(last_heartbeat)) => Some(Alert::DeviceRecovered {
+            serial_number: serial_number.to_string(),
+            location: location.to_string(),
+            last_heartbeat,
+        }),
+        _ => None,
+    };
+    let status = DeviceStatus {
+        serial_number: serial_number.to_string(),
+        location: location.to_string(),
+        last_heartbeat,
+        stale,
+        checked_at: now,
+    };
+    (status, alert)
+}
+
+/// Compares every device registered in `locations` against its last heartbeat,
+/// records which are stale and posts an alert to `webhook` for each device that
+/// went stale or recovered.
+#[tracing::instrument(name = "stale_device_check", skip_all)]
+pub async fn check_stale_devices(
+    db: &FirestoreDb,
+    threshold: Duration,
+    webhook: Option<&Webhook>,
+) -> Result<StaleCheckReport, Error> {
+    let previous = fetch_device_statuses(db).await?;
+    let mut report = StaleCheckReport::default();
+    for registered in read_locations(db).await? {
+        let serial_number = registered.device.serial_number.as_str();
+        let last_heartbeat = fetch_last_heartbeat(db, serial_number).await?;
+        let (status, alert) = evaluate(
+            previous.get(serial_number),
+            serial_number,
+            &registered.location,
+            last_heartbeat,
+            threshold,
+            Utc::now(),
+        );
+        report.devices += 1;
+        if status.stale {
+            report.stale += 1;
+        }
+        if let Some(alert) = alert {
+            tracing::warn!(serial_number, "{alert}");
+            if let Some(webhook) = webhook {
+                if let Err(e) = webhook.notify(&alert).await {
+                    tracing::error!(error = ?e, serial_number, "Failed to send alert");
+                    report.alerts_failed += 1;
+                    continue;
+                }
+                report.alerts_sent += 1;
+            }
+        }
+        save_device_status(db, &status).await?;
+    }
+    Ok(report)
+}
+
+/// Runs the stale device check with the configured threshold and webhook.
+pub async fn check_devices(db: &FirestoreDb) -> Result<StaleCheckReport, Error> {
+    let webhook = Webhook::from_env()?;
+    let report = check_stale_devices(db, stale_device_threshold(), webhook.as_ref()).await?;
+    tracing::info!(
+        devices = report.devices,
+        stale = report.stale,
+        alerts_sent = report.alerts_sent,
+        alerts_failed = report.alerts_failed,
+        "Checked for stale devices"
+    );
+    Ok(report)
+}
+
+/// Runs the stale device check every `interval` until shutdown, so devices that
+/// go quiet between aggregation jobs are noticed without an outside scheduler.
+pub async fn check_devices_every(
+    db: FirestoreDb,
+    interval: std::time::Duration,
+    shutdown: Shutdown,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick is immediate, and jobs check on startup anyway
+    ticks.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.triggered() => return,
+        }
+        if let Err(e) = check_devices(&db).await {
+            tracing::error!(error = ?e, "Stale device check failed");
+        }
+    }
+}
+
+/// Statuses from the last check, optionally only stale or only live devices.
+pub async fn device_statuses(
+    db: &FirestoreDb,
+    stale: Option<bool>,
+) -> Result<Vec<DeviceStatus>, Error> {
+    let mut statuses = fetch_device_statuses(db)
+        .await?
+        .into_values()
+        .filter(|status| stale.is_none_or(|stale| status.stale == stale))
+        .collect::<Vec<_>>();
+    statuses.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(
+        previous: Option<&DeviceStatus>,
+        last_heartbeat: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> (DeviceStatus, Option<Alert>) {
+        evaluate(
+            previous,
+            "Lib298190",
+            "Caldo Office",
+            last_heartbeat,
+            Duration::minutes(30),
+            now,
+        )
+    }
+
+    #[test]
+    fn it_alerts_once_when_a_device_goes_stale() {
+        let now = Utc::now();
+        let heartbeat = Some(now - Duration::minutes(10));
+        let (live, alert) = check(None, heartbeat, now);
+        assert!(!live.stale);
+        assert_eq!(alert, None);
+
+        let later = now + Duration::hours(1);
+        let (stale, alert) = check(Some(&live), heartbeat, later);
+        assert!(stale.stale);
+        assert!(matches!(alert, Some(Alert::DeviceStale { .. })));
+
+        // Still stale: nothing new to say
+        let (_, alert) = check(Some(&stale), heartbeat, later + Duration::hours(1));
+        assert_eq!(alert, None);
+
+        let (recovered, alert) = check(Some(&stale), Some(later), later);
+        assert!(!recovered.stale);
+        assert!(matches!(alert, Some(Alert::DeviceRecovered { .. })));
+    }
+
+    #[test]
+    fn it_flags_devices_that_never_sent_a_heartbeat() {
+        let (status, alert) = check(None, None, Utc::now());
+        assert!(status.stale);
+        assert_eq!(
+            alert.map(|alert| alert.to_string()),
+            Some("Device Lib298190 at Caldo Office has never sent a heartbeat".to_string())
+        );
+    }
+}
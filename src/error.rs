@@ -1,5 +1,6 @@
 use std::env;
 
+use chrono::{DateTime, Utc};
 use firestore::errors::FirestoreError;
 use thiserror::Error;
 use warp::reject::Reject;
@@ -14,5 +15,28 @@ pub enum Error {
     JsonError(#[from] serde_json::Error),
     #[error("Data does not meet query requirements")]
     QueryError,
+    #[error("Failed to encode metrics")]
+    MetricsError(#[from] prometheus::Error),
+    #[error("Failed to set up trace export")]
+    TelemetryError(#[from] opentelemetry_otlp::ExporterBuildError),
+    #[error("Service is shutting down")]
+    ShuttingDown,
+    #[error("Document is not a valid reading: {0}")]
+    InvalidDocument(String),
+    #[error("Batch of {size} readings exceeds the limit of {limit}")]
+    BatchTooLarge { size: usize, limit: usize },
+    #[error("Aggregation lease is held by {owner} until {expires_at}")]
+    LeaseHeld {
+        owner: String,
+        expires_at: DateTime<Utc>,
+    },
+    #[error("Failed to read import file")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to read CSV")]
+    CsvError(#[from] csv::Error),
+    #[error("Failed to deliver alert")]
+    WebhookError(#[from] reqwest::Error),
+    #[error("Aggregation lease of {owner} could not be renewed")]
+    LeaseLost { owner: String },
 }
 impl Reject for Error {}
@@ -0,0 +1,56 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::anomaly::{Anomaly, Granularity};
+use chrono::{DateTime, Utc};
+use firestore::{FirestoreDb, FirestoreQueryDirection, FirestoreTimestamp};
+
+const ANOMALIES_COLLECTION: &str = "anomalies";
+
+/// One anomaly per location, granularity and bucket, however often it is detected.
+fn document_id(anomaly: &Anomaly) -> String {
+    let granularity = match anomaly.granularity {
+        Granularity::Hourly => "hourly",
+        Granularity::Daily => "daily",
+    };
+    hashed_id(&[
+        &anomaly.location,
+        granularity,
+        &anomaly.start.timestamp().to_string(),
+    ])
+}
+
+/// Records an anomaly, replacing what was stored for its bucket.
+pub async fn save_anomaly(db: &FirestoreDb, anomaly: &Anomaly) -> Result<(), Error> {
+    observe_firestore(
+        "save_anomaly",
+        db.fluent()
+            .update()
+            .in_col(ANOMALIES_COLLECTION)
+            .document_id(document_id(anomaly))
+            .object(anomaly)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Anomalies in buckets starting at `since` or later, newest first.
+pub async fn fetch_anomalies(
+    db: &FirestoreDb,
+    since: DateTime<Utc>,
+) -> Result<Vec<Anomaly>, Error> {
+    let since = FirestoreTimestamp::from(since);
+    let anomalies = observe_firestore(
+        "fetch_anomalies",
+        db.fluent()
+            .select()
+            .from(ANOMALIES_COLLECTION)
+            .filter(|q| q.field("start").greater_than_or_equal(since.clone()))
+            .order_by([("start", FirestoreQueryDirection::Descending)])
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(anomalies)
+}
@@ -1,17 +1,48 @@
+use crate::config::{
+    clock_skew_correction, clock_skew_threshold, heartbeat_timeout, ingest_max_future_skew,
+    location_hours, portion_bins, portion_targets,
+};
 use crate::error::Error;
+use crate::firestore::clocks::update_device_clocks;
+use crate::firestore::compliance::{fetch_compliance_days, write_compliance};
+use crate::firestore::heatmaps::{fetch_heatmaps, write_heatmaps};
+use crate::firestore::inventory::{fetch_inventory, write_inventory};
+use crate::firestore::lease::LeaseGuard;
 use crate::firestore::metadata::{fetch_metadata, update_metadata, LastProcessed, Metadata};
+use crate::firestore::portions::{fetch_portions, write_portions};
+use crate::firestore::quarantine::{parse_or_quarantine, parse_or_skip};
+use crate::firestore::rollups::{fetch_rollups_since, write_rollups};
+use crate::firestore::schema::CURRENT_SCHEMA_VERSION;
+use crate::firestore::stockouts::{fetch_open_stockouts, write_stockouts};
+use crate::firestore::uptime::{fetch_device_states, fetch_uptime_days, write_uptime};
+use crate::metrics::{observe_firestore, record_aggregation_success, record_duplicates_dropped};
 use crate::processing::action::{aggregate_actions, ActionAggregates};
 use crate::processing::category::aggregate_by_category;
+use crate::processing::compliance::aggregate_compliance;
+use crate::processing::dedup::{content_id, dedup};
+use crate::processing::heatmap::aggregate_heatmap;
+use crate::processing::inventory::aggregate_inventory;
+use crate::processing::portion::aggregate_portions;
+use crate::processing::rollup::{aggregate_rollups, earliest_local_date};
+use crate::processing::skew::{
+    correct_future_timestamps, correct_timestamps, estimate_offsets, is_skewed,
+};
+use crate::processing::stockout::pair_stockouts;
 use crate::processing::time::{aggregate_daily, aggregate_hourly};
+use crate::processing::uptime::{aggregate_uptime, MAX_LOOKBACK};
+use crate::shutdown::Shutdown;
 use chrono::{DateTime, Utc};
 use firestore::errors::FirestoreError;
 use firestore::FirestoreTimestamp;
 use firestore::*;
 use menu::{action::Action, libra_data::LibraData};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
 use time::Date;
 use time::OffsetDateTime;
+use tokio::sync::watch;
+use tracing::{info_span, Span};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FirestoreLibraData {
@@ -23,6 +54,21 @@ pub struct FirestoreLibraData {
     pub amount: f64,
     #[serde(with = "firestore::serialize_as_timestamp")]
     pub timestamp: DateTime<Utc>,
+    #[serde(rename = "schemaVersion", default = "current_schema_version")]
+    pub schema_version: u64,
+    /// Server time the reading arrived, unlike `timestamp` which comes from the
+    /// device clock. Missing on readings stored before it was recorded and on
+    /// imported ones.
+    #[serde(
+        rename = "receivedAt",
+        default,
+        with = "firestore::serialize_as_optional_timestamp"
+    )]
+    pub received_at: Option<DateTime<Utc>>,
+}
+
+fn current_schema_version() -> u64 {
+    CURRENT_SCHEMA_VERSION
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,10 +95,33 @@ impl From<LibraData> for FirestoreLibraData {
             data_action: data.data_action,
             amount: data.amount,
             timestamp: chrono_timestamp,
+            schema_version: CURRENT_SCHEMA_VERSION,
+            received_at: None,
         }
     }
 }
 
+impl FirestoreLibraData {
+    /// Document id derived from the reading's content, so storing the same
+    /// reading twice hits the same document.
+    pub fn content_id(&self) -> String {
+        content_id(
+            &self.device.serial_number,
+            &self.data_action,
+            &self.ingredient,
+            self.amount,
+            self.timestamp.timestamp_micros(),
+        )
+    }
+
+    /// Whether a regular aggregation run still fetches this reading once the
+    /// watermark is at `watermark`. Runs go by arrival time where it is known,
+    /// so device clocks that are off cannot make them skip or re-read readings.
+    pub fn is_after_watermark(&self, watermark: DateTime<Utc>) -> bool {
+        self.received_at.unwrap_or(self.timestamp) > watermark
+    }
+}
+
 impl From<FirestoreLibraData> for LibraData {
     fn from(data: FirestoreLibraData) -> Self {
         // Convert chrono::DateTime<Utc> to time::OffsetDateTime
@@ -75,74 +144,267 @@ impl From<FirestoreLibraData> for LibraData {
     }
 }
 
-async fn fetch_all_entries(db: &FirestoreDb) -> Result<Vec<LibraData>, FirestoreError> {
-    let firestore_entries: Vec<FirestoreLibraData> =
-        db.fluent().select().from("libra").obj().query().await?;
-    Ok(firestore_entries.into_iter().map(LibraData::from).collect())
+/// How far an aggregation run has got, published while it runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregationProgress {
+    pub entries_fetched: usize,
+    #[serde(default)]
+    pub duplicates_dropped: usize,
+    #[serde(default)]
+    pub quarantined: usize,
+    #[serde(default)]
+    pub timestamps_corrected: usize,
+    pub entries_processed: usize,
 }
 
+#[derive(Deserialize)]
+struct EntryTimestamp {
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct EntryArrival {
+    #[serde(rename = "receivedAt", with = "firestore::serialize_as_timestamp")]
+    received_at: DateTime<Utc>,
+}
+
+/// Entries fetched for a run, and how many invalid documents were quarantined instead.
+struct FetchedEntries {
+    entries: Vec<FirestoreLibraData>,
+    quarantined: usize,
+}
+
+async fn parse_entries(db: &FirestoreDb, documents: Vec<Value>) -> FetchedEntries {
+    let (entries, quarantined) = parse_or_quarantine(db, documents, "libra").await;
+    FetchedEntries {
+        entries,
+        quarantined,
+    }
+}
+
+/// Every reading up to `until`, the watermark the run will leave behind.
+async fn fetch_all_entries(
+    db: &FirestoreDb,
+    until: DateTime<Utc>,
+) -> Result<FetchedEntries, Error> {
+    let documents: Vec<Value> = observe_firestore(
+        "fetch_all_entries",
+        db.fluent().select().from("libra").obj().query(),
+    )
+    .await?;
+    let mut fetched = parse_entries(db, documents).await;
+    fetched
+        .entries
+        .retain(|entry| !entry.is_after_watermark(until));
+    Ok(fetched)
+}
+
+/// Readings after the watermark up to `until`, the watermark the run will leave
+/// behind, so readings arriving during the run are left for the next one.
 async fn fetch_new_entries(
     db: &FirestoreDb,
     last_processed: LastProcessed,
-) -> Result<Vec<LibraData>, FirestoreError> {
-    let timestamp = FirestoreTimestamp::from(last_processed.timestamp);
-    let firestore_entries: Vec<FirestoreLibraData> = db
-        .fluent()
-        .select()
-        .from("libra")
-        .filter(|q| q.field("timestamp").greater_than(timestamp.clone()))
-        .obj()
-        .query()
-        .await?;
-    Ok(firestore_entries.into_iter().map(LibraData::from).collect())
+    until: DateTime<Utc>,
+) -> Result<FetchedEntries, Error> {
+    let watermark = FirestoreTimestamp::from(last_processed.timestamp);
+    let until = FirestoreTimestamp::from(until);
+    let mut documents: Vec<Value> = observe_firestore(
+        "fetch_new_entries",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("receivedAt").greater_than(watermark.clone()),
+                    q.field("receivedAt").less_than_or_equal(until.clone()),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    // Readings without an arrival time can only go by the device clock. Capping
+    // it too means one dated in the future is fetched once its time has come,
+    // rather than by every run until then
+    let by_device_clock: Vec<Value> = observe_firestore(
+        "fetch_new_entries_by_timestamp",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("timestamp").greater_than(watermark.clone()),
+                    q.field("timestamp").less_than_or_equal(until.clone()),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    documents.extend(
+        by_device_clock
+            .into_iter()
+            .filter(|document| document.get("receivedAt").is_none_or(Value::is_null)),
+    );
+    Ok(parse_entries(db, documents).await)
+}
+
+/// Readings of one action since `since` by device time, for analyses that look
+/// back over a period rather than at what is new.
+pub async fn fetch_readings_since(
+    db: &FirestoreDb,
+    action: &Action,
+    since: DateTime<Utc>,
+) -> Result<Vec<FirestoreLibraData>, Error> {
+    let since = FirestoreTimestamp::from(since);
+    let documents: Vec<Value> = observe_firestore(
+        "fetch_readings_since",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("dataAction").eq(action.clone()),
+                    q.field("timestamp").greater_than_or_equal(since.clone()),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    let (entries, skipped) = parse_or_skip(documents);
+    if skipped > 0 {
+        tracing::warn!(skipped, "Skipped invalid readings");
+    }
+    Ok(entries)
+}
+
+/// When the newest `Heartbeat` from a device arrived, if it ever sent one. Goes
+/// by device time only for devices whose heartbeats were all stored before
+/// arrival times were, as a device clock that is off would make the device look
+/// stale or alive when it is not.
+pub async fn fetch_last_heartbeat(
+    db: &FirestoreDb,
+    serial_number: &str,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    // Documents without `receivedAt` are left out of a query ordered by it
+    let arrived: Vec<EntryArrival> = observe_firestore(
+        "fetch_last_heartbeat",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("device.serialNumber").eq(serial_number),
+                    q.field("dataAction").eq(Action::Heartbeat),
+                ])
+            })
+            .order_by([("receivedAt", FirestoreQueryDirection::Descending)])
+            .limit(1)
+            .obj()
+            .query(),
+    )
+    .await?;
+    if let Some(entry) = arrived.into_iter().next() {
+        return Ok(Some(entry.received_at));
+    }
+
+    let latest: Vec<EntryTimestamp> = observe_firestore(
+        "fetch_last_heartbeat_by_timestamp",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("device.serialNumber").eq(serial_number),
+                    q.field("dataAction").eq(Action::Heartbeat),
+                ])
+            })
+            .order_by([("timestamp", FirestoreQueryDirection::Descending)])
+            .limit(1)
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(latest.into_iter().next().map(|entry| entry.timestamp))
+}
+
+/// Timestamp of the newest reading in `libra`, if there is any.
+pub async fn fetch_latest_entry_timestamp(
+    db: &FirestoreDb,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    let latest: Vec<EntryTimestamp> = observe_firestore(
+        "fetch_latest_entry",
+        db.fluent()
+            .select()
+            .from("libra")
+            .order_by([("timestamp", FirestoreQueryDirection::Descending)])
+            .limit(1)
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(latest.into_iter().next().map(|entry| entry.timestamp))
 }
 
 async fn write_by_category(
     db: &FirestoreDb,
     aggregates: &HashMap<String, usize>,
 ) -> Result<(), Error> {
-    db.fluent()
-        .update()
-        .in_col("aggregates")
-        .document_id("categories")
-        .object(aggregates)
-        .execute::<()>()
-        .await?;
+    observe_firestore(
+        "write_by_category",
+        db.fluent()
+            .update()
+            .in_col("aggregates")
+            .document_id("categories")
+            .object(aggregates)
+            .execute::<()>(),
+    )
+    .await?;
 
     Ok(())
 }
 
-
 async fn fetch_by_category(
     db: &FirestoreDb,
 ) -> Result<Option<HashMap<String, usize>>, FirestoreError> {
-    db.fluent()
-        .select()
-        .by_id_in("aggregates")
-        .obj::<HashMap<String, usize>>()
-        .one("category")
-        .await
+    observe_firestore(
+        "fetch_by_category",
+        db.fluent()
+            .select()
+            .by_id_in("aggregates")
+            .obj::<HashMap<String, usize>>()
+            .one("category"),
+    )
+    .await
 }
 
 async fn write_by_action(db: &FirestoreDb, aggregates: &ActionAggregates) -> Result<(), Error> {
-    db.fluent()
-        .update()
-        .in_col("aggregates")
-        .document_id("actions")
-        .object(aggregates)
-        .execute::<()>()
-        .await?;
+    observe_firestore(
+        "write_by_action",
+        db.fluent()
+            .update()
+            .in_col("aggregates")
+            .document_id("actions")
+            .object(aggregates)
+            .execute::<()>(),
+    )
+    .await?;
     Ok(())
 }
 
 async fn write_by_hour(db: &FirestoreDb, aggregates: &HashMap<u8, usize>) -> Result<(), Error> {
-    db.fluent()
-        .update()
-        .in_col("aggregates")
-        .document_id("hourly")
-        .object(aggregates)
-        .execute::<()>()
-        .await?;
+    observe_firestore(
+        "write_by_hour",
+        db.fluent()
+            .update()
+            .in_col("aggregates")
+            .document_id("hourly")
+            .object(aggregates)
+            .execute::<()>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -150,83 +412,294 @@ async fn write_by_hour(db: &FirestoreDb, aggregates: &HashMap<u8, usize>) -> Res
 async fn fetch_hourly_aggregates(
     db: &FirestoreDb,
 ) -> Result<Option<HashMap<u8, usize>>, FirestoreError> {
-    db.fluent()
-        .select()
-        .by_id_in("aggregates")
-        .obj::<HashMap<u8, usize>>()
-        .one("hourly")
-        .await
+    observe_firestore(
+        "fetch_hourly_aggregates",
+        db.fluent()
+            .select()
+            .by_id_in("aggregates")
+            .obj::<HashMap<u8, usize>>()
+            .one("hourly"),
+    )
+    .await
 }
 
 async fn write_by_date(db: &FirestoreDb, aggregates: &HashMap<Date, usize>) -> Result<(), Error> {
-    db.fluent()
-        .update()
-        .in_col("aggregates")
-        .document_id("daily")
-        .object(aggregates)
-        .execute::<()>()
-        .await?;
+    observe_firestore(
+        "write_by_date",
+        db.fluent()
+            .update()
+            .in_col("aggregates")
+            .document_id("daily")
+            .object(aggregates)
+            .execute::<()>(),
+    )
+    .await?;
     Ok(())
 }
 
-
 async fn fetch_daily_aggregates(
     db: &FirestoreDb,
 ) -> Result<Option<HashMap<Date, usize>>, FirestoreError> {
-    db.fluent()
-        .select()
-        .by_id_in("aggregates")
-        .obj::<HashMap<Date, usize>>()
-        .one("hourly")
-        .await
+    observe_firestore(
+        "fetch_daily_aggregates",
+        db.fluent()
+            .select()
+            .by_id_in("aggregates")
+            .obj::<HashMap<Date, usize>>()
+            .one("hourly"),
+    )
+    .await
 }
 
-
-pub async fn process_aggregations(db: &FirestoreDb) -> Result<(), Error> {
-    let (entries, last_aggregate) = match fetch_metadata(db).await? {
+#[tracing::instrument(
+    name = "aggregation_run",
+    skip_all,
+    fields(
+        run_id = %uuid::Uuid::new_v4(),
+        entries_processed = tracing::field::Empty,
+        duplicates_dropped = tracing::field::Empty,
+        locations = tracing::field::Empty,
+    )
+)]
+pub async fn process_aggregations(
+    db: &FirestoreDb,
+    lease: &LeaseGuard,
+    shutdown: &Shutdown,
+    progress: &watch::Sender<AggregationProgress>,
+) -> Result<AggregationProgress, Error> {
+    // The watermark this run leaves behind. Readings that arrive while it runs
+    // are after it, so the next run picks them up
+    let started = Utc::now();
+    let (fetched, last_aggregate) = match fetch_metadata(db).await? {
         Some(metadata) => (
-            fetch_new_entries(db, metadata.last_processed).await?,
+            fetch_new_entries(db, metadata.last_processed, started).await?,
             metadata.last_aggregate,
         ),
-        None => (fetch_all_entries(db).await?, ActionAggregates::new()),
+        None => (
+            fetch_all_entries(db, started).await?,
+            ActionAggregates::new(),
+        ),
     };
+    let FetchedEntries {
+        mut entries,
+        quarantined,
+    } = fetched;
+
+    tracing::info!(
+        entries = entries.len(),
+        quarantined,
+        "Fetched entries for processing"
+    );
+    let fetched = entries.len();
 
-    println!("Fetched {} entries for processing", entries.len());
+    let corrected = if entries.is_empty() {
+        0
+    } else {
+        check_device_clocks(db, &mut entries).await?
+    };
+    let entries = entries.into_iter().map(LibraData::from).collect();
+
+    // Devices retry uploads, and readings stored before ids were derived from
+    // content can appear more than once
+    let (entries, duplicates) = info_span!("dedup").in_scope(|| dedup(entries));
+    if duplicates > 0 {
+        tracing::warn!(duplicates, "Dropped duplicate readings");
+    }
+    record_duplicates_dropped(duplicates);
+    progress.send_modify(|progress| {
+        progress.entries_fetched = fetched;
+        progress.duplicates_dropped = duplicates;
+        progress.quarantined = quarantined;
+        progress.timestamps_corrected = corrected;
+    });
+    let locations = entries
+        .iter()
+        .map(|entry| entry.location.as_str())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>()
+        .join(",");
+    Span::current()
+        .record("entries_processed", entries.len())
+        .record("duplicates_dropped", duplicates)
+        .record("locations", locations.as_str());
 
     if entries.is_empty() {
-        println!("No entries to process");
-        return Ok(());
+        tracing::info!("No entries to process");
+        record_aggregation_success(0);
+        return Ok(*progress.borrow());
     }
 
-    let action_aggregates = aggregate_actions(entries.as_slice(), &last_aggregate);
+    // Last safe point to stop: neither the aggregates nor the metadata have been
+    // written yet, so the next run picks these entries up again from the
+    // unchanged metadata. Invalid documents have already moved to quarantine and
+    // clock estimates have been saved, but the next run no longer sees the
+    // former and saves the same estimates again
+    if shutdown.is_triggered() {
+        tracing::warn!("Shutdown requested, abandoning aggregation before writing");
+        return Err(Error::ShuttingDown);
+    }
+    lease.renew(db).await?;
+
+    let action_aggregates = write_aggregates(db, &entries, &last_aggregate).await?;
+    write_device_aggregates(db, &entries).await?;
+    progress.send_modify(|progress| progress.entries_processed = entries.len());
+
+    // Update the last processed timestamp
+    let metadata = Metadata {
+        last_processed: LastProcessed { timestamp: started },
+        last_aggregate: action_aggregates.clone(),
+    };
+    update_metadata(db, &metadata).await?;
+    tracing::info!("Updated last processed timestamp");
+    record_aggregation_success(entries.len());
+
+    Ok(*progress.borrow())
+}
+
+/// Updates the per-device clock offsets from a run's readings and, if correction
+/// is on, shifts the timestamps of skewed devices. Returns how many were shifted.
+async fn check_device_clocks(
+    db: &FirestoreDb,
+    entries: &mut [FirestoreLibraData],
+) -> Result<usize, Error> {
+    let threshold = clock_skew_threshold();
+    let offsets = estimate_offsets(entries);
+    for (serial_number, (offset_ms, samples)) in &offsets {
+        if is_skewed(*offset_ms, threshold) {
+            tracing::warn!(serial_number, offset_ms, samples, "Device clock is off");
+        }
+    }
+    let offsets = update_device_clocks(db, offsets, Utc::now()).await?;
+    let mut corrected = 0;
+    if clock_skew_correction() {
+        corrected += correct_timestamps(entries, &offsets, threshold);
+        corrected += correct_future_timestamps(entries, ingest_max_future_skew());
+    }
+    if corrected > 0 {
+        tracing::info!(corrected, "Corrected timestamps of skewed devices");
+    }
+    Ok(corrected)
+}
+
+/// Folds `entries` into the stored aggregates that do not depend on the order
+/// readings arrive in and writes them back, returning the new action totals for
+/// the metadata.
+async fn write_aggregates(
+    db: &FirestoreDb,
+    entries: &[LibraData],
+    last_aggregate: &ActionAggregates,
+) -> Result<ActionAggregates, Error> {
+    let action_aggregates = info_span!("aggregate", aggregator = "actions")
+        .in_scope(|| aggregate_actions(entries, last_aggregate));
     write_by_action(db, &action_aggregates).await?;
 
     if let Some(agg) = fetch_hourly_aggregates(db).await? {
-        let hourly_aggregates = aggregate_hourly(&entries, Action::Served, &agg);
+        let hourly_aggregates = info_span!("aggregate", aggregator = "hourly")
+            .in_scope(|| aggregate_hourly(entries, Action::Served, &agg));
         write_by_hour(db, &hourly_aggregates).await?;
     }
 
     if let Some(agg) = fetch_daily_aggregates(db).await? {
-        let daily_aggregates = aggregate_daily(&entries, Action::Served, &agg);
+        let daily_aggregates = info_span!("aggregate", aggregator = "daily")
+            .in_scope(|| aggregate_daily(entries, Action::Served, &agg));
         write_by_date(db, &daily_aggregates).await?;
     }
 
     if let Some(agg) = fetch_by_category(db).await? {
-        let category_aggregates = aggregate_by_category(&entries, &agg);
+        let category_aggregates = info_span!("aggregate", aggregator = "category")
+            .in_scope(|| aggregate_by_category(entries, &agg));
         write_by_category(db, &category_aggregates).await?;
     }
 
-    // Update the last processed timestamp
+    let hours = location_hours();
+    let past_heatmaps = fetch_heatmaps(db).await?;
+    let heatmaps = info_span!("aggregate", aggregator = "heatmap")
+        .in_scope(|| aggregate_heatmap(entries, &hours, &past_heatmaps));
+    write_heatmaps(db, &heatmaps).await?;
+
+    if let Some(since) = earliest_local_date(entries, &hours) {
+        let past_rollups = fetch_rollups_since(db, since).await?;
+        let rollups = info_span!("aggregate", aggregator = "rollups")
+            .in_scope(|| aggregate_rollups(entries, &hours, &past_rollups));
+        write_rollups(db, &rollups).await?;
+
+        let targets = portion_targets();
+        let past_days = fetch_compliance_days(db, since, None).await?;
+        let days = info_span!("aggregate", aggregator = "compliance")
+            .in_scope(|| aggregate_compliance(entries, &targets, &hours, &past_days));
+        write_compliance(db, &days).await?;
+    }
+
+    let past_portions = fetch_portions(db).await?;
+    let portions = info_span!("aggregate", aggregator = "portions")
+        .in_scope(|| aggregate_portions(entries, &portion_bins(), &past_portions));
+    write_portions(db, &portions).await?;
+    Ok(action_aggregates)
+}
+
+/// Folds `entries` into the aggregates that carry each scale's or device's
+/// state on from the last run: inventory levels, open stockouts and uptime
+/// intervals. Readings from before that state would be applied on top of it as
+/// if they were newer, so only regular runs fold into these.
+async fn write_device_aggregates(db: &FirestoreDb, entries: &[LibraData]) -> Result<(), Error> {
+    let past_levels = fetch_inventory(db).await?;
+    let levels = info_span!("aggregate", aggregator = "inventory")
+        .in_scope(|| aggregate_inventory(entries, &past_levels));
+    write_inventory(db, &levels).await?;
+
+    let open_stockouts = fetch_open_stockouts(db).await?;
+    let stockouts = info_span!("aggregate", aggregator = "stockouts")
+        .in_scope(|| pair_stockouts(entries, &open_stockouts, &past_levels));
+    write_stockouts(db, &stockouts).await?;
+
+    let now = Utc::now();
+    let past_states = fetch_device_states(db).await?;
+    let past_days = match past_states.values().map(|state| state.counted_until).min() {
+        Some(since) => {
+            let since = since.max(now - MAX_LOOKBACK);
+            fetch_uptime_days(db, since.date_naive(), None).await?
+        }
+        None => HashMap::new(),
+    };
+    let (states, days) = info_span!("aggregate", aggregator = "uptime")
+        .in_scope(|| aggregate_uptime(entries, &past_states, &past_days, heartbeat_timeout(), now));
+    write_uptime(db, &states, &days).await?;
+    Ok(())
+}
+
+/// Folds readings that regular runs no longer fetch into the aggregates. Runs
+/// only fetch readings after the watermark, so readings added to `libra` late,
+/// e.g. imported or replayed from quarantine, would otherwise never be counted.
+/// Readings after the watermark are left for the next run. Inventory, stockouts
+/// and uptime are left alone, as they continue from the devices' latest state.
+pub async fn aggregate_entries(
+    db: &FirestoreDb,
+    lease: &LeaseGuard,
+    entries: Vec<FirestoreLibraData>,
+) -> Result<usize, Error> {
+    let Some(metadata) = fetch_metadata(db).await? else {
+        // The first run fetches everything
+        return Ok(0);
+    };
+    let watermark = metadata.last_processed.timestamp;
+    let entries = entries
+        .into_iter()
+        .filter(|entry| !entry.is_after_watermark(watermark))
+        .map(LibraData::from)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    lease.renew(db).await?;
+    let last_aggregate = write_aggregates(db, &entries, &metadata.last_aggregate).await?;
     let metadata = Metadata {
-        last_processed: LastProcessed {
-            timestamp: Utc::now(),
-        },
-        last_aggregate: action_aggregates.clone(),
+        last_processed: metadata.last_processed,
+        last_aggregate,
     };
     update_metadata(db, &metadata).await?;
-    println!("Updated last processed timestamp");
-
-    Ok(())
+    Ok(entries.len())
 }
 
 pub async fn read_locations(db: &FirestoreDb) -> Result<Vec<LocationData>, Error> {
@@ -234,8 +707,11 @@ pub async fn read_locations(db: &FirestoreDb) -> Result<Vec<LocationData>, Error
 }
 
 async fn fetch_location_entries(db: &FirestoreDb) -> Result<Vec<LocationData>, Error> {
-    let firestore_data: Vec<LocationData> =
-        db.fluent().select().from("locations").obj().query().await?;
+    let firestore_data: Vec<LocationData> = observe_firestore(
+        "fetch_location_entries",
+        db.fluent().select().from("locations").obj().query(),
+    )
+    .await?;
     Ok(firestore_data)
 }
 #[derive(Deserialize, Serialize, Debug)]
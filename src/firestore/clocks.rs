@@ -0,0 +1,95 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::skew::is_skewed;
+use chrono::{DateTime, Duration, Utc};
+use firestore::FirestoreDb;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const CLOCKS_COLLECTION: &str = "device_clocks";
+
+/// Latest estimate of how far a device's clock is behind server time; negative
+/// when it runs ahead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceClock {
+    pub serial_number: String,
+    pub offset_ms: i64,
+    /// Readings the estimate is based on.
+    pub samples: usize,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl DeviceClock {
+    pub fn is_skewed(&self, threshold: Duration) -> bool {
+        is_skewed(self.offset_ms, threshold)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceSkew {
+    #[serde(flatten)]
+    pub clock: DeviceClock,
+    pub skewed: bool,
+}
+
+pub async fn save_device_clock(db: &FirestoreDb, clock: &DeviceClock) -> Result<(), Error> {
+    observe_firestore(
+        "save_device_clock",
+        db.fluent()
+            .update()
+            .in_col(CLOCKS_COLLECTION)
+            .document_id(hashed_id(&[&clock.serial_number]))
+            .object(clock)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn fetch_device_clocks(db: &FirestoreDb) -> Result<Vec<DeviceClock>, Error> {
+    let clocks = observe_firestore(
+        "fetch_device_clocks",
+        db.fluent().select().from(CLOCKS_COLLECTION).obj().query(),
+    )
+    .await?;
+    Ok(clocks)
+}
+
+/// Replaces the stored estimates of devices in `offsets` and returns every
+/// device's current offset.
+pub async fn update_device_clocks(
+    db: &FirestoreDb,
+    offsets: HashMap<String, (i64, usize)>,
+    now: DateTime<Utc>,
+) -> Result<HashMap<String, i64>, Error> {
+    let mut current = fetch_device_clocks(db)
+        .await?
+        .into_iter()
+        .map(|clock| (clock.serial_number, clock.offset_ms))
+        .collect::<HashMap<_, _>>();
+    for (serial_number, (offset_ms, samples)) in offsets {
+        let clock = DeviceClock {
+            serial_number,
+            offset_ms,
+            samples,
+            updated_at: now,
+        };
+        save_device_clock(db, &clock).await?;
+        current.insert(clock.serial_number, offset_ms);
+    }
+    Ok(current)
+}
+
+/// Every device's clock estimate, flagged if it is off by more than `threshold`.
+pub async fn device_skew(db: &FirestoreDb, threshold: Duration) -> Result<Vec<DeviceSkew>, Error> {
+    Ok(fetch_device_clocks(db)
+        .await?
+        .into_iter()
+        .map(|clock| DeviceSkew {
+            skewed: clock.is_skewed(threshold),
+            clock,
+        })
+        .collect())
+}
@@ -0,0 +1,57 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::compliance::{ComplianceKey, DailyCompliance};
+use chrono::NaiveDate;
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const COMPLIANCE_COLLECTION: &str = "portion_compliance";
+
+fn document_id((location, ingredient, date): &ComplianceKey) -> String {
+    format!("{}_{date}", hashed_id(&[location, ingredient]))
+}
+
+/// Daily compliance of every location and ingredient from `since` through
+/// `until`.
+pub async fn fetch_compliance_days(
+    db: &FirestoreDb,
+    since: NaiveDate,
+    until: Option<NaiveDate>,
+) -> Result<HashMap<ComplianceKey, DailyCompliance>, Error> {
+    let days: Vec<DailyCompliance> = observe_firestore(
+        "fetch_compliance_days",
+        db.fluent()
+            .select()
+            .from(COMPLIANCE_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field("date").greater_than_or_equal(since.to_string()),
+                    until.and_then(|until| q.field("date").less_than_or_equal(until.to_string())),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(days.into_iter().map(|day| (day.key(), day)).collect())
+}
+
+pub async fn write_compliance(
+    db: &FirestoreDb,
+    days: &HashMap<ComplianceKey, DailyCompliance>,
+) -> Result<(), Error> {
+    for (key, day) in days {
+        observe_firestore(
+            "write_compliance",
+            db.fluent()
+                .update()
+                .in_col(COMPLIANCE_COLLECTION)
+                .document_id(document_id(key))
+                .object(day)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
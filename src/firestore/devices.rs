@@ -0,0 +1,49 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use chrono::{DateTime, Utc};
+use firestore::FirestoreDb;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const STATUS_COLLECTION: &str = "device_status";
+
+/// Whether a registered device is still sending heartbeats, as of the last check.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub serial_number: String,
+    pub location: String,
+    #[serde(default, with = "firestore::serialize_as_optional_timestamp")]
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    pub stale: bool,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub checked_at: DateTime<Utc>,
+}
+
+pub async fn fetch_device_statuses(
+    db: &FirestoreDb,
+) -> Result<HashMap<String, DeviceStatus>, Error> {
+    let statuses: Vec<DeviceStatus> = observe_firestore(
+        "fetch_device_statuses",
+        db.fluent().select().from(STATUS_COLLECTION).obj().query(),
+    )
+    .await?;
+    Ok(statuses
+        .into_iter()
+        .map(|status| (status.serial_number.clone(), status))
+        .collect())
+}
+
+pub async fn save_device_status(db: &FirestoreDb, status: &DeviceStatus) -> Result<(), Error> {
+    observe_firestore(
+        "save_device_status",
+        db.fluent()
+            .update()
+            .in_col(STATUS_COLLECTION)
+            .document_id(hashed_id(&[&status.serial_number]))
+            .object(status)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
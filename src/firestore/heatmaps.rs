@@ -0,0 +1,46 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::heatmap::{Heatmap, HeatmapKey};
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const HEATMAPS_COLLECTION: &str = "heatmaps";
+
+fn document_id((location, ingredient): &HeatmapKey) -> String {
+    hashed_id(&[location, ingredient])
+}
+
+pub async fn fetch_heatmaps(db: &FirestoreDb) -> Result<HashMap<HeatmapKey, Heatmap>, Error> {
+    let heatmaps: Vec<Heatmap> = observe_firestore(
+        "fetch_heatmaps",
+        db.fluent().select().from(HEATMAPS_COLLECTION).obj().query(),
+    )
+    .await?;
+    Ok(heatmaps
+        .into_iter()
+        .map(|heatmap| {
+            let key = (heatmap.location.clone(), heatmap.ingredient.clone());
+            (key, heatmap)
+        })
+        .collect())
+}
+
+pub async fn write_heatmaps(
+    db: &FirestoreDb,
+    heatmaps: &HashMap<HeatmapKey, Heatmap>,
+) -> Result<(), Error> {
+    for (key, heatmap) in heatmaps {
+        observe_firestore(
+            "write_heatmap",
+            db.fluent()
+                .update()
+                .in_col(HEATMAPS_COLLECTION)
+                .document_id(document_id(key))
+                .object(heatmap)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::inventory::{InventoryKey, StockLevel};
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const INVENTORY_COLLECTION: &str = "inventory";
+
+fn document_id((serial_number, ingredient): &InventoryKey) -> String {
+    hashed_id(&[serial_number, ingredient])
+}
+
+pub async fn fetch_inventory(db: &FirestoreDb) -> Result<HashMap<InventoryKey, StockLevel>, Error> {
+    let levels: Vec<StockLevel> = observe_firestore(
+        "fetch_inventory",
+        db.fluent()
+            .select()
+            .from(INVENTORY_COLLECTION)
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(levels
+        .into_iter()
+        .map(|level| {
+            let key = (level.serial_number.clone(), level.ingredient.clone());
+            (key, level)
+        })
+        .collect())
+}
+
+pub async fn write_inventory(
+    db: &FirestoreDb,
+    levels: &HashMap<InventoryKey, StockLevel>,
+) -> Result<(), Error> {
+    for (key, level) in levels {
+        observe_firestore(
+            "write_inventory",
+            db.fluent()
+                .update()
+                .in_col(INVENTORY_COLLECTION)
+                .document_id(document_id(key))
+                .object(level)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use crate::error::Error;
+use crate::firestore::client::AggregationProgress;
+use crate::metrics::observe_firestore;
+use chrono::{DateTime, Duration, Utc};
+use firestore::FirestoreDb;
+use serde::{Deserialize, Serialize};
+
+const JOBS_COLLECTION: &str = "aggregation_jobs";
+
+/// A running job refreshes `updated_at` at least this often.
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Unfinished jobs without a heartbeat for this long belong to an instance that died.
+const STALE_AFTER: Duration = Duration::seconds(120);
+const INTERRUPTED: &str = "Interrupted before completion, the service was restarted";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub state: JobState,
+    pub progress: AggregationProgress,
+    pub error: Option<String>,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "firestore::serialize_as_optional_timestamp")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "firestore::serialize_as_optional_timestamp")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub updated_at: DateTime<Utc>,
+    pub duration_ms: Option<i64>,
+}
+
+impl Job {
+    pub fn queued(id: String, now: DateTime<Utc>) -> Self {
+        Job {
+            id,
+            state: JobState::Queued,
+            progress: AggregationProgress::default(),
+            error: None,
+            created_at: now,
+            started_at: None,
+            finished_at: None,
+            updated_at: now,
+            duration_ms: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Succeeded | JobState::Failed)
+    }
+
+    pub fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        !self.is_finished() && now - self.updated_at > STALE_AFTER
+    }
+
+    pub fn start(&mut self, now: DateTime<Utc>) {
+        self.state = JobState::Running;
+        self.started_at = Some(now);
+        self.updated_at = now;
+    }
+
+    pub fn record_progress(&mut self, progress: AggregationProgress, now: DateTime<Utc>) {
+        self.progress = progress;
+        self.updated_at = now;
+    }
+
+    pub fn succeed(&mut self, progress: AggregationProgress, now: DateTime<Utc>) {
+        self.progress = progress;
+        self.finish(JobState::Succeeded, now);
+    }
+
+    pub fn fail(&mut self, error: String, now: DateTime<Utc>) {
+        self.error = Some(error);
+        self.finish(JobState::Failed, now);
+    }
+
+    pub fn interrupt(&mut self, now: DateTime<Utc>) {
+        self.fail(INTERRUPTED.to_string(), now);
+    }
+
+    fn finish(&mut self, state: JobState, now: DateTime<Utc>) {
+        self.state = state;
+        self.finished_at = Some(now);
+        self.updated_at = now;
+        self.duration_ms = self
+            .started_at
+            .map(|started_at| (now - started_at).num_milliseconds());
+    }
+}
+
+pub async fn insert_job(db: &FirestoreDb, job: &Job) -> Result<(), Error> {
+    observe_firestore(
+        "insert_job",
+        db.fluent()
+            .insert()
+            .into(JOBS_COLLECTION)
+            .document_id(&job.id)
+            .object(job)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn update_job(db: &FirestoreDb, job: &Job) -> Result<(), Error> {
+    observe_firestore(
+        "update_job",
+        db.fluent()
+            .update()
+            .in_col(JOBS_COLLECTION)
+            .document_id(&job.id)
+            .object(job)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn fetch_job(db: &FirestoreDb, id: &str) -> Result<Option<Job>, Error> {
+    let job = observe_firestore(
+        "fetch_job",
+        db.fluent()
+            .select()
+            .by_id_in(JOBS_COLLECTION)
+            .obj::<Job>()
+            .one(id),
+    )
+    .await?;
+    Ok(job)
+}
+
+pub async fn fetch_unfinished_jobs(db: &FirestoreDb) -> Result<Vec<Job>, Error> {
+    let jobs = observe_firestore(
+        "fetch_unfinished_jobs",
+        db.fluent()
+            .select()
+            .from(JOBS_COLLECTION)
+            .filter(|q| {
+                q.for_any([
+                    q.field("state").eq(JobState::Queued),
+                    q.field("state").eq(JobState::Running),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_a_successful_run() {
+        let created = Utc::now();
+        let mut job = Job::queued("job-1".to_string(), created);
+        job.start(created + Duration::seconds(1));
+        job.record_progress(
+            AggregationProgress {
+                entries_fetched: 10,
+                ..Default::default()
+            },
+            created + Duration::seconds(2),
+        );
+        job.succeed(
+            AggregationProgress {
+                entries_fetched: 10,
+                entries_processed: 10,
+                ..Default::default()
+            },
+            created + Duration::seconds(5),
+        );
+
+        assert_eq!(job.state, JobState::Succeeded);
+        assert!(job.is_finished());
+        assert_eq!(job.progress.entries_processed, 10);
+        assert_eq!(job.duration_ms, Some(4000));
+        assert_eq!(job.error, None);
+    }
+
+    #[test]
+    fn it_keeps_progress_and_error_when_a_run_fails() {
+        let created = Utc::now();
+        let mut job = Job::queued("job-2".to_string(), created);
+        job.start(created);
+        job.record_progress(
+            AggregationProgress {
+                entries_fetched: 3,
+                ..Default::default()
+            },
+            created,
+        );
+        job.fail("Service is shutting down".to_string(), created);
+
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(job.progress.entries_fetched, 3);
+        assert_eq!(job.error.as_deref(), Some("Service is shutting down"));
+    }
+
+    #[test]
+    fn it_treats_unfinished_jobs_without_heartbeat_as_stale() {
+        let now = Utc::now();
+        let mut job = Job::queued("job-3".to_string(), now - Duration::minutes(10));
+        assert!(job.is_stale(now));
+        assert!(!job.is_stale(now - Duration::minutes(9)));
+
+        job.interrupt(now);
+        assert!(!job.is_stale(now + Duration::hours(1)));
+        assert_eq!(job.duration_ms, None);
+    }
+}
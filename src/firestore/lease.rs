@@ -0,0 +1,211 @@
+use crate::error::Error;
+use crate::metrics::observe_firestore;
+use chrono::{DateTime, Duration, Utc};
+use firestore::{FirestoreConsistencySelector, FirestoreDb, FirestoreTransaction};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const LEASE_COLLECTION: &str = "aggregates";
+const LEASE_DOCUMENT: &str = "lease";
+
+/// How long a lease lasts without renewal. Runs renew it on every job heartbeat,
+/// so this has to comfortably exceed `jobs::HEARTBEAT_INTERVAL`.
+pub const LEASE_TTL: Duration = Duration::seconds(120);
+
+/// Grants one aggregation run at a time the right to read and write the aggregates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub owner: String,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub acquired_at: DateTime<Utc>,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Lease {
+    pub fn is_available_to(&self, owner: &str, now: DateTime<Utc>) -> bool {
+        self.owner == owner || self.expires_at <= now
+    }
+
+    /// The lease `owner` should write: a fresh one, or this one extended if they already hold it.
+    fn claimed_by(current: Option<&Lease>, owner: &str, now: DateTime<Utc>) -> Lease {
+        let acquired_at = current
+            .filter(|lease| lease.owner == owner)
+            .map_or(now, |lease| lease.acquired_at);
+        Lease {
+            owner: owner.to_string(),
+            acquired_at,
+            expires_at: now + LEASE_TTL,
+        }
+    }
+}
+
+/// A lease taken by `LeaseGuard::acquire`, required by everything that writes
+/// the aggregates. Once a renewal fails the guard stays lost, as another run may
+/// have taken the lease in the meantime, and the run holding it has to stop at
+/// its next safe point.
+#[derive(Debug, Clone)]
+pub struct LeaseGuard {
+    owner: String,
+    lost: Arc<AtomicBool>,
+}
+
+impl LeaseGuard {
+    pub async fn acquire(db: &FirestoreDb, owner: &str) -> Result<LeaseGuard, Error> {
+        acquire_lease(db, owner).await?;
+        Ok(LeaseGuard {
+            owner: owner.to_string(),
+            lost: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    /// Extends the lease, failing with `Error::LeaseLost` if an earlier renewal
+    /// failed.
+    pub async fn renew(&self, db: &FirestoreDb) -> Result<(), Error> {
+        if self.is_lost() {
+            return Err(Error::LeaseLost {
+                owner: self.owner.clone(),
+            });
+        }
+        if let Err(e) = acquire_lease(db, &self.owner).await {
+            self.lost.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn release(&self, db: &FirestoreDb) -> Result<(), Error> {
+        release_lease(db, &self.owner).await
+    }
+}
+
+async fn read_lease(
+    db: &FirestoreDb,
+    transaction: &FirestoreTransaction<'_>,
+    document: &str,
+) -> Result<Option<Lease>, Error> {
+    let lease = observe_firestore(
+        "fetch_lease",
+        db.clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+            transaction.transaction_id().clone(),
+        ))
+        .fluent()
+        .select()
+        .by_id_in(LEASE_COLLECTION)
+        .obj::<Lease>()
+        .one(document),
+    )
+    .await?;
+    Ok(lease)
+}
+
+/// Takes the lease for `owner` if it is free or expired, or extends it if `owner`
+/// already holds it. Fails with `Error::LeaseHeld` while another owner holds it.
+pub async fn acquire_lease(db: &FirestoreDb, owner: &str) -> Result<Lease, Error> {
+    acquire_named_lease(db, LEASE_DOCUMENT, owner).await
+}
+
+/// `acquire_lease` on a lease stored in `document` rather than the aggregation
+/// lease.
+pub async fn acquire_named_lease(
+    db: &FirestoreDb,
+    document: &str,
+    owner: &str,
+) -> Result<Lease, Error> {
+    let mut transaction = db.begin_transaction().await?;
+    let current = read_lease(db, &transaction, document).await?;
+    let now = Utc::now();
+
+    if let Some(current) = current
+        .as_ref()
+        .filter(|lease| !lease.is_available_to(owner, now))
+    {
+        transaction.rollback().await?;
+        return Err(Error::LeaseHeld {
+            owner: current.owner.clone(),
+            expires_at: current.expires_at,
+        });
+    }
+
+    let lease = Lease::claimed_by(current.as_ref(), owner, now);
+    db.fluent()
+        .update()
+        .in_col(LEASE_COLLECTION)
+        .document_id(document)
+        .object(&lease)
+        .add_to_transaction(&mut transaction)?;
+    observe_firestore("commit_lease", transaction.commit()).await?;
+    Ok(lease)
+}
+
+/// Gives the lease up early so the next run does not wait for it to expire.
+/// Does nothing if `owner` no longer holds it.
+pub async fn release_lease(db: &FirestoreDb, owner: &str) -> Result<(), Error> {
+    release_named_lease(db, LEASE_DOCUMENT, owner).await
+}
+
+pub async fn release_named_lease(
+    db: &FirestoreDb,
+    document: &str,
+    owner: &str,
+) -> Result<(), Error> {
+    let mut transaction = db.begin_transaction().await?;
+    match read_lease(db, &transaction, document).await? {
+        Some(lease) if lease.owner == owner => {
+            db.fluent()
+                .delete()
+                .from(LEASE_COLLECTION)
+                .document_id(document)
+                .add_to_transaction(&mut transaction)?;
+            observe_firestore("commit_lease", transaction.commit()).await?;
+        }
+        _ => transaction.rollback().await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(owner: &str, acquired_at: DateTime<Utc>) -> Lease {
+        Lease {
+            owner: owner.to_string(),
+            acquired_at,
+            expires_at: acquired_at + LEASE_TTL,
+        }
+    }
+
+    #[test]
+    fn it_is_only_available_to_others_once_expired() {
+        let now = Utc::now();
+        let held = lease("run-a", now);
+
+        assert!(held.is_available_to("run-a", now));
+        assert!(!held.is_available_to("run-b", now));
+        assert!(held.is_available_to("run-b", now + LEASE_TTL));
+    }
+
+    #[test]
+    fn it_keeps_acquisition_time_when_renewed() {
+        let now = Utc::now();
+        let held = lease("run-a", now - Duration::seconds(30));
+
+        let renewed = Lease::claimed_by(Some(&held), "run-a", now);
+        assert_eq!(renewed.acquired_at, held.acquired_at);
+        assert_eq!(renewed.expires_at, now + LEASE_TTL);
+
+        let taken_over = Lease::claimed_by(Some(&held), "run-b", now);
+        assert_eq!(taken_over.owner, "run-b");
+        assert_eq!(taken_over.acquired_at, now);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::error::Error;
+use crate::metrics::observe_firestore;
 use crate::processing::action::ActionAggregates;
 use chrono::{DateTime, Utc};
 use firestore::FirestoreDb;
@@ -17,36 +18,43 @@ pub struct Metadata {
 }
 
 pub async fn fetch_metadata(db: &FirestoreDb) -> Result<Option<Metadata>, Error> {
-    let metadata = db
-        .fluent()
-        .select()
-        .by_id_in("aggregates")
-        .obj::<Metadata>()
-        .one("metadata")
-        .await?;
+    let metadata = observe_firestore(
+        "fetch_metadata",
+        db.fluent()
+            .select()
+            .by_id_in("aggregates")
+            .obj::<Metadata>()
+            .one("metadata"),
+    )
+    .await?;
     Ok(metadata)
 }
 
 pub async fn update_metadata(db: &FirestoreDb, metadata: &Metadata) -> Result<(), Error> {
     // Use insert first, then update if it fails (upsert behavior)
-    let insert_result = db
-        .fluent()
-        .insert()
-        .into("aggregates")
-        .document_id("metadata")
-        .object(metadata)
-        .execute::<()>()
-        .await;
-
-    if insert_result.is_err() {
-        // Document exists, update it
+    let insert_result = observe_firestore(
+        "insert_metadata",
         db.fluent()
-            .update()
-            .in_col("aggregates")
+            .insert()
+            .into("aggregates")
             .document_id("metadata")
             .object(metadata)
-            .execute::<()>()
-            .await?;
+            .execute::<()>(),
+    )
+    .await;
+
+    if insert_result.is_err() {
+        // Document exists, update it
+        observe_firestore(
+            "update_metadata",
+            db.fluent()
+                .update()
+                .in_col("aggregates")
+                .document_id("metadata")
+                .object(metadata)
+                .execute::<()>(),
+        )
+        .await?;
     }
     Ok(())
 }
@@ -1,2 +1,44 @@
+pub mod anomalies;
 pub mod client;
+pub mod clocks;
+pub mod compliance;
+pub mod devices;
+pub mod heatmaps;
+pub mod inventory;
+pub mod jobs;
+pub mod lease;
 pub mod metadata;
+pub mod portions;
+pub mod quarantine;
+pub mod rollups;
+pub mod schema;
+pub mod stockouts;
+pub mod uptime;
+
+use sha2::{Digest, Sha256};
+
+/// Document id for the values that identify a document. Free text such as
+/// ingredient names may contain `/`, which ids cannot, so the values are hashed,
+/// each prefixed with its length so that no two lists of values share an id.
+pub(crate) fn hashed_id(values: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for value in values {
+        hasher.update((value.len() as u64).to_be_bytes());
+        hasher.update(value.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_hashes_values_into_safe_distinct_ids() {
+        let id = hashed_id(&["Caldo/Office", "Popcorn"]);
+        assert_eq!(id.len(), 64);
+        assert!(!id.contains('/'));
+        assert_ne!(id, hashed_id(&["Caldo/OfficePop", "corn"]));
+        assert_eq!(id, hashed_id(&["Caldo/Office", "Popcorn"]));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::portion::{PortionDistribution, PortionKey};
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const PORTIONS_COLLECTION: &str = "portions";
+
+fn document_id((location, ingredient): &PortionKey) -> String {
+    hashed_id(&[location, ingredient])
+}
+
+pub async fn fetch_portions(
+    db: &FirestoreDb,
+) -> Result<HashMap<PortionKey, PortionDistribution>, Error> {
+    let distributions: Vec<PortionDistribution> = observe_firestore(
+        "fetch_portions",
+        db.fluent().select().from(PORTIONS_COLLECTION).obj().query(),
+    )
+    .await?;
+    Ok(distributions
+        .into_iter()
+        .map(|distribution| {
+            let key = (
+                distribution.location.clone(),
+                distribution.ingredient.clone(),
+            );
+            (key, distribution)
+        })
+        .collect())
+}
+
+pub async fn write_portions(
+    db: &FirestoreDb,
+    distributions: &HashMap<PortionKey, PortionDistribution>,
+) -> Result<(), Error> {
+    for (key, distribution) in distributions {
+        observe_firestore(
+            "write_portions",
+            db.fluent()
+                .update()
+                .in_col(PORTIONS_COLLECTION)
+                .document_id(document_id(key))
+                .object(distribution)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
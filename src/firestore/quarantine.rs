@@ -0,0 +1,253 @@
+use crate::error::Error;
+use crate::firestore::client::FirestoreLibraData;
+use crate::firestore::hashed_id;
+use crate::firestore::schema::{parse_reading, take_document_id};
+use crate::metrics::{observe_firestore, record_invalid_document, record_quarantined};
+use chrono::{DateTime, Utc};
+use firestore::{FirestoreDb, FirestoreQueryDirection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const QUARANTINE_COLLECTION: &str = "quarantine";
+
+/// A document that could not be parsed as a reading, moved out of its source
+/// collection so it stops breaking queries and aggregation runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedDocument {
+    /// Id of the document in its source collection, or a hash of the document
+    /// if it came without one; also its id here.
+    pub id: String,
+    pub source: String,
+    pub document: Value,
+    pub error: String,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub quarantined_at: DateTime<Utc>,
+    #[serde(default, with = "firestore::serialize_as_optional_timestamp")]
+    pub fixed_at: Option<DateTime<Utc>>,
+}
+
+impl QuarantinedDocument {
+    pub fn reading(&self) -> Result<FirestoreLibraData, String> {
+        parse_reading(self.document.clone())
+    }
+}
+
+/// Parses a raw document of any supported schema version, splitting off the ones
+/// that cannot be parsed.
+pub fn parse_document(
+    mut document: Value,
+    source: &str,
+    now: DateTime<Utc>,
+) -> Result<FirestoreLibraData, Box<QuarantinedDocument>> {
+    let error = match parse_reading(document.clone()) {
+        Ok(data) => return Ok(data),
+        Err(e) => e,
+    };
+    let id = take_document_id(&mut document);
+    Err(Box::new(QuarantinedDocument {
+        id: id.unwrap_or_else(|| hashed_id(&[&document.to_string()])),
+        source: source.to_string(),
+        document,
+        error,
+        quarantined_at: now,
+        fixed_at: None,
+    }))
+}
+
+/// Copies an invalid document into quarantine, then deletes it from its source.
+pub async fn quarantine(db: &FirestoreDb, record: &QuarantinedDocument) -> Result<(), Error> {
+    tracing::warn!(
+        source = %record.source,
+        document_id = %record.id,
+        error = %record.error,
+        "Quarantining invalid document"
+    );
+    save_quarantined(db, record).await?;
+    observe_firestore(
+        "delete_quarantined_source",
+        db.fluent()
+            .delete()
+            .from(record.source.as_str())
+            .document_id(&record.id)
+            .execute(),
+    )
+    .await?;
+    record_quarantined(&record.source);
+    Ok(())
+}
+
+/// Parses every document, skipping and counting the invalid ones without touching
+/// them. For read paths, which must not change what is stored.
+pub fn parse_or_skip(documents: Vec<Value>) -> (Vec<FirestoreLibraData>, usize) {
+    let mut valid = Vec::with_capacity(documents.len());
+    let mut skipped = 0;
+    for mut document in documents {
+        match parse_reading(document.clone()) {
+            Ok(data) => valid.push(data),
+            Err(error) => {
+                skipped += 1;
+                record_invalid_document();
+                tracing::warn!(
+                    document_id = take_document_id(&mut document).as_deref(),
+                    error = %error,
+                    "Skipping invalid document"
+                );
+            }
+        }
+    }
+    (valid, skipped)
+}
+
+/// Parses every document, quarantining the invalid ones. A failure to quarantine
+/// is logged rather than returned, so one bad document cannot fail the caller.
+/// Only for aggregation runs holding the lease; read paths use `parse_or_skip`.
+pub async fn parse_or_quarantine(
+    db: &FirestoreDb,
+    documents: Vec<Value>,
+    source: &str,
+) -> (Vec<FirestoreLibraData>, usize) {
+    let now = Utc::now();
+    let mut valid = Vec::with_capacity(documents.len());
+    let mut quarantined = 0;
+    for document in documents {
+        match parse_document(document, source, now) {
+            Ok(data) => valid.push(data),
+            Err(record) => {
+                quarantined += 1;
+                if let Err(e) = quarantine(db, &record).await {
+                    tracing::error!(
+                        document_id = %record.id,
+                        error = ?e,
+                        "Failed to quarantine invalid document"
+                    );
+                }
+            }
+        }
+    }
+    (valid, quarantined)
+}
+
+pub async fn save_quarantined(db: &FirestoreDb, record: &QuarantinedDocument) -> Result<(), Error> {
+    observe_firestore(
+        "save_quarantined",
+        db.fluent()
+            .update()
+            .in_col(QUARANTINE_COLLECTION)
+            .document_id(&record.id)
+            .object(record)
+            .execute::<()>(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn list_quarantined(
+    db: &FirestoreDb,
+    limit: u32,
+) -> Result<Vec<QuarantinedDocument>, Error> {
+    let records = observe_firestore(
+        "list_quarantined",
+        db.fluent()
+            .select()
+            .from(QUARANTINE_COLLECTION)
+            .order_by([("quarantined_at", FirestoreQueryDirection::Descending)])
+            .limit(limit)
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(records)
+}
+
+pub async fn fetch_quarantined(
+    db: &FirestoreDb,
+    id: &str,
+) -> Result<Option<QuarantinedDocument>, Error> {
+    let record = observe_firestore(
+        "fetch_quarantined",
+        db.fluent()
+            .select()
+            .by_id_in(QUARANTINE_COLLECTION)
+            .obj::<QuarantinedDocument>()
+            .one(id),
+    )
+    .await?;
+    Ok(record)
+}
+
+pub async fn delete_quarantined(db: &FirestoreDb, id: &str) -> Result<(), Error> {
+    observe_firestore(
+        "delete_quarantined",
+        db.fluent()
+            .delete()
+            .from(QUARANTINE_COLLECTION)
+            .document_id(id)
+            .execute(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn document(amount: Value) -> Value {
+        json!({
+            "_firestore_id": "abc123",
+            "_firestore_full_id": "projects/p/databases/(default)/documents/libra/abc123",
+            "device": { "model": "LibraV0", "serialNumber": "Lib298190" },
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "dataAction": "Served",
+            "amount": amount,
+            "timestamp": "2025-06-01T12:00:00Z",
+        })
+    }
+
+    #[test]
+    fn it_parses_valid_documents() {
+        let data = parse_document(document(json!(12.5)), "libra", Utc::now()).unwrap();
+        assert_eq!(data.amount, 12.5);
+    }
+
+    #[test]
+    fn it_skips_invalid_documents_on_reads() {
+        let documents = vec![document(json!(12.5)), document(json!("12.5g"))];
+        let (valid, skipped) = parse_or_skip(documents);
+        assert_eq!(valid.len(), 1);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn it_quarantines_invalid_documents_with_source_id_and_error() {
+        let now = Utc::now();
+        let record = parse_document(document(json!("12.5g")), "libra", now).unwrap_err();
+
+        assert_eq!(record.id, "abc123");
+        assert_eq!(record.source, "libra");
+        assert_eq!(record.quarantined_at, now);
+        assert!(record.error.contains("invalid type"), "{}", record.error);
+        assert_eq!(record.document["amount"], "12.5g");
+        assert!(record.document.get("_firestore_id").is_none());
+        assert!(record.document.get("_firestore_full_id").is_none());
+        assert!(record.reading().is_err());
+    }
+
+    #[test]
+    fn it_quarantines_documents_without_an_id_under_their_hash() {
+        let mut without_id = document(json!("12.5g"));
+        without_id.as_object_mut().unwrap().remove("_firestore_id");
+
+        let first = parse_document(without_id.clone(), "libra", Utc::now()).unwrap_err();
+        let again = parse_document(without_id, "libra", Utc::now()).unwrap_err();
+        assert!(!first.id.is_empty());
+        assert_eq!(first.id, again.id);
+
+        let mut other = document(json!("13g"));
+        other.as_object_mut().unwrap().remove("_firestore_id");
+        let other = parse_document(other, "libra", Utc::now()).unwrap_err();
+        assert_ne!(first.id, other.id);
+    }
+}
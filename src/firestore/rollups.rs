@@ -0,0 +1,88 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::rollup::{Period, Rollup, RollupKey};
+use chrono::NaiveDate;
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const ROLLUPS_COLLECTION: &str = "rollups";
+
+fn document_id((location, period, start): &RollupKey) -> String {
+    let period = match period {
+        Period::Day => "day",
+        Period::Week => "week",
+        Period::Month => "month",
+        Period::Year => "year",
+    };
+    format!("{}_{period}_{start}", hashed_id(&[location]))
+}
+
+/// Rollups of every location and period ending on or after `since`.
+pub async fn fetch_rollups_since(
+    db: &FirestoreDb,
+    since: NaiveDate,
+) -> Result<HashMap<RollupKey, Rollup>, Error> {
+    let rollups: Vec<Rollup> = observe_firestore(
+        "fetch_rollups_since",
+        db.fluent()
+            .select()
+            .from(ROLLUPS_COLLECTION)
+            .filter(|q| q.for_all([q.field("end").greater_than_or_equal(since.to_string())]))
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(rollups
+        .into_iter()
+        .map(|rollup| (rollup.key(), rollup))
+        .collect())
+}
+
+/// Rollups of one period overlapping `from` through `to`, including ones that
+/// start before `from` and end on or after it.
+pub async fn fetch_rollups(
+    db: &FirestoreDb,
+    period: Period,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Rollup>, Error> {
+    let rollups: Vec<Rollup> = observe_firestore(
+        "fetch_rollups",
+        db.fluent()
+            .select()
+            .from(ROLLUPS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field("period").eq(period),
+                    q.field("end").greater_than_or_equal(from.to_string()),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(rollups
+        .into_iter()
+        .filter(|rollup| rollup.start <= to)
+        .collect())
+}
+
+pub async fn write_rollups(
+    db: &FirestoreDb,
+    rollups: &HashMap<RollupKey, Rollup>,
+) -> Result<(), Error> {
+    for (key, rollup) in rollups {
+        observe_firestore(
+            "write_rollup",
+            db.fluent()
+                .update()
+                .in_col(ROLLUPS_COLLECTION)
+                .document_id(document_id(key))
+                .object(rollup)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use crate::firestore::client::FirestoreLibraData;
+use serde_json::{Map, Value};
+use std::fmt;
+
+pub const SCHEMA_VERSION_FIELD: &str = "schemaVersion";
+
+/// Version of the `libra` document layout that `FirestoreLibraData` reads and writes.
+///
+/// - 0: flat legacy layout, `serialNumber` and `model` at the top level and `action`
+///   instead of `dataAction`
+/// - 1: nested `device`, `dataAction`; documents written before versioning carry no
+///   `schemaVersion` field
+pub const CURRENT_SCHEMA_VERSION: u64 = 1;
+
+type Upgrade = fn(Map<String, Value>) -> Map<String, Value>;
+
+/// `UPGRADES[n]` turns a version `n` document into a version `n + 1` document.
+/// Supporting a new layout means bumping `CURRENT_SCHEMA_VERSION` and adding its
+/// upgrade here.
+const UPGRADES: [Upgrade; CURRENT_SCHEMA_VERSION as usize] = [upgrade_v0_to_v1];
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    NotAnObject,
+    /// Written by firmware newer than this service.
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::NotAnObject => write!(f, "document is not an object"),
+            SchemaError::UnsupportedVersion(version) => write!(
+                f,
+                "schema version {version} is newer than the supported version {CURRENT_SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+/// The layout version of a stored document, inferred for documents written
+/// before the `schemaVersion` field existed.
+pub fn schema_version(document: &Map<String, Value>) -> u64 {
+    match document.get(SCHEMA_VERSION_FIELD).and_then(Value::as_u64) {
+        Some(version) => version,
+        None if document.get("device").is_some_and(Value::is_object) => 1,
+        None => 0,
+    }
+}
+
+/// Normalizes a document of any supported version to the current layout.
+pub fn upgrade(document: Value) -> Result<Value, SchemaError> {
+    let Value::Object(mut document) = document else {
+        return Err(SchemaError::NotAnObject);
+    };
+    let version = schema_version(&document);
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(SchemaError::UnsupportedVersion(version));
+    }
+    for upgrade in &UPGRADES[version as usize..] {
+        document = upgrade(document);
+    }
+    document.insert(SCHEMA_VERSION_FIELD.into(), CURRENT_SCHEMA_VERSION.into());
+    Ok(Value::Object(document))
+}
+
+/// Upgrades a stored document and parses it as a reading.
+pub fn parse_reading(document: Value) -> Result<FirestoreLibraData, String> {
+    let document = upgrade(document).map_err(|e| e.to_string())?;
+    serde_json::from_value(document).map_err(|e| e.to_string())
+}
+
+/// Removes the metadata firestore adds to documents read into a `Value`,
+/// returning the document id.
+pub fn take_document_id(document: &mut Value) -> Option<String> {
+    let Value::Object(fields) = document else {
+        return None;
+    };
+    let id = fields
+        .remove("_firestore_id")
+        .and_then(|id| id.as_str().map(str::to_string));
+    fields.retain(|name, _| !name.starts_with("_firestore_"));
+    id
+}
+
+fn upgrade_v0_to_v1(mut document: Map<String, Value>) -> Map<String, Value> {
+    let mut device = Map::new();
+    // LibraV0 was the only model when the flat layout was in use
+    device.insert(
+        "model".into(),
+        document.remove("model").unwrap_or("LibraV0".into()),
+    );
+    if let Some(serial_number) = document.remove("serialNumber") {
+        device.insert("serialNumber".into(), serial_number);
+    }
+    document.insert("device".into(), Value::Object(device));
+    if let Some(action) = document.remove("action") {
+        document.insert("dataAction".into(), action);
+    }
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn v0() -> Value {
+        json!({
+            "serialNumber": "Lib298190",
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "action": "Served",
+            "amount": 12.5,
+            "timestamp": "2025-06-01T12:00:00Z",
+        })
+    }
+
+    fn v1() -> Value {
+        json!({
+            "device": { "model": "LibraV0", "serialNumber": "Lib298190" },
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "dataAction": "Served",
+            "amount": 12.5,
+            "timestamp": "2025-06-01T12:00:00Z",
+        })
+    }
+
+    #[test]
+    fn it_infers_versions_of_unversioned_documents() {
+        assert_eq!(schema_version(v0().as_object().unwrap()), 0);
+        assert_eq!(schema_version(v1().as_object().unwrap()), 1);
+
+        let mut versioned = v1();
+        versioned[SCHEMA_VERSION_FIELD] = json!(1);
+        assert_eq!(schema_version(versioned.as_object().unwrap()), 1);
+    }
+
+    #[test]
+    fn it_upgrades_legacy_documents_to_the_current_layout() {
+        let mut expected = v1();
+        expected[SCHEMA_VERSION_FIELD] = json!(CURRENT_SCHEMA_VERSION);
+
+        assert_eq!(upgrade(v0()).unwrap(), expected);
+        assert_eq!(upgrade(v1()).unwrap(), expected);
+
+        let data = parse_reading(v0()).unwrap();
+        assert_eq!(data.device.serial_number, "Lib298190");
+        assert_eq!(data.schema_version, CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn it_refuses_documents_from_newer_firmware() {
+        let mut future = v1();
+        future[SCHEMA_VERSION_FIELD] = json!(CURRENT_SCHEMA_VERSION + 1);
+        assert_eq!(
+            upgrade(future),
+            Err(SchemaError::UnsupportedVersion(CURRENT_SCHEMA_VERSION + 1))
+        );
+        assert_eq!(upgrade(json!("reading")), Err(SchemaError::NotAnObject));
+    }
+}
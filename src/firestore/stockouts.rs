@@ -0,0 +1,80 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::inventory::InventoryKey;
+use crate::processing::stockout::Stockout;
+use chrono::{DateTime, Utc};
+use firestore::{FirestoreDb, FirestoreTimestamp};
+use std::collections::HashMap;
+
+const STOCKOUTS_COLLECTION: &str = "stockouts";
+
+/// A stockout keeps its id from when it starts to when it is resolved.
+fn document_id(stockout: &Stockout) -> String {
+    hashed_id(&[
+        &stockout.serial_number,
+        &stockout.ingredient,
+        &stockout.ran_out_at.timestamp_micros().to_string(),
+    ])
+}
+
+async fn fetch_unresolved(db: &FirestoreDb) -> Result<Vec<Stockout>, Error> {
+    let stockouts = observe_firestore(
+        "fetch_unresolved_stockouts",
+        db.fluent()
+            .select()
+            .from(STOCKOUTS_COLLECTION)
+            .filter(|q| q.field("resolved").eq(false))
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(stockouts)
+}
+
+/// Stockouts not refilled yet, by scale and ingredient.
+pub async fn fetch_open_stockouts(
+    db: &FirestoreDb,
+) -> Result<HashMap<InventoryKey, Stockout>, Error> {
+    Ok(fetch_unresolved(db)
+        .await?
+        .into_iter()
+        .map(|stockout| (stockout.key(), stockout))
+        .collect())
+}
+
+/// Every stockout still going on at `since` or later.
+pub async fn fetch_stockouts_since(
+    db: &FirestoreDb,
+    since: DateTime<Utc>,
+) -> Result<Vec<Stockout>, Error> {
+    let since = FirestoreTimestamp::from(since);
+    let mut stockouts: Vec<Stockout> = observe_firestore(
+        "fetch_stockouts_since",
+        db.fluent()
+            .select()
+            .from(STOCKOUTS_COLLECTION)
+            .filter(|q| q.field("refilled_at").greater_than(since.clone()))
+            .obj()
+            .query(),
+    )
+    .await?;
+    stockouts.extend(fetch_unresolved(db).await?);
+    Ok(stockouts)
+}
+
+pub async fn write_stockouts(db: &FirestoreDb, stockouts: &[Stockout]) -> Result<(), Error> {
+    for stockout in stockouts {
+        observe_firestore(
+            "write_stockout",
+            db.fluent()
+                .update()
+                .in_col(STOCKOUTS_COLLECTION)
+                .document_id(document_id(stockout))
+                .object(stockout)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
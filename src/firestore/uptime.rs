@@ -0,0 +1,85 @@
+use crate::error::Error;
+use crate::firestore::hashed_id;
+use crate::metrics::observe_firestore;
+use crate::processing::uptime::{DailyUptime, DeviceState, UptimeKey};
+use chrono::NaiveDate;
+use firestore::FirestoreDb;
+use std::collections::HashMap;
+
+const STATES_COLLECTION: &str = "device_states";
+const UPTIME_COLLECTION: &str = "uptime";
+
+fn document_id((serial_number, date): &UptimeKey) -> String {
+    format!("{}_{date}", hashed_id(&[serial_number]))
+}
+
+pub async fn fetch_device_states(db: &FirestoreDb) -> Result<HashMap<String, DeviceState>, Error> {
+    let states: Vec<DeviceState> = observe_firestore(
+        "fetch_device_states",
+        db.fluent().select().from(STATES_COLLECTION).obj().query(),
+    )
+    .await?;
+    Ok(states
+        .into_iter()
+        .map(|state| (state.serial_number.clone(), state))
+        .collect())
+}
+
+/// Daily uptime of every device from `since` through `until`.
+pub async fn fetch_uptime_days(
+    db: &FirestoreDb,
+    since: NaiveDate,
+    until: Option<NaiveDate>,
+) -> Result<HashMap<UptimeKey, DailyUptime>, Error> {
+    let days: Vec<DailyUptime> = observe_firestore(
+        "fetch_uptime_days",
+        db.fluent()
+            .select()
+            .from(UPTIME_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field("date").greater_than_or_equal(since.to_string()),
+                    until.and_then(|until| q.field("date").less_than_or_equal(until.to_string())),
+                ])
+            })
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(days
+        .into_iter()
+        .map(|day| ((day.serial_number.clone(), day.date), day))
+        .collect())
+}
+
+pub async fn write_uptime(
+    db: &FirestoreDb,
+    states: &HashMap<String, DeviceState>,
+    days: &HashMap<UptimeKey, DailyUptime>,
+) -> Result<(), Error> {
+    for (key, day) in days {
+        observe_firestore(
+            "write_uptime",
+            db.fluent()
+                .update()
+                .in_col(UPTIME_COLLECTION)
+                .document_id(document_id(key))
+                .object(day)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    for state in states.values() {
+        observe_firestore(
+            "write_device_state",
+            db.fluent()
+                .update()
+                .in_col(STATES_COLLECTION)
+                .document_id(hashed_id(&[&state.serial_number]))
+                .object(state)
+                .execute::<()>(),
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::error::Error;
+use crate::firestore::client::fetch_readings_since;
+use crate::firestore::inventory::fetch_inventory;
+use crate::processing::forecast::{
+    backtest, consumption_profile, group_served, group_served_by_scale, predict_run_out,
+    BacktestReport, RunOutForecast,
+};
+use chrono::{DateTime, Duration, Utc};
+use firestore::FirestoreDb;
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Forecasts further out than this report no run-out.
+const HORIZON: Duration = Duration::days(7);
+const DEFAULT_BACKTEST_DAYS: i64 = 30;
+const MAX_BACKTEST_DAYS: i64 = 365;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ForecastQuery {
+    pub location: Option<String>,
+    pub ingredient: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BacktestQuery {
+    /// How many days back to look for run-outs.
+    pub days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Forecast {
+    pub location: String,
+    pub ingredient: String,
+    /// Estimated stock across the location's scales.
+    pub remaining: f64,
+    pub daily_consumption: f64,
+    pub run_out: RunOutForecast,
+}
+
+async fn fetch_since(
+    db: &FirestoreDb,
+    action: Action,
+    since: DateTime<Utc>,
+) -> Result<Vec<LibraData>, Error> {
+    Ok(fetch_readings_since(db, &action, since)
+        .await?
+        .into_iter()
+        .map(LibraData::from)
+        .collect())
+}
+
+/// Predicts when each ingredient runs out at each location from its current
+/// inventory and the servings over the `lookback`, soonest first.
+pub async fn forecast_run_outs(
+    db: &FirestoreDb,
+    query: &ForecastQuery,
+    lookback: Duration,
+) -> Result<Vec<Forecast>, Error> {
+    let now = Utc::now();
+    let remaining = fetch_inventory(db)
+        .await?
+        .into_values()
+        .filter(|level| {
+            query.location.as_ref().is_none_or(|v| *v == level.location)
+                && query
+                    .ingredient
+                    .as_ref()
+                    .is_none_or(|v| *v == level.ingredient)
+        })
+        .fold(HashMap::new(), |mut remaining, level| {
+            *remaining
+                .entry((level.location, level.ingredient))
+                .or_insert(0.0) += level.remaining;
+            remaining
+        });
+    if remaining.is_empty() {
+        return Ok(Vec::new());
+    }
+    let served = group_served(fetch_since(db, Action::Served, now - lookback).await?);
+
+    let mut forecasts = remaining
+        .into_iter()
+        .map(|(key, remaining)| {
+            let group = served.get(&key).map_or(&[][..], Vec::as_slice);
+            let profile = consumption_profile(group, lookback.num_days());
+            let (location, ingredient) = key;
+            Forecast {
+                location,
+                ingredient,
+                remaining,
+                daily_consumption: profile.daily_mean,
+                run_out: predict_run_out(remaining, &profile, now, HORIZON),
+            }
+        })
+        .collect::<Vec<_>>();
+    forecasts.sort_by_key(|forecast| {
+        (
+            forecast.run_out.expected.is_none(),
+            forecast.run_out.expected,
+        )
+    });
+    Ok(forecasts)
+}
+
+/// Checks forecasts made at past refills against the run-outs that followed.
+pub async fn backtest_forecasts(
+    db: &FirestoreDb,
+    query: &BacktestQuery,
+    lookback: Duration,
+) -> Result<BacktestReport, Error> {
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_BACKTEST_DAYS)
+        .clamp(1, MAX_BACKTEST_DAYS);
+    let from = Utc::now() - Duration::days(days);
+    // Forecasts at refills early in the period need servings from before it
+    let since = from - lookback - HORIZON;
+
+    let served = group_served_by_scale(fetch_since(db, Action::Served, since).await?);
+    let mut resets = fetch_since(db, Action::Refilled, since).await?;
+    resets.extend(fetch_since(db, Action::RanOut, since).await?);
+    Ok(backtest(&served, &resets, from, lookback, HORIZON))
+}
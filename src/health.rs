@@ -0,0 +1,145 @@
+use crate::firestore::metadata::{fetch_metadata, Metadata};
+use chrono::{DateTime, Utc};
+use firestore::FirestoreDb;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FirestoreStatus {
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Informational only: a stale watermark means the scheduler is not calling us,
+/// not that this instance cannot serve traffic.
+#[derive(Debug, Serialize)]
+pub struct AggregationStatus {
+    pub watermark: Option<DateTime<Utc>>,
+    pub watermark_age_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Components {
+    pub firestore: FirestoreStatus,
+    pub aggregation: AggregationStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: Status,
+    pub components: Components,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == Status::Ok
+    }
+}
+
+/// Reads `aggregates/metadata` as a cheap end-to-end check of credentials and
+/// connectivity, giving up after `timeout`.
+pub async fn check_readiness(db: &FirestoreDb, timeout: Duration) -> Readiness {
+    let start = Instant::now();
+    let metadata = match tokio::time::timeout(timeout, fetch_metadata(db)).await {
+        Ok(Ok(metadata)) => Ok(metadata),
+        Ok(Err(e)) => Err(format!("{e:?}")),
+        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+    };
+    readiness_from(metadata, start.elapsed(), Utc::now())
+}
+
+fn readiness_from(
+    metadata: Result<Option<Metadata>, String>,
+    latency: Duration,
+    now: DateTime<Utc>,
+) -> Readiness {
+    let latency_ms = latency.as_millis() as u64;
+    let (firestore, watermark) = match metadata {
+        Ok(metadata) => (
+            FirestoreStatus {
+                status: Status::Ok,
+                latency_ms,
+                error: None,
+            },
+            metadata.map(|metadata| metadata.last_processed.timestamp),
+        ),
+        Err(error) => (
+            FirestoreStatus {
+                status: Status::Unavailable,
+                latency_ms,
+                error: Some(error),
+            },
+            None,
+        ),
+    };
+
+    Readiness {
+        status: firestore.status,
+        components: Components {
+            firestore,
+            aggregation: AggregationStatus {
+                watermark,
+                watermark_age_seconds: watermark.map(|watermark| (now - watermark).num_seconds()),
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::metadata::LastProcessed;
+    use crate::processing::action::ActionAggregates;
+
+    #[test]
+    fn it_is_ready_with_watermark_age() {
+        let now = Utc::now();
+        let metadata = Metadata {
+            last_processed: LastProcessed {
+                timestamp: now - chrono::Duration::minutes(90),
+            },
+            last_aggregate: ActionAggregates::new(),
+        };
+
+        let readiness = readiness_from(Ok(Some(metadata)), Duration::from_millis(12), now);
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.components.firestore.latency_ms, 12);
+        assert_eq!(
+            readiness.components.aggregation.watermark_age_seconds,
+            Some(90 * 60)
+        );
+    }
+
+    #[test]
+    fn it_is_ready_before_the_first_aggregation() {
+        let readiness = readiness_from(Ok(None), Duration::ZERO, Utc::now());
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.components.aggregation.watermark, None);
+    }
+
+    #[test]
+    fn it_is_unavailable_when_firestore_fails() {
+        let readiness = readiness_from(
+            Err("timed out after 2000ms".to_string()),
+            Duration::from_secs(2),
+            Utc::now(),
+        );
+        assert!(!readiness.is_ready());
+
+        let json = serde_json::to_value(&readiness).unwrap();
+        assert_eq!(json["status"], "unavailable");
+        assert_eq!(
+            json["components"]["firestore"]["error"],
+            "timed out after 2000ms"
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use crate::error::Error;
+use crate::firestore::client::{aggregate_entries, FirestoreDevice, FirestoreLibraData};
+use crate::firestore::lease::LeaseGuard;
+use crate::firestore::schema::CURRENT_SCHEMA_VERSION;
+use crate::ingest::{check_reading, registered_devices};
+use crate::metrics::observe_firestore;
+use crate::migrate::MAX_BATCH_SIZE;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use firestore::{FirestoreDb, FirestoreSimpleBatchWriter};
+use futures::StreamExt;
+use menu::action::Action;
+use menu::device::Model;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Formats tried, in order, for timestamps without a UTC offset.
+const LOCAL_TIMESTAMP_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            other => Err(format!("Unknown format {other}, expected csv or jsonl")),
+        }
+    }
+}
+
+/// Names of the columns, or JSON keys, each reading field is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnMapping {
+    pub serial_number: String,
+    /// Optional in the file; readings without it are from a `LibraV0`.
+    pub model: String,
+    pub location: String,
+    pub ingredient: String,
+    pub action: String,
+    pub amount: String,
+    pub timestamp: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            serial_number: "serialNumber".to_string(),
+            model: "model".to_string(),
+            location: "location".to_string(),
+            ingredient: "ingredient".to_string(),
+            action: "dataAction".to_string(),
+            amount: "amount".to_string(),
+            timestamp: "timestamp".to_string(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Reads `field` from `column` instead of its default, given as `field=column`.
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (field, column) = assignment
+            .split_once('=')
+            .ok_or(format!("Expected field=column, got {assignment}"))?;
+        let target = match field {
+            "serialNumber" => &mut self.serial_number,
+            "model" => &mut self.model,
+            "location" => &mut self.location,
+            "ingredient" => &mut self.ingredient,
+            "dataAction" => &mut self.action,
+            "amount" => &mut self.amount,
+            "timestamp" => &mut self.timestamp,
+            other => return Err(format!("Unknown field {other}")),
+        };
+        *target = column.to_string();
+        Ok(())
+    }
+}
+
+/// A row of the input file by 1-based line number, or why it could not be read.
+pub type Row = (usize, Result<Map<String, Value>, String>);
+
+/// Splits a file into rows keyed by column name. Only a missing or unreadable
+/// CSV header fails the whole file; bad rows are returned as errors.
+pub fn read_rows(format: Format, input: impl Read) -> Result<Vec<Row>, Error> {
+    match format {
+        Format::Csv => read_csv(input),
+        Format::JsonLines => read_json_lines(input),
+    }
+}
+
+fn read_csv(input: impl Read) -> Result<Vec<Row>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let rows = reader
+        .records()
+        .map(|record| match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |p| p.line() as usize);
+                let fields = headers
+                    .iter()
+                    .zip(record.iter())
+                    .filter(|(_, value)| !value.is_empty())
+                    .map(|(header, value)| (header.to_string(), Value::from(value)))
+                    .collect();
+                (line, Ok(fields))
+            }
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line() as usize);
+                (line, Err(e.to_string()))
+            }
+        })
+        .collect();
+    Ok(rows)
+}
+
+fn read_json_lines(input: impl Read) -> Result<Vec<Row>, Error> {
+    let mut rows = Vec::new();
+    for (index, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = match serde_json::from_str(&line) {
+            Ok(Value::Object(fields)) => Ok(fields),
+            Ok(_) => Err("Line is not a JSON object".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        rows.push((index + 1, fields));
+    }
+    Ok(rows)
+}
+
+fn text(row: &Map<String, Value>, column: &str) -> Result<String, String> {
+    match row.get(column) {
+        Some(Value::String(value)) if !value.is_empty() => Ok(value.clone()),
+        Some(Value::Number(value)) => Ok(value.to_string()),
+        _ => Err(format!("Missing column {column}")),
+    }
+}
+
+fn variant<T: DeserializeOwned>(value: String, column: &str) -> Result<T, String> {
+    serde_json::from_value(Value::String(value.clone()))
+        .map_err(|_| format!("Unknown {column} {value}"))
+}
+
+/// Parses a timestamp, reading ones without a UTC offset as local time in
+/// `timezone`. Ambiguous local times at the end of daylight saving time resolve
+/// to the earlier instant.
+pub fn parse_timestamp(value: &str, timezone: Tz) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    let local = LOCAL_TIMESTAMP_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .ok_or(format!("Cannot parse timestamp {value}"))?;
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .ok_or(format!("Timestamp {value} does not exist in {timezone}"))
+}
+
+pub fn parse_row(
+    row: &Map<String, Value>,
+    mapping: &ColumnMapping,
+    timezone: Tz,
+) -> Result<FirestoreLibraData, String> {
+    let model = match text(row, &mapping.model) {
+        Ok(model) => variant(model, &mapping.model)?,
+        Err(_) => Model::LibraV0,
+    };
+    let data_action: Action = variant(text(row, &mapping.action)?, &mapping.action)?;
+    let amount = text(row, &mapping.amount)?;
+    let amount = amount
+        .parse()
+        .map_err(|_| format!("Amount {amount} is not a number"))?;
+    Ok(FirestoreLibraData {
+        device: FirestoreDevice {
+            model,
+            serial_number: text(row, &mapping.serial_number)?,
+        },
+        location: text(row, &mapping.location)?,
+        ingredient: text(row, &mapping.ingredient)?,
+        data_action,
+        amount,
+        timestamp: parse_timestamp(&text(row, &mapping.timestamp)?, timezone)?,
+        schema_version: CURRENT_SCHEMA_VERSION,
+        received_at: None,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub mapping: ColumnMapping,
+    /// Time zone of timestamps without a UTC offset.
+    pub timezone: Tz,
+    pub batch_size: usize,
+    pub dry_run: bool,
+    pub max_future_skew: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RejectedRow {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub rows: usize,
+    /// Readings written to `libra`, or that would be on a dry run.
+    pub imported: usize,
+    /// Readings repeated in the file or already stored.
+    pub duplicates: usize,
+    pub rejected: Vec<RejectedRow>,
+    /// Imported readings folded into the aggregates straight away. The rest are
+    /// newer than the watermark and counted by the next aggregation run.
+    pub backfilled: usize,
+}
+
+/// Parses and validates rows, dropping readings repeated within the file.
+pub fn prepare(
+    rows: Vec<Row>,
+    options: &ImportOptions,
+    registered: &HashSet<String>,
+    now: DateTime<Utc>,
+) -> (Vec<(String, FirestoreLibraData)>, ImportReport) {
+    let mut report = ImportReport {
+        rows: rows.len(),
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut readings = Vec::new();
+    for (line, row) in rows {
+        let data = row
+            .and_then(|row| parse_row(&row, &options.mapping, options.timezone))
+            .and_then(|data| {
+                check_reading(&data, registered, options.max_future_skew, now)
+                    .map_err(|invalid| invalid.to_string())?;
+                Ok(data)
+            });
+        match data {
+            Ok(data) => {
+                let id = data.content_id();
+                if seen.insert(id.clone()) {
+                    readings.push((id, data));
+                } else {
+                    report.duplicates += 1;
+                }
+            }
+            Err(error) => report.rejected.push(RejectedRow { line, error }),
+        }
+    }
+    (readings, report)
+}
+
+/// Ids among `ids` that already have a document in `libra`.
+async fn stored_ids(db: &FirestoreDb, ids: &[&String]) -> Result<HashSet<String>, Error> {
+    let documents = observe_firestore(
+        "fetch_reading_ids",
+        db.fluent()
+            .select()
+            .by_id_in("libra")
+            .obj::<Value>()
+            .batch(ids.iter().map(|id| id.as_str())),
+    )
+    .await?;
+    Ok(documents
+        .filter_map(|(id, document)| async move { document.map(|_| id) })
+        .collect()
+        .await)
+}
+
+async fn write_batch(
+    db: &FirestoreDb,
+    writer: &FirestoreSimpleBatchWriter,
+    readings: &[(String, FirestoreLibraData)],
+) -> Result<(), Error> {
+    let mut batch = writer.new_batch();
+    for (id, data) in readings {
+        db.fluent()
+            .update()
+            .in_col("libra")
+            .document_id(id)
+            .object(data)
+            .add_to_batch(&mut batch)?;
+    }
+    observe_firestore("import_batch", batch.write()).await?;
+    tracing::info!(readings = readings.len(), "Imported batch");
+    Ok(())
+}
+
+/// Imports historical readings into `libra` and backfills the aggregates for
+/// each batch once it is written, so a failure part way through leaves no
+/// written reading uncounted. Holds the aggregation lease throughout, so no run
+/// can move the watermark past readings between writing and backfilling them;
+/// fails with `Error::LeaseHeld` while a run is in progress. A dry run only
+/// reads.
+#[tracing::instrument(name = "import", skip_all, fields(rows = rows.len(), dry_run = options.dry_run))]
+pub async fn import(
+    db: &FirestoreDb,
+    rows: Vec<Row>,
+    options: &ImportOptions,
+) -> Result<ImportReport, Error> {
+    let registered = registered_devices(db).await?;
+    let (readings, report) = prepare(rows, options, &registered, Utc::now());
+    if options.dry_run {
+        return import_prepared(db, readings, report, options, None).await;
+    }
+
+    let lease = LeaseGuard::acquire(db, &format!("import-{}", Uuid::new_v4())).await?;
+    let result = import_prepared(db, readings, report, options, Some(&lease)).await;
+    if let Err(e) = lease.release(db).await {
+        tracing::warn!(error = ?e, "Failed to release aggregation lease, it will expire instead");
+    }
+    result
+}
+
+async fn import_prepared(
+    db: &FirestoreDb,
+    readings: Vec<(String, FirestoreLibraData)>,
+    mut report: ImportReport,
+    options: &ImportOptions,
+    lease: Option<&LeaseGuard>,
+) -> Result<ImportReport, Error> {
+    let batch_size = options.batch_size.clamp(1, MAX_BATCH_SIZE);
+    let writer = db.create_simple_batch_writer().await?;
+    let mut readings = readings.into_iter().peekable();
+    while readings.peek().is_some() {
+        let batch = readings.by_ref().take(batch_size).collect::<Vec<_>>();
+        let ids = batch.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        let stored = stored_ids(db, &ids).await?;
+        let (duplicates, new): (Vec<_>, Vec<_>) =
+            batch.into_iter().partition(|(id, _)| stored.contains(id));
+        report.duplicates += duplicates.len();
+        report.imported += new.len();
+
+        if let Some(lease) = lease {
+            if !new.is_empty() {
+                write_batch(db, &writer, &new).await?;
+            }
+            // Renew, as a large file can take longer than the lease lasts
+            lease.renew(db).await?;
+            let imported = new.into_iter().map(|(_, data)| data).collect();
+            report.backfilled += aggregate_entries(db, lease, imported).await?;
+        }
+    }
+
+    tracing::info!(
+        imported = report.imported,
+        duplicates = report.duplicates,
+        rejected = report.rejected.len(),
+        backfilled = report.backfilled,
+        "Import finished"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    fn options() -> ImportOptions {
+        ImportOptions {
+            mapping: ColumnMapping::default(),
+            timezone: Berlin,
+            batch_size: MAX_BATCH_SIZE,
+            dry_run: true,
+            max_future_skew: Duration::minutes(5),
+        }
+    }
+
+    fn registered() -> HashSet<String> {
+        HashSet::from(["Lib298190".to_string()])
+    }
+
+    #[test]
+    fn it_reads_local_timestamps_in_the_given_timezone() {
+        let expected = DateTime::parse_from_rfc3339("2024-01-15T11:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_timestamp("2024-01-15 12:30:00", Berlin), Ok(expected));
+        assert_eq!(
+            parse_timestamp("2024-01-15T12:30:00.000", Berlin),
+            Ok(expected)
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-15T12:30:00+01:00", chrono_tz::UTC),
+            Ok(expected)
+        );
+
+        // Clocks went from 02:00 to 03:00 in Berlin that night
+        assert!(parse_timestamp("2024-03-31 02:30:00", Berlin).is_err());
+        assert!(parse_timestamp("yesterday", Berlin).is_err());
+    }
+
+    #[test]
+    fn it_maps_csv_columns_to_readings() {
+        let csv = "\
+scale,site,item,event,grams,time
+Lib298190,Caldo Office,Popcorn,Served,12.5,2024-01-15 12:30:00
+Lib298190,Caldo Office,Popcorn,Served,not much,2024-01-15 12:31:00
+";
+        let mut mapping = ColumnMapping::default();
+        for assignment in [
+            "serialNumber=scale",
+            "location=site",
+            "ingredient=item",
+            "dataAction=event",
+            "amount=grams",
+            "timestamp=time",
+        ] {
+            mapping.set(assignment).unwrap();
+        }
+        assert!(mapping.set("weight=grams").is_err());
+
+        let rows = read_rows(Format::Csv, csv.as_bytes()).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let data = parse_row(rows[0].1.as_ref().unwrap(), &mapping, Berlin).unwrap();
+        assert_eq!(data.device.serial_number, "Lib298190");
+        assert_eq!(data.data_action, Action::Served);
+        assert_eq!(data.amount, 12.5);
+        assert_eq!(data.timestamp.to_rfc3339(), "2024-01-15T11:30:00+00:00");
+
+        let error = parse_row(rows[1].1.as_ref().unwrap(), &mapping, Berlin).unwrap_err();
+        assert_eq!(error, "Amount not much is not a number");
+    }
+
+    #[test]
+    fn it_reports_rejected_lines_and_drops_repeats() {
+        let jsonl = r#"{"serialNumber":"Lib298190","location":"Caldo Office","ingredient":"Popcorn","dataAction":"Served","amount":12.5,"timestamp":"2024-01-15T11:30:00Z"}
+
+{"serialNumber":"Lib298190","location":"Caldo Office","ingredient":"Popcorn","dataAction":"Served","amount":12.5,"timestamp":"2024-01-15T11:30:00Z"}
+{"serialNumber":"Lib000000","location":"Caldo Office","ingredient":"Popcorn","dataAction":"Served","amount":12.5,"timestamp":"2024-01-15T11:30:00Z"}
+not json
+"#;
+        let rows = read_rows(Format::JsonLines, jsonl.as_bytes()).unwrap();
+        let (readings, report) = prepare(rows, &options(), &registered(), Utc::now());
+
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].0, readings[0].1.content_id());
+        assert_eq!(report.rows, 4);
+        assert_eq!(report.duplicates, 1);
+        let lines = report.rejected.iter().map(|r| r.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![4, 5]);
+        assert!(report.rejected[0].error.contains("not registered"));
+    }
+
+    #[test]
+    fn it_picks_the_format_from_the_extension() {
+        assert_eq!(Format::from_path(Path::new("logs.csv")), Some(Format::Csv));
+        assert_eq!(
+            Format::from_path(Path::new("logs.ndjson")),
+            Some(Format::JsonLines)
+        );
+        assert_eq!(Format::from_path(Path::new("logs.txt")), None);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::error::Error;
+use crate::firestore::client::{read_locations, FirestoreLibraData};
+use crate::metrics::{observe_firestore, record_ingested};
+use chrono::{DateTime, Duration, Utc};
+use firestore::errors::FirestoreError::DataConflictError;
+use firestore::FirestoreDb;
+use menu::libra_data::LibraData;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Furthest ahead of the server clock a timestamp is ever accepted. Closer ones
+/// from a fast device clock are corrected by aggregation runs; beyond this the
+/// timestamp is too far off to come from a clock that was ever set.
+pub const MAX_CLOCK_AHEAD: Duration = Duration::days(1);
+
+/// Why a reading was refused. Retrying these will not help.
+#[derive(Debug, PartialEq)]
+pub enum Invalid {
+    Schema(String),
+    UnregisteredDevice(String),
+    Amount(f64),
+    FutureTimestamp(DateTime<Utc>),
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::Schema(e) => write!(f, "Reading does not match the LibraData schema: {e}"),
+            Invalid::UnregisteredDevice(serial) => {
+                write!(f, "Device {serial} is not registered in locations")
+            }
+            Invalid::Amount(amount) => write!(f, "Amount {amount} is not a non-negative number"),
+            Invalid::FutureTimestamp(timestamp) => {
+                write!(f, "Timestamp {timestamp} is too far in the future")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordStatus {
+    /// Written to `libra`.
+    Accepted,
+    /// Already stored by an earlier request; do not retry.
+    Duplicate,
+    /// Failed validation; do not retry.
+    Rejected,
+    /// Valid but could not be written; safe to retry.
+    Failed,
+}
+
+impl RecordStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            RecordStatus::Accepted => "accepted",
+            RecordStatus::Duplicate => "duplicate",
+            RecordStatus::Rejected => "rejected",
+            RecordStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecordResult {
+    pub index: usize,
+    pub status: RecordStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestResponse {
+    pub accepted: usize,
+    pub duplicates: usize,
+    pub rejected: usize,
+    pub failed: usize,
+    pub results: Vec<RecordResult>,
+}
+
+impl IngestResponse {
+    fn from_results(results: Vec<RecordResult>) -> Self {
+        let count = |status| results.iter().filter(|r| r.status == status).count();
+        IngestResponse {
+            accepted: count(RecordStatus::Accepted),
+            duplicates: count(RecordStatus::Duplicate),
+            rejected: count(RecordStatus::Rejected),
+            failed: count(RecordStatus::Failed),
+            results,
+        }
+    }
+}
+
+/// Accepts either a single reading or an array of them.
+pub fn readings(body: Value) -> Vec<Value> {
+    match body {
+        Value::Array(readings) => readings,
+        reading => vec![reading],
+    }
+}
+
+/// Checks a reading against the registered devices and sanity limits, and
+/// records `now` as its arrival time. Readings from a fast device clock are
+/// accepted up to `MAX_CLOCK_AHEAD`; aggregation runs can correct their
+/// timestamps.
+pub fn validate(
+    reading: Value,
+    registered: &HashSet<String>,
+    max_future_skew: Duration,
+    now: DateTime<Utc>,
+) -> Result<FirestoreLibraData, Invalid> {
+    let data: LibraData =
+        serde_json::from_value(reading).map_err(|e| Invalid::Schema(e.to_string()))?;
+    let mut data = FirestoreLibraData::from(data);
+    data.received_at = Some(now);
+    check_reading(&data, registered, max_future_skew, now)?;
+    if data.timestamp > now + max_future_skew {
+        tracing::warn!(
+            serial_number = data.device.serial_number,
+            timestamp = %data.timestamp,
+            "Device clock is ahead, timestamp needs correcting"
+        );
+    }
+    Ok(data)
+}
+
+/// The checks `validate` applies once a reading has parsed. Readings more than
+/// `MAX_CLOCK_AHEAD` in the future are refused, and so are readings more than
+/// `max_future_skew` ahead without an arrival time to correct them by.
+pub fn check_reading(
+    data: &FirestoreLibraData,
+    registered: &HashSet<String>,
+    max_future_skew: Duration,
+    now: DateTime<Utc>,
+) -> Result<(), Invalid> {
+    if !registered.contains(&data.device.serial_number) {
+        return Err(Invalid::UnregisteredDevice(
+            data.device.serial_number.clone(),
+        ));
+    }
+    if !data.amount.is_finite() || data.amount < 0.0 {
+        return Err(Invalid::Amount(data.amount));
+    }
+    let max_ahead = match data.received_at {
+        Some(_) => max_future_skew.max(MAX_CLOCK_AHEAD),
+        None => max_future_skew,
+    };
+    if data.timestamp > now + max_ahead {
+        return Err(Invalid::FutureTimestamp(data.timestamp));
+    }
+    Ok(())
+}
+
+pub(crate) enum Written {
+    New,
+    Duplicate,
+}
+
+/// Inserts a reading under `id`, so a reading that was already stored under it
+/// is reported as a duplicate instead of being written again.
+pub(crate) async fn write_reading(
+    db: &FirestoreDb,
+    id: &str,
+    data: &FirestoreLibraData,
+) -> Result<Written, Error> {
+    let result = observe_firestore(
+        "insert_reading",
+        db.fluent()
+            .insert()
+            .into("libra")
+            .document_id(id)
+            .object(data)
+            .execute::<()>(),
+    )
+    .await;
+    match result {
+        Ok(()) => Ok(Written::New),
+        Err(DataConflictError(_)) => Ok(Written::Duplicate),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Serial numbers of every device registered in `locations`.
+pub(crate) async fn registered_devices(db: &FirestoreDb) -> Result<HashSet<String>, Error> {
+    Ok(read_locations(db)
+        .await?
+        .into_iter()
+        .map(|location| location.device.serial_number)
+        .collect())
+}
+
+/// Validates and writes each reading independently, so one bad reading does
+/// not hold back the rest of the batch.
+#[tracing::instrument(name = "ingest", skip_all, fields(readings = readings.len()))]
+pub async fn ingest(
+    db: &FirestoreDb,
+    readings: Vec<Value>,
+    max_future_skew: Duration,
+) -> Result<IngestResponse, Error> {
+    let registered = registered_devices(db).await?;
+    let now = Utc::now();
+
+    let mut results = Vec::with_capacity(readings.len());
+    for (index, reading) in readings.into_iter().enumerate() {
+        let result = match validate(reading, &registered, max_future_skew, now) {
+            Ok(data) => {
+                let id = data.content_id();
+                match write_reading(db, &id, &data).await {
+                    Ok(written) => RecordResult {
+                        index,
+                        status: match written {
+                            Written::New => RecordStatus::Accepted,
+                            Written::Duplicate => RecordStatus::Duplicate,
+                        },
+                        id: Some(id),
+                        error: None,
+                    },
+                    Err(e) => {
+                        tracing::error!(index, error = ?e, "Failed to write reading");
+                        RecordResult {
+                            index,
+                            status: RecordStatus::Failed,
+                            id: None,
+                            error: Some(e.to_string()),
+                        }
+                    }
+                }
+            }
+            Err(invalid) => {
+                tracing::warn!(index, reason = %invalid, "Rejected reading");
+                RecordResult {
+                    index,
+                    status: RecordStatus::Rejected,
+                    id: None,
+                    error: Some(invalid.to_string()),
+                }
+            }
+        };
+        record_ingested(result.status.as_str());
+        results.push(result);
+    }
+    Ok(IngestResponse::from_results(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reading(serial_number: &str, amount: f64, timestamp: &str) -> Value {
+        json!({
+            "device": { "model": "LibraV0", "serial_number": serial_number },
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "data_action": "Served",
+            "amount": amount,
+            "timestamp": timestamp,
+        })
+    }
+
+    fn registered() -> HashSet<String> {
+        HashSet::from(["Lib298190".to_string()])
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2025-06-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn it_accepts_single_and_batched_bodies() {
+        let single = reading("Lib298190", 1.0, "2025-06-01T11:00:00Z");
+        assert_eq!(readings(single.clone()).len(), 1);
+        assert_eq!(readings(json!([single.clone(), single])).len(), 2);
+    }
+
+    #[test]
+    fn it_accepts_valid_readings() {
+        let data = validate(
+            reading("Lib298190", 12.5, "2025-06-01T11:59:00Z"),
+            &registered(),
+            Duration::minutes(5),
+            now(),
+        )
+        .unwrap();
+        assert_eq!(data.amount, 12.5);
+        assert_eq!(data.device.serial_number, "Lib298190");
+    }
+
+    #[test]
+    fn it_rejects_invalid_readings() {
+        let check = |reading| validate(reading, &registered(), Duration::minutes(5), now());
+
+        assert!(matches!(
+            check(json!({ "amount": 1.0 })),
+            Err(Invalid::Schema(_))
+        ));
+        assert_eq!(
+            check(reading("Lib000000", 1.0, "2025-06-01T11:00:00Z")).unwrap_err(),
+            Invalid::UnregisteredDevice("Lib000000".to_string())
+        );
+        assert_eq!(
+            check(reading("Lib298190", -1.0, "2025-06-01T11:00:00Z")).unwrap_err(),
+            Invalid::Amount(-1.0)
+        );
+    }
+
+    #[test]
+    fn it_accepts_fast_clocks_only_with_an_arrival_time() {
+        let data = validate(
+            reading("Lib298190", 1.0, "2025-06-01T12:06:00Z"),
+            &registered(),
+            Duration::minutes(5),
+            now(),
+        )
+        .unwrap();
+        assert_eq!(data.received_at, Some(now()));
+
+        let imported = FirestoreLibraData {
+            received_at: None,
+            ..data
+        };
+        assert!(matches!(
+            check_reading(&imported, &registered(), Duration::minutes(5), now()),
+            Err(Invalid::FutureTimestamp(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_timestamps_no_clock_could_be_that_far_ahead() {
+        let far_ahead = validate(
+            reading("Lib298190", 1.0, "9999-01-01T00:00:00Z"),
+            &registered(),
+            Duration::minutes(5),
+            now(),
+        );
+        assert!(matches!(far_ahead, Err(Invalid::FutureTimestamp(_))));
+    }
+}
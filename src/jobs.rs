@@ -0,0 +1,149 @@
+use crate::anomalies;
+use crate::devices::check_devices;
+use crate::error::Error;
+use crate::firestore::client::{process_aggregations, AggregationProgress};
+use crate::firestore::jobs::{
+    fetch_job, fetch_unfinished_jobs, insert_job, update_job, Job, HEARTBEAT_INTERVAL,
+};
+use crate::firestore::lease::LeaseGuard;
+use crate::shutdown::Shutdown;
+use chrono::Utc;
+use firestore::FirestoreDb;
+use tokio::sync::watch;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Takes the aggregation lease, records a queued job and runs the aggregation in
+/// the background, returning as soon as the job is persisted. Fails with
+/// `Error::LeaseHeld` while another run is in progress.
+pub async fn start_aggregation_job(db: &FirestoreDb, shutdown: &Shutdown) -> Result<Job, Error> {
+    if shutdown.is_triggered() {
+        return Err(Error::ShuttingDown);
+    }
+
+    let job = Job::queued(Uuid::new_v4().to_string(), Utc::now());
+    let lease = LeaseGuard::acquire(db, &job.id).await?;
+    if let Err(e) = insert_job(db, &job).await {
+        release(db, &lease).await;
+        return Err(e);
+    }
+    tracing::info!(job_id = %job.id, "Queued aggregation job");
+
+    let span = tracing::info_span!("aggregation_job", job_id = %job.id);
+    shutdown.spawn(run_job(db.clone(), shutdown.clone(), lease, job.clone()).instrument(span));
+    Ok(job)
+}
+
+/// Reads a job back, failing it first if the instance running it has gone away.
+pub async fn get_job(db: &FirestoreDb, id: &str) -> Result<Option<Job>, Error> {
+    let Some(mut job) = fetch_job(db, id).await? else {
+        return Ok(None);
+    };
+    let now = Utc::now();
+    if job.is_stale(now) {
+        job.interrupt(now);
+        update_job(db, &job).await?;
+    }
+    Ok(Some(job))
+}
+
+/// Fails jobs left queued or running by an instance that stopped without finishing them.
+pub async fn recover_interrupted_jobs(db: &FirestoreDb) -> Result<usize, Error> {
+    let now = Utc::now();
+    let mut recovered = 0;
+    for mut job in fetch_unfinished_jobs(db).await? {
+        if job.is_stale(now) {
+            job.interrupt(now);
+            update_job(db, &job).await?;
+            recovered += 1;
+        }
+    }
+    Ok(recovered)
+}
+
+async fn run_job(db: FirestoreDb, shutdown: Shutdown, lease: LeaseGuard, mut job: Job) {
+    job.start(Utc::now());
+    if let Err(e) = update_job(&db, &job).await {
+        tracing::error!(error = ?e, "Failed to mark job as running");
+    }
+
+    let (sender, receiver) = watch::channel(AggregationProgress::default());
+    let run = async {
+        let result = process_aggregations(&db, &lease, &shutdown, &sender).await;
+        // Dropping the sender stops the heartbeat below
+        drop(sender);
+        result
+    };
+    let (result, mut job) = tokio::join!(run, heartbeat(&db, job, &lease, receiver));
+
+    // A run that lost the lease after its last safe point may have overlapped
+    // another, so it is not reported as a success either
+    let result = result.and_then(|progress| {
+        if lease.is_lost() {
+            Err(Error::LeaseLost {
+                owner: lease.owner().to_string(),
+            })
+        } else {
+            Ok(progress)
+        }
+    });
+    match result {
+        Ok(progress) => {
+            job.succeed(progress, Utc::now());
+            tracing::info!(duration_ms = job.duration_ms, "Aggregation job succeeded");
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "Aggregation job failed");
+            job.fail(e.to_string(), Utc::now());
+        }
+    }
+    if let Err(e) = update_job(&db, &job).await {
+        tracing::error!(error = ?e, "Failed to record job result");
+    }
+    release(&db, &lease).await;
+
+    // A finished run is a convenient time to look for devices that went quiet
+    // and for unusual volume
+    if let Err(e) = check_devices(&db).await {
+        tracing::error!(error = ?e, "Stale device check failed");
+    }
+    if let Err(e) = anomalies::detect(&db).await {
+        tracing::error!(error = ?e, "Anomaly detection failed");
+    }
+}
+
+async fn release(db: &FirestoreDb, lease: &LeaseGuard) {
+    if let Err(e) = lease.release(db).await {
+        tracing::warn!(error = ?e, "Failed to release aggregation lease, it will expire instead");
+    }
+}
+
+/// Persists progress as it changes, and at least every `HEARTBEAT_INTERVAL` so the
+/// job is not mistaken for one abandoned by a dead instance. Renews the lease on
+/// the same schedule; once that fails the run stops at its next safe point.
+async fn heartbeat(
+    db: &FirestoreDb,
+    mut job: Job,
+    lease: &LeaseGuard,
+    mut progress: watch::Receiver<AggregationProgress>,
+) -> Job {
+    loop {
+        let running = tokio::select! {
+            changed = progress.changed() => changed.is_ok(),
+            _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => true,
+        };
+        if !running {
+            return job;
+        }
+        job.record_progress(*progress.borrow_and_update(), Utc::now());
+        if let Err(e) = update_job(db, &job).await {
+            tracing::warn!(error = ?e, "Failed to record job progress");
+        }
+        if lease.is_lost() {
+            continue;
+        }
+        if let Err(e) = lease.renew(db).await {
+            tracing::error!(error = ?e, "Failed to renew aggregation lease, stopping the run");
+        }
+    }
+}
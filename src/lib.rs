@@ -1,5 +1,20 @@
+pub mod alerts;
+pub mod anomalies;
 pub mod config;
+pub mod devices;
 pub mod error;
 pub mod firestore;
+pub mod forecast;
+pub mod health;
+pub mod import;
+pub mod ingest;
+pub mod jobs;
+pub mod metrics;
+pub mod migrate;
 pub mod processing;
+pub mod pubsub;
+pub mod quarantine;
 pub mod query;
+pub mod retention;
+pub mod shutdown;
+pub mod telemetry;
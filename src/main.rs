@@ -1,20 +1,47 @@
+use data_aggregation::config::{
+    clock_skew_threshold, device_check_interval, forecast_lookback, ingest_max_batch,
+    ingest_max_future_skew, inventory_low_fraction, location_hours, portion_bins, portion_targets,
+    readiness_timeout, shutdown_timeout,
+};
+use data_aggregation::devices::{check_devices, check_devices_every, device_statuses};
 use data_aggregation::error::Error;
-use data_aggregation::firestore::client::{process_aggregations, read_locations, LocationData};
-use data_aggregation::query::{DataQuery, LocationQuery};
+use data_aggregation::firestore::client::{read_locations, LocationData};
+use data_aggregation::firestore::clocks::device_skew;
+use data_aggregation::firestore::quarantine::list_quarantined;
+use data_aggregation::forecast::{
+    backtest_forecasts, forecast_run_outs, BacktestQuery, ForecastQuery,
+};
+use data_aggregation::health::check_readiness;
+use data_aggregation::ingest;
+use data_aggregation::jobs::{get_job, recover_interrupted_jobs, start_aggregation_job};
+use data_aggregation::metrics;
+use data_aggregation::pubsub;
+use data_aggregation::quarantine;
+use data_aggregation::query::{
+    AnomalyQuery, ComplianceQuery, DataQuery, HeatmapQuery, InventoryQuery, LocationQuery,
+    PortionQuery, RollupQuery, StockoutQuery, UptimeQuery,
+};
+use data_aggregation::shutdown::{self, Shutdown};
+use data_aggregation::telemetry;
 use dotenv::dotenv;
 use firestore::*;
 use std::env;
+use warp::hyper::body::Bytes;
 use warp::{Filter, Rejection, Reply};
 
+const MAX_INGEST_BODY_BYTES: u64 = 4 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     // Load .env for local development only
     dotenv().ok();
 
+    let telemetry = telemetry::init()?;
+
     // Debug: Check if the emulator host env var is set correctly
-    println!(
-        "FIRESTORE_EMULATOR_HOST: {:?}",
-        env::var("FIRESTORE_EMULATOR_HOST")
+    tracing::debug!(
+        emulator_host = ?env::var("FIRESTORE_EMULATOR_HOST"),
+        "Checked FIRESTORE_EMULATOR_HOST"
     );
 
     // Initialize rustls crypto provider
@@ -25,18 +52,94 @@ async fn main() -> Result<(), Error> {
     // Get project ID from environment variable
     let project = env::var("GOOGLE_CLOUD_PROJECT")?;
 
-    println!("Starting data aggregation service for project: {}", project);
+    tracing::info!(project = %project, "Starting data aggregation service");
 
     let db = FirestoreDb::new(&project).await?;
 
+    match recover_interrupted_jobs(&db).await {
+        Ok(0) => {}
+        Ok(recovered) => tracing::warn!(recovered, "Marked interrupted aggregation jobs as failed"),
+        Err(e) => tracing::error!(error = ?e, "Failed to recover interrupted aggregation jobs"),
+    }
+
+    let shutdown = Shutdown::new();
+    if let Some(interval) = device_check_interval() {
+        shutdown.spawn(check_devices_every(db.clone(), interval, shutdown.clone()));
+    }
+
     let with_db = warp::any().map(move || db.clone());
+    let with_shutdown = {
+        let shutdown = shutdown.clone();
+        warp::any().map(move || shutdown.clone())
+    };
 
     // Create the aggregation route
     let aggregation_route = warp::path("aggregate")
         .and(warp::post())
         .and(with_db.clone())
+        .and(with_shutdown)
         .and_then(run_aggregation_handler);
 
+    let job_route = warp::path!("jobs" / String)
+        .and(warp::get())
+        .and(with_db.clone())
+        .and_then(handle_job_query);
+
+    let ingest_route = warp::path("ingest")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_INGEST_BODY_BYTES))
+        .and(warp::body::json())
+        .and(with_db.clone())
+        .and_then(handle_ingest);
+
+    // Push subscription for readings published by devices
+    let pubsub_route = warp::path!("pubsub" / "push")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_INGEST_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(with_db.clone())
+        .and_then(handle_pubsub_push);
+
+    // Admin routes for documents that failed schema validation
+    let quarantine_list_route = warp::path!("admin" / "quarantine")
+        .and(warp::get())
+        .and(warp::query::<QuarantineQuery>())
+        .and(with_db.clone())
+        .and_then(handle_quarantine_list);
+
+    let quarantine_fix_route = warp::path!("admin" / "quarantine" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_INGEST_BODY_BYTES))
+        .and(warp::body::json())
+        .and(with_db.clone())
+        .and_then(handle_quarantine_fix);
+
+    let quarantine_replay_route = warp::path!("admin" / "quarantine" / String / "replay")
+        .and(warp::post())
+        .and(with_db.clone())
+        .and_then(handle_quarantine_replay);
+
+    // Devices whose clock is off, estimated by aggregation runs
+    let device_skew_route = warp::path!("devices" / "skew")
+        .and(warp::get())
+        .and(warp::query::<SkewQuery>())
+        .and(with_db.clone())
+        .and_then(handle_device_skew);
+
+    // Registered devices that stopped sending heartbeats, as of the last check
+    let device_status_route = warp::path!("devices" / "status")
+        .and(warp::get())
+        .and(warp::query::<StatusQuery>())
+        .and(with_db.clone())
+        .and_then(handle_device_status);
+
+    // Runs the stale device check on demand; the service also runs it every
+    // `DEVICE_CHECK_INTERVAL_SECS`
+    let device_check_route = warp::path!("devices" / "check")
+        .and(warp::post())
+        .and(with_db.clone())
+        .and_then(handle_device_check);
+
     // Create the locations route
     let locations_route = warp::path("locations")
         .and(warp::query::<LocationQuery>())
@@ -50,43 +153,270 @@ async fn main() -> Result<(), Error> {
         .and(with_db.clone())
         .and_then(handle_data_query);
 
-    // Health check route
+    // Estimated stock per scale and ingredient, kept up to date by aggregation runs
+    let inventory_route = warp::path("inventory")
+        .and(warp::get())
+        .and(warp::query::<InventoryQuery>())
+        .and(with_db.clone())
+        .and_then(handle_inventory_query);
+
+    // Daily availability per device and location
+    let uptime_route = warp::path("uptime")
+        .and(warp::get())
+        .and(warp::query::<UptimeQuery>())
+        .and(with_db.clone())
+        .and_then(handle_uptime_query);
+
+    // How long ingredients stayed empty, per location
+    let stockout_route = warp::path("stockouts")
+        .and(warp::get())
+        .and(warp::query::<StockoutQuery>())
+        .and(with_db.clone())
+        .and_then(handle_stockout_query);
+
+    // Servings per location and day, ISO week, month or year
+    let rollup_route = warp::path("rollups")
+        .and(warp::get())
+        .and(warp::query::<RollupQuery>())
+        .and(with_db.clone())
+        .and_then(handle_rollup_query);
+
+    // Distribution of served amounts per ingredient and location
+    let portion_route = warp::path!("portions")
+        .and(warp::get())
+        .and(warp::query::<PortionQuery>())
+        .and(with_db.clone())
+        .and_then(handle_portion_query);
+
+    // Share of servings within their target portion, with the trend
+    let compliance_route = warp::path!("portions" / "compliance")
+        .and(warp::get())
+        .and(warp::query::<ComplianceQuery>())
+        .and(with_db.clone())
+        .and_then(handle_compliance_query);
+
+    // Serves per weekday and hour, shaped for heatmap charts
+    let heatmap_route = warp::path("heatmap")
+        .and(warp::get())
+        .and(warp::query::<HeatmapQuery>())
+        .and(with_db.clone())
+        .and_then(handle_heatmap_query);
+
+    // Hours and days when a location served far more or less than usual
+    let anomaly_route = warp::path("anomalies")
+        .and(warp::get())
+        .and(warp::query::<AnomalyQuery>())
+        .and(with_db.clone())
+        .and_then(handle_anomaly_query);
+
+    // When each ingredient is expected to run out, and how past forecasts did
+    let forecast_route = warp::path!("forecast")
+        .and(warp::get())
+        .and(warp::query::<ForecastQuery>())
+        .and(with_db.clone())
+        .and_then(handle_forecast);
+
+    let backtest_route = warp::path!("forecast" / "backtest")
+        .and(warp::get())
+        .and(warp::query::<BacktestQuery>())
+        .and(with_db.clone())
+        .and_then(handle_forecast_backtest);
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .and(with_db.clone())
+        .and_then(handle_metrics);
+
+    // Health check route, liveness only
     let health_route = warp::path("health").and(warp::get()).map(|| "OK");
 
+    // Readiness route, checks that Firestore is reachable
+    let ready_route = warp::path("ready")
+        .and(warp::get())
+        .and(with_db.clone())
+        .and_then(handle_readiness);
+
     // Root route for basic requests
     let root_route = warp::path::end()
         .and(warp::any())
         .map(|| "Data Aggregation Service is running");
 
-    let routes = aggregation_route
-        .or(health_route)
-        .or(root_route)
-        .or(locations_route)
-        .or(data_route)
-        .recover(handle_rejection);
+    let routes = telemetry::request_id()
+        .and(
+            aggregation_route
+                .or(health_route)
+                .or(ready_route)
+                .or(root_route)
+                .or(locations_route)
+                .or(data_route)
+                .or(inventory_route)
+                .or(uptime_route)
+                .or(stockout_route)
+                .or(anomaly_route)
+                .or(heatmap_route)
+                .or(rollup_route)
+                .or(portion_route)
+                .or(compliance_route)
+                .or(forecast_route)
+                .or(backtest_route)
+                .or(metrics_route)
+                .or(job_route)
+                .or(ingest_route)
+                .or(pubsub_route)
+                .or(quarantine_list_route)
+                .or(quarantine_fix_route)
+                .or(quarantine_replay_route)
+                .or(device_skew_route)
+                .or(device_status_route)
+                .or(device_check_route)
+                .recover(handle_rejection),
+        )
+        .map(|request_id: String, reply| {
+            warp::reply::with_header(reply, telemetry::REQUEST_ID_HEADER, request_id)
+        })
+        .with(warp::log::custom(|info| {
+            metrics::observe_request(info.path(), info.status().as_u16(), info.elapsed());
+            tracing::info!(
+                status = info.status().as_u16(),
+                latency_ms = info.elapsed().as_millis() as u64,
+                "Request completed"
+            );
+        }))
+        .with(warp::trace(telemetry::request_span));
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            tracing::info!("Shutdown signal received, no longer accepting requests");
+            shutdown.trigger();
+        }
+    });
+
+    tracing::info!(port = 8080, "Server starting");
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 8080), {
+        let shutdown = shutdown.clone();
+        async move { shutdown.triggered().await }
+    });
 
-    println!("Server starting on port 8080");
-    warp::serve(routes).run(([0, 0, 0, 0], 8080)).await;
+    if shutdown::drain(server, &shutdown, shutdown_timeout()).await {
+        tracing::info!("Server stopped");
+    } else {
+        tracing::warn!("Shutdown deadline exceeded, exiting with requests still in flight");
+    }
+    telemetry.shutdown();
 
     Ok(())
 }
 
-async fn run_aggregation_handler(db: FirestoreDb) -> Result<impl Reply, Rejection> {
-    match process_aggregations(&db).await {
-        Ok(_) => {
-            println!("Data aggregation completed successfully");
-            Ok(warp::reply::with_status(
-                "Success",
-                warp::http::StatusCode::OK,
-            ))
+async fn run_aggregation_handler(
+    db: FirestoreDb,
+    shutdown: Shutdown,
+) -> Result<impl Reply, Rejection> {
+    match start_aggregation_job(&db, &shutdown).await {
+        Ok(job) => Ok(warp::reply::with_status(
+            warp::reply::json(&job),
+            warp::http::StatusCode::ACCEPTED,
+        )),
+        Err(e @ Error::LeaseHeld { .. }) => {
+            tracing::warn!(error = %e, "Aggregation already running");
+            Err(warp::reject::custom(e))
         }
         Err(e) => {
-            eprintln!("Data aggregation failed: {:?}", e);
+            tracing::error!(error = ?e, "Failed to start data aggregation");
             Err(warp::reject::custom(e))
         }
     }
 }
 
+async fn handle_job_query(id: String, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    match get_job(&db, &id).await? {
+        Some(job) => Ok(warp::reply::json(&job)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+async fn handle_ingest(body: serde_json::Value, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let readings = ingest::readings(body);
+    let limit = ingest_max_batch();
+    if readings.len() > limit {
+        return Err(warp::reject::custom(Error::BatchTooLarge {
+            size: readings.len(),
+            limit,
+        }));
+    }
+    let response = ingest::ingest(&db, readings, ingest_max_future_skew()).await?;
+    Ok(warp::reply::json(&response))
+}
+
+async fn handle_pubsub_push(body: Bytes, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let result = pubsub::handle_push_body(&db, &body, ingest_max_future_skew()).await?;
+    Ok(warp::reply::json(&result))
+}
+
+#[derive(serde::Deserialize)]
+struct QuarantineQuery {
+    limit: Option<u32>,
+}
+
+async fn handle_quarantine_list(
+    query: QuarantineQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let records = list_quarantined(&db, query.limit.unwrap_or(100)).await?;
+    Ok(warp::reply::json(&records))
+}
+
+async fn handle_quarantine_fix(
+    id: String,
+    document: serde_json::Value,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    match quarantine::fix(&db, &id, document).await? {
+        Some(record) => Ok(warp::reply::json(&record)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+async fn handle_quarantine_replay(id: String, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    match quarantine::replay(&db, &id).await? {
+        Some(result) => Ok(warp::reply::json(&result)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SkewQuery {
+    skewed: Option<bool>,
+}
+
+async fn handle_device_skew(query: SkewQuery, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let devices = device_skew(&db, clock_skew_threshold())
+        .await?
+        .into_iter()
+        .filter(|device| query.skewed.is_none_or(|skewed| device.skewed == skewed))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&devices))
+}
+
+#[derive(serde::Deserialize)]
+struct StatusQuery {
+    stale: Option<bool>,
+}
+
+async fn handle_device_status(
+    query: StatusQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let statuses = device_statuses(&db, query.stale).await?;
+    Ok(warp::reply::json(&statuses))
+}
+
+async fn handle_device_check(db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let report = check_devices(&db).await?;
+    Ok(warp::reply::json(&report))
+}
+
 async fn handle_location_query(
     param: LocationQuery,
     db: FirestoreDb,
@@ -108,18 +438,112 @@ async fn handle_location_query(
             Ok(warp::reply::with_status(reply, warp::http::StatusCode::OK))
         }
         Err(e) => {
-            eprintln!("Location query failed: {:?}", e);
+            tracing::error!(error = ?e, "Location query failed");
             Err(warp::reject::custom(e))
         }
     }
 }
 
 async fn handle_data_query(query: DataQuery, db: FirestoreDb) -> Result<impl Reply, Rejection> {
-    let data = query.run_query(&db).await?;
+    let data = query.run_query(&db, &portion_targets()).await?;
     let reply = warp::reply::json(&data);
     Ok(warp::reply::with_status(reply, warp::http::StatusCode::OK))
 }
 
+async fn handle_inventory_query(
+    query: InventoryQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let items = query.run_query(&db, inventory_low_fraction()).await?;
+    Ok(warp::reply::json(&items))
+}
+
+async fn handle_uptime_query(query: UptimeQuery, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let report = query.run_query(&db).await?;
+    Ok(warp::reply::json(&report))
+}
+
+async fn handle_stockout_query(
+    query: StockoutQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let summary = query.run_query(&db, &location_hours()).await?;
+    Ok(warp::reply::json(&summary))
+}
+
+async fn handle_rollup_query(query: RollupQuery, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let rollups = query.run_query(&db).await?;
+    Ok(warp::reply::json(&rollups))
+}
+
+async fn handle_portion_query(
+    query: PortionQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let report = query.run_query(&db, &portion_bins()).await?;
+    Ok(warp::reply::json(&report))
+}
+
+async fn handle_compliance_query(
+    query: ComplianceQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let summary = query.run_query(&db, &portion_targets()).await?;
+    Ok(warp::reply::json(&summary))
+}
+
+async fn handle_heatmap_query(
+    query: HeatmapQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let charts = query.run_query(&db).await?;
+    Ok(warp::reply::json(&charts))
+}
+
+async fn handle_anomaly_query(
+    query: AnomalyQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let anomalies = query.run_query(&db).await?;
+    Ok(warp::reply::json(&anomalies))
+}
+
+async fn handle_forecast(query: ForecastQuery, db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let forecasts = forecast_run_outs(&db, &query, forecast_lookback()).await?;
+    Ok(warp::reply::json(&forecasts))
+}
+
+async fn handle_forecast_backtest(
+    query: BacktestQuery,
+    db: FirestoreDb,
+) -> Result<impl Reply, Rejection> {
+    let report = backtest_forecasts(&db, &query, forecast_lookback()).await?;
+    Ok(warp::reply::json(&report))
+}
+
+async fn handle_readiness(db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let readiness = check_readiness(&db, readiness_timeout()).await;
+    let status = if readiness.is_ready() {
+        warp::http::StatusCode::OK
+    } else {
+        tracing::warn!(readiness = ?readiness, "Readiness check failed");
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+async fn handle_metrics(db: FirestoreDb) -> Result<impl Reply, Rejection> {
+    let body = metrics::render(&db).await?;
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        metrics::TEXT_FORMAT,
+    ))
+}
+
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
     if err.is_not_found() {
         Ok(warp::reply::with_status(
@@ -131,6 +555,34 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
             "Invalid query: unknown field or wrong type",
             warp::http::StatusCode::BAD_REQUEST,
         ))
+    } else if let Some(Error::ShuttingDown) = err.find::<Error>() {
+        Ok(warp::reply::with_status(
+            "Service is shutting down",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    } else if err.find::<warp::body::BodyDeserializeError>().is_some() {
+        Ok(warp::reply::with_status(
+            "Invalid body: expected a JSON reading or array of readings",
+            warp::http::StatusCode::BAD_REQUEST,
+        ))
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some()
+        || matches!(err.find::<Error>(), Some(Error::BatchTooLarge { .. }))
+    {
+        Ok(warp::reply::with_status(
+            "Too many readings in one request, split the batch",
+            warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+        ))
+    } else if let Some(Error::InvalidDocument(reason)) = err.find::<Error>() {
+        tracing::warn!(reason = %reason, "Rejected invalid document");
+        Ok(warp::reply::with_status(
+            "Document is not a valid reading",
+            warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+        ))
+    } else if let Some(Error::LeaseHeld { .. } | Error::LeaseLost { .. }) = err.find::<Error>() {
+        Ok(warp::reply::with_status(
+            "Another aggregation run is in progress",
+            warp::http::StatusCode::CONFLICT,
+        ))
     } else {
         Err(err)
     }
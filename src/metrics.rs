@@ -0,0 +1,249 @@
+use crate::error::Error;
+use crate::firestore::client::fetch_latest_entry_timestamp;
+use crate::firestore::metadata::fetch_metadata;
+use chrono::Utc;
+use firestore::FirestoreDb;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, Encoder, Gauge, Histogram, HistogramVec,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+pub use prometheus::TEXT_FORMAT;
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests handled, by route and status code",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency, by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static FIRESTORE_CALL_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "firestore_call_duration_seconds",
+        "Firestore call latency, by operation",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+static FIRESTORE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "firestore_errors_total",
+        "Failed Firestore calls, by operation",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+static AGGREGATION_ENTRIES: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "aggregation_entries_processed",
+        "Entries processed per aggregation run",
+        exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+static DUPLICATES_DROPPED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "aggregation_duplicates_dropped_total",
+        "Duplicate readings dropped before aggregation"
+    )
+    .unwrap()
+});
+
+static INGESTED_READINGS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "ingest_readings_total",
+        "Readings received by /ingest, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static INVALID_DOCUMENTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "query_invalid_documents_skipped_total",
+        "Documents skipped by data queries because they do not match the LibraData schema"
+    )
+    .unwrap()
+});
+
+static QUARANTINED_DOCUMENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "documents_quarantined_total",
+        "Documents moved to quarantine because they do not match the LibraData schema, by source collection",
+        &["source"]
+    )
+    .unwrap()
+});
+
+static LAST_SUCCESS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "aggregation_last_success_timestamp_seconds",
+        "Unix time of the last successful aggregation run"
+    )
+    .unwrap()
+});
+
+static SINCE_LAST_SUCCESS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "aggregation_seconds_since_last_success",
+        "Seconds since the last successful aggregation run"
+    )
+    .unwrap()
+});
+
+static WATERMARK_LAG: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "aggregation_watermark_lag_seconds",
+        "How far the newest reading in libra is ahead of the last processed watermark"
+    )
+    .unwrap()
+});
+
+/// Collapses a request path to a bounded route label so scanners probing
+/// random URLs cannot blow up the series count.
+pub fn route_label(path: &str, status: u16) -> String {
+    if status == 404 {
+        return "unmatched".to_string();
+    }
+    match path.trim_start_matches('/').split('/').next() {
+        Some("") | None => "/".to_string(),
+        Some(segment) => format!("/{segment}"),
+    }
+}
+
+pub fn observe_request(path: &str, status: u16, elapsed: Duration) {
+    let route = route_label(path, status);
+    HTTP_REQUESTS
+        .with_label_values(&[route.as_str(), &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route.as_str()])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Times a Firestore call and counts it as an error if it fails. The call runs in
+/// its own span so it is attributed to the request or aggregation run that made it.
+pub async fn observe_firestore<T, E, F>(operation: &str, call: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let result = call
+        .instrument(tracing::info_span!("firestore", operation))
+        .await;
+    FIRESTORE_CALL_DURATION
+        .with_label_values(&[operation])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        FIRESTORE_ERRORS.with_label_values(&[operation]).inc();
+    }
+    result
+}
+
+pub fn record_invalid_document() {
+    INVALID_DOCUMENTS.inc();
+}
+
+pub fn record_quarantined(source: &str) {
+    QUARANTINED_DOCUMENTS.with_label_values(&[source]).inc();
+}
+
+pub fn record_duplicates_dropped(duplicates: usize) {
+    DUPLICATES_DROPPED.inc_by(duplicates as u64);
+}
+
+pub fn record_ingested(result: &str) {
+    INGESTED_READINGS.with_label_values(&[result]).inc();
+}
+
+pub fn record_aggregation_success(entries_processed: usize) {
+    AGGREGATION_ENTRIES.observe(entries_processed as f64);
+    LAST_SUCCESS.set(Utc::now().timestamp() as f64);
+}
+
+async fn update_watermark_lag(db: &FirestoreDb) -> Result<(), Error> {
+    let Some(metadata) = fetch_metadata(db).await? else {
+        return Ok(());
+    };
+    if let Some(latest) = fetch_latest_entry_timestamp(db).await? {
+        let lag = (latest - metadata.last_processed.timestamp).num_milliseconds() as f64 / 1000.0;
+        WATERMARK_LAG.set(lag.max(0.0));
+    }
+    Ok(())
+}
+
+/// Renders all metrics in the Prometheus text format, refreshing the derived gauges first.
+pub async fn render(db: &FirestoreDb) -> Result<String, Error> {
+    if let Err(e) = update_watermark_lag(db).await {
+        tracing::warn!(error = ?e, "Failed to compute watermark lag");
+    }
+    let last_success = LAST_SUCCESS.get();
+    if last_success > 0.0 {
+        SINCE_LAST_SUCCESS.set(Utc::now().timestamp() as f64 - last_success);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_labels_routes_by_first_segment() {
+        assert_eq!(route_label("/", 200), "/");
+        assert_eq!(route_label("/data", 200), "/data");
+        assert_eq!(route_label("/jobs/abc", 200), "/jobs");
+        assert_eq!(route_label("/wp-admin/setup.php", 404), "unmatched");
+    }
+
+    #[tokio::test]
+    async fn it_counts_failed_firestore_calls() {
+        let ok: Result<(), ()> = observe_firestore("test_ok", async { Ok(()) }).await;
+        let err: Result<(), ()> = observe_firestore("test_err", async { Err(()) }).await;
+        assert!(ok.is_ok() && err.is_err());
+
+        assert_eq!(FIRESTORE_ERRORS.with_label_values(&["test_ok"]).get(), 0);
+        assert_eq!(FIRESTORE_ERRORS.with_label_values(&["test_err"]).get(), 1);
+        assert_eq!(
+            FIRESTORE_CALL_DURATION
+                .with_label_values(&["test_err"])
+                .get_sample_count(),
+            1
+        );
+    }
+
+    #[test]
+    fn it_exports_request_metrics_in_text_format() {
+        observe_request("/health", 200, Duration::from_millis(3));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(r#"http_requests_total{route="/health",status="200"}"#));
+        assert!(text.contains("http_request_duration_seconds_bucket"));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::error::Error;
+use crate::firestore::client::FirestoreLibraData;
+use crate::firestore::schema::{parse_reading, CURRENT_SCHEMA_VERSION, SCHEMA_VERSION_FIELD};
+use crate::metrics::observe_firestore;
+use firestore::{FirestoreDb, FirestoreSimpleBatchWriter};
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Firestore rejects batches with more writes than this.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub scanned: usize,
+    pub current: usize,
+    pub migrated: usize,
+    /// Documents that could not be upgraded; they are left untouched and will
+    /// be quarantined the next time they are read.
+    pub invalid: usize,
+}
+
+#[derive(Debug)]
+pub enum Migration {
+    Current,
+    Upgrade {
+        id: String,
+        data: FirestoreLibraData,
+    },
+    Invalid {
+        id: String,
+        error: String,
+    },
+}
+
+/// Decides what to do with one stored document. Unversioned documents in the
+/// current layout are rewritten too, to stamp them with `schemaVersion`.
+pub fn plan(document: Value) -> Migration {
+    let id = document
+        .get("_firestore_id")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let version = document.get(SCHEMA_VERSION_FIELD).and_then(Value::as_u64);
+    if version == Some(CURRENT_SCHEMA_VERSION) {
+        return Migration::Current;
+    }
+    match parse_reading(document) {
+        Ok(data) => Migration::Upgrade { id, data },
+        Err(error) => Migration::Invalid { id, error },
+    }
+}
+
+async fn write_batch(
+    db: &FirestoreDb,
+    writer: &FirestoreSimpleBatchWriter,
+    pending: &mut Vec<(String, FirestoreLibraData)>,
+) -> Result<(), Error> {
+    let mut batch = writer.new_batch();
+    for (id, data) in pending.iter() {
+        db.fluent()
+            .update()
+            .in_col("libra")
+            .document_id(id)
+            .object(data)
+            .add_to_batch(&mut batch)?;
+    }
+    observe_firestore("migrate_batch", batch.write()).await?;
+    tracing::info!(documents = pending.len(), "Migrated batch");
+    pending.clear();
+    Ok(())
+}
+
+/// Rewrites every `libra` document older than the current schema version in
+/// place, `batch_size` documents per write. With `dry_run` nothing is written.
+#[tracing::instrument(name = "migrate_libra", skip(db))]
+pub async fn migrate_libra(
+    db: &FirestoreDb,
+    batch_size: usize,
+    dry_run: bool,
+) -> Result<MigrationReport, Error> {
+    let batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+    let writer = db.create_simple_batch_writer().await?;
+    let mut documents = db
+        .fluent()
+        .select()
+        .from("libra")
+        .obj::<Value>()
+        .stream_query_with_errors()
+        .await?;
+
+    let mut report = MigrationReport::default();
+    let mut pending = Vec::with_capacity(batch_size);
+    while let Some(document) = documents.next().await {
+        report.scanned += 1;
+        match plan(document?) {
+            Migration::Current => report.current += 1,
+            Migration::Upgrade { id, data } => {
+                report.migrated += 1;
+                if !dry_run {
+                    pending.push((id, data));
+                    if pending.len() == batch_size {
+                        write_batch(db, &writer, &mut pending).await?;
+                    }
+                }
+            }
+            Migration::Invalid { id, error } => {
+                tracing::warn!(document_id = %id, error = %error, "Cannot migrate document");
+                report.invalid += 1;
+            }
+        }
+    }
+    if !pending.is_empty() {
+        write_batch(db, &writer, &mut pending).await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_plans_upgrades_only_for_outdated_documents() {
+        let legacy = json!({
+            "_firestore_id": "legacy",
+            "serialNumber": "Lib298190",
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "action": "Served",
+            "amount": 12.5,
+            "timestamp": "2025-06-01T12:00:00Z",
+        });
+        match plan(legacy) {
+            Migration::Upgrade { id, data } => {
+                assert_eq!(id, "legacy");
+                assert_eq!(data.device.serial_number, "Lib298190");
+            }
+            other => panic!("expected an upgrade, got {other:?}"),
+        }
+
+        let current = json!({
+            "_firestore_id": "current",
+            "device": { "model": "LibraV0", "serialNumber": "Lib298190" },
+            "location": "Caldo Office",
+            "ingredient": "Popcorn",
+            "dataAction": "Served",
+            "amount": 12.5,
+            "timestamp": "2025-06-01T12:00:00Z",
+            "schemaVersion": CURRENT_SCHEMA_VERSION,
+        });
+        assert!(matches!(plan(current), Migration::Current));
+
+        let broken = json!({ "_firestore_id": "broken", "amount": "lots" });
+        assert!(matches!(plan(broken), Migration::Invalid { id, .. } if id == "broken"));
+    }
+}
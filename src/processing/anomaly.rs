@@ -0,0 +1,311 @@
+use crate::processing::hours::LocationHours;
+use crate::processing::timestamp_utc;
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, Timelike, Utc};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Scales the median absolute deviation to match the standard deviation of
+/// normally distributed counts.
+const MAD_SCALE: f64 = 1.4826;
+/// Buckets with less history than this many weeks are not scored.
+const MIN_BASELINE_WEEKS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Hourly,
+    Daily,
+}
+
+impl Granularity {
+    /// Start of the local bucket `local` falls in.
+    pub fn bucket(&self, local: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Granularity::Hourly => local.date().and_hms_opt(local.hour(), 0, 0).unwrap(),
+            Granularity::Daily => local.date().and_time(NaiveTime::MIN),
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        match self {
+            Granularity::Hourly => Duration::hours(1),
+            Granularity::Daily => Duration::days(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+/// When an anomaly is scored, and how far from the baseline it must be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectionSettings {
+    /// Weeks of the same weekday and hour the baseline is taken from.
+    pub baseline_weeks: usize,
+    /// Robust score from which a bucket is a warning; twice that is critical.
+    pub threshold: f64,
+}
+
+/// A bucket of a location's `Served` counts far from its seasonal baseline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Anomaly {
+    pub location: String,
+    pub granularity: Granularity,
+    /// Start of the bucket in the location's timezone, and as an instant.
+    pub local_start: NaiveDateTime,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    /// Median of the same bucket in earlier weeks.
+    pub baseline: f64,
+    /// Deviation from the baseline in robust standard deviations; negative for
+    /// drops.
+    pub score: f64,
+    pub severity: Severity,
+    /// Whether its alert was delivered; later runs retry until it is.
+    #[serde(default)]
+    pub alerted: bool,
+    /// Set once readings that arrived late brought the bucket back within the
+    /// threshold.
+    #[serde(default)]
+    pub resolved: bool,
+}
+
+/// Location, granularity and start of the bucket an anomaly is in.
+pub type AnomalyKey = (String, Granularity, DateTime<Utc>);
+
+impl Anomaly {
+    pub fn key(&self) -> AnomalyKey {
+        (self.location.clone(), self.granularity, self.start)
+    }
+}
+
+/// Counts of `Served` readings per location and local bucket.
+pub fn served_series(
+    served: &[LibraData],
+    hours: &LocationHours,
+    granularity: Granularity,
+) -> HashMap<String, BTreeMap<NaiveDateTime, usize>> {
+    served
+        .iter()
+        .filter(|data| data.data_action == Action::Served)
+        .fold(HashMap::new(), |mut series, data| {
+            let timezone = hours.get(&data.location).timezone;
+            let local = timestamp_utc(data).with_timezone(&timezone).naive_local();
+            *series
+                .entry(data.location.clone())
+                .or_insert_with(BTreeMap::new)
+                .entry(granularity.bucket(local))
+                .or_insert(0) += 1;
+            series
+        })
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// The median of `baseline` and how many robust standard deviations `value`
+/// is from it. The spread is at least one, so flat baselines of small counts
+/// do not make every change an anomaly.
+pub fn robust_score(value: f64, baseline: &[f64]) -> Option<(f64, f64)> {
+    if baseline.is_empty() {
+        return None;
+    }
+    let mut sorted = baseline.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let center = median(&sorted);
+    let mut deviations = sorted
+        .iter()
+        .map(|v| (v - center).abs())
+        .collect::<Vec<_>>();
+    deviations.sort_by(f64::total_cmp);
+    let spread = (MAD_SCALE * median(&deviations)).max(1.0);
+    Some((center, (value - center) / spread))
+}
+
+/// Scores every completed bucket from `since` to `now` at every location in
+/// `series` against the same bucket in the weeks before, returning those past
+/// the threshold. Buckets without servings count as zero once a location has
+/// any history.
+pub fn detect_anomalies(
+    series: &HashMap<String, BTreeMap<NaiveDateTime, usize>>,
+    hours: &LocationHours,
+    granularity: Granularity,
+    settings: DetectionSettings,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    for (location, counts) in series {
+        let Some(first) = counts.keys().next().copied() else {
+            continue;
+        };
+        let opening = hours.get(location);
+        let local = |at: DateTime<Utc>| at.with_timezone(&opening.timezone).naive_local();
+        let current = granularity.bucket(local(now));
+        let count_at = |bucket: NaiveDateTime| counts.get(&bucket).copied().unwrap_or(0);
+
+        let mut bucket = granularity.bucket(local(since));
+        while bucket < current {
+            let baseline = (1..=settings.baseline_weeks)
+                .map(|weeks| bucket - Duration::weeks(weeks as i64))
+                .filter(|earlier| *earlier >= first)
+                .map(|earlier| count_at(earlier) as f64)
+                .collect::<Vec<_>>();
+            let count = count_at(bucket);
+            if baseline.len() >= MIN_BASELINE_WEEKS {
+                if let Some((center, score)) = robust_score(count as f64, &baseline) {
+                    let severity = if score.abs() >= 2.0 * settings.threshold {
+                        Some(Severity::Critical)
+                    } else if score.abs() >= settings.threshold {
+                        Some(Severity::Warning)
+                    } else {
+                        None
+                    };
+                    if let Some(severity) = severity {
+                        anomalies.push(Anomaly {
+                            location: location.clone(),
+                            granularity,
+                            local_start: bucket,
+                            start: opening.to_utc(bucket),
+                            count,
+                            baseline: center,
+                            score,
+                            severity,
+                            alerted: false,
+                            resolved: false,
+                        });
+                    }
+                }
+            }
+            bucket += granularity.step();
+        }
+    }
+    anomalies.sort_by_key(|anomaly| anomaly.start);
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z, a Monday
+
+    fn served(location: &str, hours: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: location.to_string(),
+            ingredient: String::from("Popcorn"),
+            data_action: Action::Served,
+            amount: 50.0,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + hours * 3600).unwrap(),
+        }
+    }
+
+    fn at(hours: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(START + hours * 3600, 0).unwrap()
+    }
+
+    const SETTINGS: DetectionSettings = DetectionSettings {
+        baseline_weeks: 4,
+        threshold: 3.5,
+    };
+
+    #[test]
+    fn it_scores_against_the_median_and_mad() {
+        let (center, score) = robust_score(2.0, &[20.0, 22.0, 18.0, 21.0]).unwrap();
+        assert_eq!(center, 20.5);
+        assert!(score < -5.0);
+        // A flat baseline of small counts tolerates small changes
+        let (_, score) = robust_score(2.0, &[0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(score, 2.0);
+        assert_eq!(robust_score(2.0, &[]), None);
+    }
+
+    #[test]
+    fn it_flags_a_drop_against_the_same_hour_in_earlier_weeks() {
+        let mut data = Vec::new();
+        // Four weeks of 20 servings at Monday noon at one office, ten at the other
+        for week in 0..4 {
+            for _ in 0..20 {
+                data.push(served("Caldo Office", week * 168 + 12));
+            }
+            for _ in 0..10 {
+                data.push(served("Google", week * 168 + 12));
+            }
+        }
+        // This Monday the first office's scale only reports twice
+        data.push(served("Caldo Office", 4 * 168 + 12));
+        data.push(served("Caldo Office", 4 * 168 + 12));
+        for _ in 0..10 {
+            data.push(served("Google", 4 * 168 + 12));
+        }
+
+        let hours = LocationHours::default();
+        let series = served_series(&data, &hours, Granularity::Hourly);
+        let anomalies = detect_anomalies(
+            &series,
+            &hours,
+            Granularity::Hourly,
+            SETTINGS,
+            at(4 * 168),
+            at(4 * 168 + 24),
+        );
+        assert_eq!(anomalies.len(), 1);
+        let anomaly = &anomalies[0];
+        assert_eq!(anomaly.location, "Caldo Office");
+        assert_eq!(anomaly.start, at(4 * 168 + 12));
+        assert_eq!(anomaly.count, 2);
+        assert_eq!(anomaly.baseline, 20.0);
+        assert_eq!(anomaly.severity, Severity::Critical);
+
+        // The daily series sees the same drop
+        let series = served_series(&data, &hours, Granularity::Daily);
+        let anomalies = detect_anomalies(
+            &series,
+            &hours,
+            Granularity::Daily,
+            SETTINGS,
+            at(4 * 168),
+            at(4 * 168 + 24),
+        );
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].granularity, Granularity::Daily);
+    }
+
+    #[test]
+    fn it_skips_the_current_bucket_and_short_history() {
+        let data = (0..2)
+            .flat_map(|week| (0..20).map(move |_| served("Caldo Office", week * 168 + 12)))
+            .collect::<Vec<_>>();
+        let hours = LocationHours::default();
+        let series = served_series(&data, &hours, Granularity::Hourly);
+        // Only two weeks of history
+        let anomalies = detect_anomalies(
+            &series,
+            &hours,
+            Granularity::Hourly,
+            SETTINGS,
+            at(2 * 168),
+            at(2 * 168 + 24),
+        );
+        assert!(anomalies.is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+use crate::processing::hours::LocationHours;
+use crate::processing::rollup::Period;
+use crate::processing::timestamp_utc;
+use chrono::NaiveDate;
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+
+/// The portion staff should serve of an ingredient, and how far off in grams a
+/// serving may be.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PortionTarget {
+    pub target: f64,
+    pub tolerance: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compliance {
+    Under,
+    Within,
+    Over,
+}
+
+/// Servings to pick by how they compared to their target; `outside` is under
+/// or over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortionFilter {
+    Under,
+    Within,
+    Over,
+    Outside,
+}
+
+impl PortionFilter {
+    pub fn matches(&self, compliance: Compliance) -> bool {
+        match self {
+            PortionFilter::Under => compliance == Compliance::Under,
+            PortionFilter::Within => compliance == Compliance::Within,
+            PortionFilter::Over => compliance == Compliance::Over,
+            PortionFilter::Outside => compliance != Compliance::Within,
+        }
+    }
+}
+
+impl PortionTarget {
+    pub fn check(&self, amount: f64) -> Compliance {
+        if amount < self.target - self.tolerance {
+            Compliance::Under
+        } else if amount > self.target + self.tolerance {
+            Compliance::Over
+        } else {
+            Compliance::Within
+        }
+    }
+}
+
+/// Target portions per ingredient, and per ingredient at a location where it
+/// differs there.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PortionTargets {
+    pub ingredients: HashMap<String, PortionTarget>,
+    /// By location and ingredient.
+    pub locations: HashMap<(String, String), PortionTarget>,
+}
+
+impl FromStr for PortionTargets {
+    type Err = String;
+
+    /// Parses a semicolon separated list of `Ingredient[@Location]=grams:tolerance`,
+    /// like `Popcorn=50:5;Popcorn@Caldo Office=40:5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut targets = PortionTargets::default();
+        for entry in s.split(';').filter(|entry| !entry.trim().is_empty()) {
+            let (name, portion) = entry.split_once('=').ok_or(format!(
+                "Expected Ingredient[@Location]=grams:tolerance, got {entry}"
+            ))?;
+            let (target, tolerance) = portion
+                .split_once(':')
+                .ok_or(format!("Expected grams:tolerance, got {portion}"))?;
+            let grams = |grams: &str| {
+                grams
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|grams| grams.is_finite() && *grams >= 0.0)
+                    .ok_or(format!("{grams} is not a number of grams"))
+            };
+            let target = PortionTarget {
+                target: grams(target)?,
+                tolerance: grams(tolerance)?,
+            };
+            match name.split_once('@') {
+                Some((ingredient, location)) => targets.locations.insert(
+                    (location.trim().to_string(), ingredient.trim().to_string()),
+                    target,
+                ),
+                None => targets.ingredients.insert(name.trim().to_string(), target),
+            };
+        }
+        Ok(targets)
+    }
+}
+
+impl PortionTargets {
+    /// The target of an ingredient at a location, if it has one.
+    pub fn get(&self, location: &str, ingredient: &str) -> Option<PortionTarget> {
+        self.locations
+            .get(&(location.to_string(), ingredient.to_string()))
+            .or_else(|| self.ingredients.get(ingredient))
+            .copied()
+    }
+
+    /// Every ingredient with a target somewhere, sorted.
+    pub fn ingredients(&self) -> Vec<String> {
+        self.ingredients
+            .keys()
+            .chain(self.locations.keys().map(|(_, ingredient)| ingredient))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// How servings compared to their target.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceCounts {
+    pub servings: usize,
+    pub within: usize,
+    pub under: usize,
+    pub over: usize,
+    /// Grams served beyond the target by servings over the tolerance.
+    pub over_grams: f64,
+}
+
+impl ComplianceCounts {
+    fn add(&mut self, target: &PortionTarget, amount: f64) {
+        self.servings += 1;
+        match target.check(amount) {
+            Compliance::Under => self.under += 1,
+            Compliance::Within => self.within += 1,
+            Compliance::Over => {
+                self.over += 1;
+                self.over_grams += amount - target.target;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &ComplianceCounts) {
+        self.servings += other.servings;
+        self.within += other.within;
+        self.under += other.under;
+        self.over += other.over;
+        self.over_grams += other.over_grams;
+    }
+
+    pub fn within_share(&self) -> Option<f64> {
+        (self.servings > 0).then(|| self.within as f64 / self.servings as f64)
+    }
+}
+
+/// Location, ingredient and local date.
+pub type ComplianceKey = (String, String, NaiveDate);
+
+/// Servings of one ingredient at one location on one local date, checked
+/// against the target in effect when they were aggregated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyCompliance {
+    pub location: String,
+    pub ingredient: String,
+    pub date: NaiveDate,
+    pub counts: ComplianceCounts,
+}
+
+impl DailyCompliance {
+    pub fn key(&self) -> ComplianceKey {
+        (self.location.clone(), self.ingredient.clone(), self.date)
+    }
+}
+
+/// Checks `Served` readings of ingredients with a target against it and adds
+/// them to their location's local date, continuing from `past_days`, returning
+/// the days changed.
+pub fn aggregate_compliance(
+    data: &[LibraData],
+    targets: &PortionTargets,
+    hours: &LocationHours,
+    past_days: &HashMap<ComplianceKey, DailyCompliance>,
+) -> HashMap<ComplianceKey, DailyCompliance> {
+    let mut days = HashMap::new();
+    for reading in data
+        .iter()
+        .filter(|data| data.data_action == Action::Served)
+    {
+        let Some(target) = targets.get(&reading.location, &reading.ingredient) else {
+            continue;
+        };
+        let date = hours
+            .get(&reading.location)
+            .local_date(timestamp_utc(reading));
+        let key = (reading.location.clone(), reading.ingredient.clone(), date);
+        days.entry(key)
+            .or_insert_with_key(|key| {
+                past_days
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| DailyCompliance {
+                        location: key.0.clone(),
+                        ingredient: key.1.clone(),
+                        date,
+                        counts: ComplianceCounts::default(),
+                    })
+            })
+            .counts
+            .add(&target, reading.amount);
+    }
+    days
+}
+
+/// Compliance over one period of a trend.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompliancePoint {
+    pub label: String,
+    pub start: NaiveDate,
+    #[serde(flatten)]
+    pub counts: ComplianceCounts,
+    pub within_share: Option<f64>,
+}
+
+/// Compliance of one ingredient at one location over a range of dates, with
+/// its trend per period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComplianceSummary {
+    pub location: String,
+    pub ingredient: String,
+    /// The target configured now; earlier days may have been checked against
+    /// another.
+    pub target: Option<PortionTarget>,
+    #[serde(flatten)]
+    pub counts: ComplianceCounts,
+    pub within_share: Option<f64>,
+    pub trend: Vec<CompliancePoint>,
+}
+
+/// One summary per location and ingredient of `days`, with the trend in
+/// `period`s.
+pub fn summarize_compliance(
+    days: &[DailyCompliance],
+    targets: &PortionTargets,
+    period: Period,
+) -> Vec<ComplianceSummary> {
+    let mut grouped: BTreeMap<(&str, &str), BTreeMap<NaiveDate, ComplianceCounts>> =
+        BTreeMap::new();
+    for day in days {
+        grouped
+            .entry((day.location.as_str(), day.ingredient.as_str()))
+            .or_default()
+            .entry(period.start(day.date))
+            .or_default()
+            .merge(&day.counts);
+    }
+    grouped
+        .into_iter()
+        .map(|((location, ingredient), periods)| {
+            let mut counts = ComplianceCounts::default();
+            let trend = periods
+                .into_iter()
+                .map(|(start, period_counts)| {
+                    counts.merge(&period_counts);
+                    CompliancePoint {
+                        label: period.label(start),
+                        start,
+                        within_share: period_counts.within_share(),
+                        counts: period_counts,
+                    }
+                })
+                .collect();
+            ComplianceSummary {
+                location: location.to_string(),
+                ingredient: ingredient.to_string(),
+                target: targets.get(location, ingredient),
+                within_share: counts.within_share(),
+                counts,
+                trend,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z, a Monday
+
+    fn served(location: &str, ingredient: &str, days: i64, amount: f64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: location.to_string(),
+            ingredient: ingredient.to_string(),
+            data_action: Action::Served,
+            amount,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + days * 86_400 + 43_200).unwrap(),
+        }
+    }
+
+    #[test]
+    fn it_parses_targets_with_location_overrides() {
+        let targets: PortionTargets = "Popcorn=50:5; Popcorn@Caldo Office=40:2".parse().unwrap();
+        let office = targets.get("Caldo Office", "Popcorn").unwrap();
+        assert_eq!(office.target, 40.0);
+        assert_eq!(targets.get("Google", "Popcorn").unwrap().tolerance, 5.0);
+        assert_eq!(targets.get("Google", "Almonds"), None);
+        assert_eq!(targets.ingredients(), vec!["Popcorn"]);
+        assert!("Popcorn=50".parse::<PortionTargets>().is_err());
+        assert!("Popcorn=fifty:5".parse::<PortionTargets>().is_err());
+    }
+
+    #[test]
+    fn it_checks_servings_against_their_target() {
+        let targets: PortionTargets = "Popcorn=50:5".parse().unwrap();
+        let hours = LocationHours::default();
+        let data = vec![
+            served("Caldo Office", "Popcorn", 0, 50.0),
+            served("Caldo Office", "Popcorn", 0, 55.0),
+            served("Caldo Office", "Popcorn", 0, 62.0),
+            served("Caldo Office", "Popcorn", 0, 40.0),
+            // No target, so not checked
+            served("Caldo Office", "Almonds", 0, 40.0),
+        ];
+        let days = aggregate_compliance(&data, &targets, &hours, &HashMap::new());
+        assert_eq!(days.len(), 1);
+        let day = days.values().next().unwrap();
+        assert_eq!(day.counts.servings, 4);
+        assert_eq!(day.counts.within, 2);
+        assert_eq!(day.counts.under, 1);
+        assert_eq!(day.counts.over, 1);
+        assert_eq!(day.counts.over_grams, 12.0);
+
+        // Later runs add to the stored day
+        let more = aggregate_compliance(
+            &[served("Caldo Office", "Popcorn", 0, 49.0)],
+            &targets,
+            &hours,
+            &days,
+        );
+        assert_eq!(more.values().next().unwrap().counts.within, 3);
+    }
+
+    #[test]
+    fn it_summarizes_the_trend_per_period() {
+        let targets: PortionTargets = "Popcorn=50:5".parse().unwrap();
+        let data = vec![
+            served("Caldo Office", "Popcorn", 0, 70.0),
+            served("Caldo Office", "Popcorn", 1, 50.0),
+            // The next week
+            served("Caldo Office", "Popcorn", 7, 50.0),
+        ];
+        let days =
+            aggregate_compliance(&data, &targets, &LocationHours::default(), &HashMap::new())
+                .into_values()
+                .collect::<Vec<_>>();
+
+        let summary = summarize_compliance(&days, &targets, Period::Week);
+        assert_eq!(summary.len(), 1);
+        let popcorn = &summary[0];
+        assert_eq!(popcorn.counts.servings, 3);
+        assert_eq!(popcorn.within_share, Some(2.0 / 3.0));
+        assert_eq!(popcorn.counts.over_grams, 20.0);
+        assert_eq!(popcorn.trend.len(), 2);
+        assert_eq!(popcorn.trend[0].label, "2025-W25");
+        assert_eq!(popcorn.trend[0].within_share, Some(0.5));
+        assert_eq!(popcorn.trend[1].within_share, Some(1.0));
+    }
+}
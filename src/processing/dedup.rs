@@ -0,0 +1,113 @@
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// Deterministic id for a reading, derived from the fields that make two readings
+/// the same: serial number, action, ingredient, amount and timestamp. Timestamps
+/// are truncated to microseconds, the precision Firestore stores.
+pub fn content_id(
+    serial_number: &str,
+    action: &Action,
+    ingredient: &str,
+    amount: f64,
+    timestamp_micros: i64,
+) -> String {
+    let action = serde_json::to_string(action).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    for field in [
+        serial_number.as_bytes(),
+        action.as_bytes(),
+        ingredient.as_bytes(),
+        &amount.to_bits().to_be_bytes(),
+        &timestamp_micros.to_be_bytes(),
+    ] {
+        // Length-prefix each field so ("ab", "c") and ("a", "bc") hash differently
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn reading_id(data: &LibraData) -> String {
+    content_id(
+        &data.device.serial_number,
+        &data.data_action,
+        &data.ingredient,
+        data.amount,
+        (data.timestamp.unix_timestamp_nanos() / 1000) as i64,
+    )
+}
+
+/// Drops readings with the same content as one earlier in `entries`, returning
+/// the remaining readings in their original order and how many were dropped.
+pub fn dedup(entries: Vec<LibraData>) -> (Vec<LibraData>, usize) {
+    let total = entries.len();
+    let mut seen = HashSet::with_capacity(total);
+    let unique = entries
+        .into_iter()
+        .filter(|entry| seen.insert(reading_id(entry)))
+        .collect::<Vec<_>>();
+    let dropped = total - unique.len();
+    (unique, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    fn noon() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_748_779_200).unwrap()
+    }
+
+    fn reading(serial_number: &str, amount: f64, timestamp: OffsetDateTime) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: serial_number.to_string(),
+            },
+            location: "kitchen".to_string(),
+            ingredient: "apple".to_string(),
+            data_action: Action::Served,
+            amount,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_dedup_drops_identical_readings() {
+        let timestamp = noon();
+        let data = vec![
+            reading("test-1", 10.0, timestamp),
+            reading("test-1", 10.0, timestamp),
+            reading("test-2", 10.0, timestamp),
+            reading("test-1", 12.0, timestamp),
+            reading("test-1", 10.0, timestamp + time::Duration::seconds(1)),
+            reading("test-1", 10.0, timestamp),
+        ];
+
+        let (unique, dropped) = dedup(data);
+        assert_eq!(dropped, 2);
+        assert_eq!(unique.len(), 4);
+        assert_eq!(unique[0].device.serial_number, "test-1");
+        assert_eq!(unique[1].device.serial_number, "test-2");
+    }
+
+    #[test]
+    fn test_reading_id_ignores_sub_microsecond_precision() {
+        let timestamp = noon() + time::Duration::microseconds(123_456);
+        let stored = reading("test-1", 10.0, timestamp);
+        let precise = reading("test-1", 10.0, timestamp + time::Duration::nanoseconds(789));
+        assert_eq!(reading_id(&stored), reading_id(&precise));
+        assert_eq!(reading_id(&stored).len(), 64);
+    }
+
+    #[test]
+    fn test_content_id_separates_fields() {
+        let a = content_id("ab", &Action::Served, "c", 1.0, 0);
+        let b = content_id("a", &Action::Served, "bc", 1.0, 0);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::processing::time::aggregate_hourly;
+use crate::processing::timestamp_utc;
+use chrono::{DateTime, Duration, DurationRound, Timelike, Utc};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Width of the confidence band in standard deviations of daily consumption,
+/// about 80% if daily consumption is normally distributed.
+const BAND_Z: f64 = 1.28;
+
+/// Location and ingredient.
+pub type ConsumptionKey = (String, String);
+
+/// Serial number and ingredient of a scale.
+pub type ScaleKey = (String, String);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConsumptionProfile {
+    /// Average amount served in each UTC hour of the day.
+    pub hourly: [f64; 24],
+    pub daily_mean: f64,
+    pub daily_std_dev: f64,
+}
+
+/// Groups `Served` readings by location and ingredient, each sorted by time.
+pub fn group_served(data: Vec<LibraData>) -> HashMap<ConsumptionKey, Vec<LibraData>> {
+    group_served_by(data, |data| {
+        (data.location.clone(), data.ingredient.clone())
+    })
+}
+
+/// Groups `Served` readings by scale, each sorted by time.
+pub fn group_served_by_scale(data: Vec<LibraData>) -> HashMap<ScaleKey, Vec<LibraData>> {
+    group_served_by(data, |data| {
+        (data.device.serial_number.clone(), data.ingredient.clone())
+    })
+}
+
+fn group_served_by(
+    data: Vec<LibraData>,
+    key: impl Fn(&LibraData) -> (String, String),
+) -> HashMap<(String, String), Vec<LibraData>> {
+    let mut groups = data
+        .into_iter()
+        .filter(|data| data.data_action == Action::Served)
+        .fold(HashMap::<_, Vec<_>>::new(), |mut groups, data| {
+            groups.entry(key(&data)).or_default().push(data);
+            groups
+        });
+    for group in groups.values_mut() {
+        group.sort_by_key(|data| data.timestamp);
+    }
+    groups
+}
+
+/// The part of a sorted group within `[since, until)`.
+pub fn window(group: &[LibraData], since: DateTime<Utc>, until: DateTime<Utc>) -> &[LibraData] {
+    let start = group.partition_point(|data| timestamp_utc(data) < since);
+    let end = group.partition_point(|data| timestamp_utc(data) < until);
+    &group[start..end.max(start)]
+}
+
+/// Consumption over `served`, the `Served` readings of one location and
+/// ingredient, or of one scale, from a window `days` long, spread over the day by the hourly
+/// profile of servings.
+pub fn consumption_profile(served: &[LibraData], days: i64) -> ConsumptionProfile {
+    let days = days.max(1);
+    let servings = aggregate_hourly(served, Action::Served, &HashMap::new());
+    let total = served.iter().map(|data| data.amount).sum::<f64>();
+    let mean_serving = if served.is_empty() {
+        0.0
+    } else {
+        total / served.len() as f64
+    };
+
+    let mut hourly = [0.0; 24];
+    for (hour, count) in servings {
+        hourly[hour as usize] = count as f64 * mean_serving / days as f64;
+    }
+
+    let by_date = served.iter().fold(HashMap::new(), |mut totals, data| {
+        *totals.entry(data.timestamp.date()).or_insert(0.0) += data.amount;
+        totals
+    });
+    let daily_mean = total / days as f64;
+    // Days without servings in the window count as zero consumption
+    let idle_days = (days as usize).saturating_sub(by_date.len()) as f64;
+    let variance = (by_date
+        .values()
+        .map(|total| (total - daily_mean).powi(2))
+        .sum::<f64>()
+        + idle_days * daily_mean.powi(2))
+        / days as f64;
+
+    ConsumptionProfile {
+        hourly,
+        daily_mean,
+        daily_std_dev: variance.sqrt(),
+    }
+}
+
+/// When the forecast expects an ingredient to run out, with the band it should
+/// fall in. `None` means not within the forecast horizon.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RunOutForecast {
+    pub expected: Option<DateTime<Utc>>,
+    pub earliest: Option<DateTime<Utc>>,
+    pub latest: Option<DateTime<Utc>>,
+}
+
+/// Steps through the hourly profile from `now`, consuming `scale` times the usual
+/// amount, until `remaining` is used up.
+fn run_out_at(
+    remaining: f64,
+    hourly: &[f64; 24],
+    scale: f64,
+    now: DateTime<Utc>,
+    horizon: Duration,
+) -> Option<DateTime<Utc>> {
+    if remaining <= 0.0 {
+        return Some(now);
+    }
+    let mut left = remaining;
+    let mut at = now;
+    while at < now + horizon {
+        let next_hour = at.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+        let rate = hourly[at.hour() as usize] * scale;
+        let consumed = rate * (next_hour - at).num_milliseconds() as f64 / 3_600_000.0;
+        if rate > 0.0 && consumed >= left {
+            return Some(at + Duration::milliseconds((left / rate * 3_600_000.0) as i64));
+        }
+        left -= consumed;
+        at = next_hour;
+    }
+    None
+}
+
+pub fn predict_run_out(
+    remaining: f64,
+    profile: &ConsumptionProfile,
+    now: DateTime<Utc>,
+    horizon: Duration,
+) -> RunOutForecast {
+    let spread = if profile.daily_mean > 0.0 {
+        BAND_Z * profile.daily_std_dev / profile.daily_mean
+    } else {
+        0.0
+    };
+    let at = |scale: f64| {
+        if scale > 0.0 {
+            run_out_at(remaining, &profile.hourly, scale, now, horizon)
+        } else {
+            None
+        }
+    };
+    RunOutForecast {
+        expected: at(1.0),
+        earliest: at(1.0 + spread),
+        latest: at(1.0 - spread),
+    }
+}
+
+/// A forecast made at a historical refill, next to the run-out that followed.
+#[derive(Debug, Serialize)]
+pub struct BacktestCase {
+    pub serial_number: String,
+    pub location: String,
+    pub ingredient: String,
+    pub refilled_at: DateTime<Utc>,
+    pub refilled: f64,
+    pub ran_out_at: DateTime<Utc>,
+    pub forecast: RunOutForecast,
+    /// Expected minus actual run-out time; positive if the forecast was late.
+    pub error_hours: Option<f64>,
+    pub within_band: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BacktestReport {
+    pub cases: Vec<BacktestCase>,
+    pub mean_absolute_error_hours: Option<f64>,
+    /// Share of run-outs that fell within the forecast band.
+    pub band_coverage: Option<f64>,
+}
+
+/// Replays the forecast at every refill that was followed by a run-out at or
+/// after `from`, using only the servings in the `lookback` before the refill.
+/// A refill stocks one scale, so only that scale's servings count towards its
+/// consumption. `served` is grouped as by `group_served_by_scale`; `resets`
+/// holds the `Refilled` and `RanOut` readings.
+pub fn backtest(
+    served: &HashMap<ScaleKey, Vec<LibraData>>,
+    resets: &[LibraData],
+    from: DateTime<Utc>,
+    lookback: Duration,
+    horizon: Duration,
+) -> BacktestReport {
+    let mut resets = resets.iter().collect::<Vec<_>>();
+    resets.sort_by_key(|data| data.timestamp);
+
+    let mut refills: HashMap<(&str, &str), &LibraData> = HashMap::new();
+    let mut cases = Vec::new();
+    for reset in resets {
+        let scale = (
+            reset.device.serial_number.as_str(),
+            reset.ingredient.as_str(),
+        );
+        match reset.data_action {
+            Action::Refilled => {
+                refills.insert(scale, reset);
+            }
+            Action::RanOut => {
+                let Some(refill) = refills.remove(&scale) else {
+                    continue;
+                };
+                let ran_out_at = timestamp_utc(reset);
+                if ran_out_at < from {
+                    continue;
+                }
+                let refilled_at = timestamp_utc(refill);
+                let group = served
+                    .get(&(
+                        refill.device.serial_number.clone(),
+                        refill.ingredient.clone(),
+                    ))
+                    .map_or(&[][..], |group| {
+                        window(group, refilled_at - lookback, refilled_at)
+                    });
+                let profile = consumption_profile(group, lookback.num_days());
+                let forecast = predict_run_out(refill.amount, &profile, refilled_at, horizon);
+                cases.push(BacktestCase {
+                    serial_number: refill.device.serial_number.clone(),
+                    location: refill.location.clone(),
+                    ingredient: refill.ingredient.clone(),
+                    refilled_at,
+                    refilled: refill.amount,
+                    ran_out_at,
+                    error_hours: forecast
+                        .expected
+                        .map(|expected| (expected - ran_out_at).num_seconds() as f64 / 3600.0),
+                    within_band: forecast
+                        .earliest
+                        .is_some_and(|earliest| earliest <= ran_out_at)
+                        && forecast.latest.is_none_or(|latest| ran_out_at <= latest),
+                    forecast,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let errors = cases
+        .iter()
+        .filter_map(|case| case.error_hours)
+        .collect::<Vec<_>>();
+    let mean_absolute_error_hours = (!errors.is_empty())
+        .then(|| errors.iter().map(|error| error.abs()).sum::<f64>() / errors.len() as f64);
+    let band_coverage = (!cases.is_empty())
+        .then(|| cases.iter().filter(|case| case.within_band).count() as f64 / cases.len() as f64);
+    BacktestReport {
+        cases,
+        mean_absolute_error_hours,
+        band_coverage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z
+
+    fn reading(action: Action, amount: f64, hours: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: String::from("Caldo Office"),
+            ingredient: String::from("Popcorn"),
+            data_action: action,
+            amount,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + hours * 3600).unwrap(),
+        }
+    }
+
+    fn at(hours: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(START + hours * 3600, 0).unwrap()
+    }
+
+    /// Two days of 100 served at noon and 50 at 18:00.
+    fn history() -> Vec<LibraData> {
+        let mut data = Vec::new();
+        for day in 0..2 {
+            data.push(reading(Action::Served, 50.0, day * 24 + 12));
+            data.push(reading(Action::Served, 50.0, day * 24 + 12));
+            data.push(reading(Action::Served, 50.0, day * 24 + 18));
+        }
+        data
+    }
+
+    #[test]
+    fn it_spreads_consumption_over_the_hourly_profile() {
+        let profile = consumption_profile(&history(), 2);
+        assert_eq!(profile.hourly[12], 100.0);
+        assert_eq!(profile.hourly[18], 50.0);
+        assert_eq!(profile.hourly[0], 0.0);
+        assert_eq!(profile.daily_mean, 150.0);
+        assert_eq!(profile.daily_std_dev, 0.0);
+    }
+
+    #[test]
+    fn it_predicts_run_out_within_the_hour_it_happens() {
+        let profile = consumption_profile(&history(), 2);
+
+        // 120 left at 06:00: 100 go around noon, the rest halfway through 18:00
+        let forecast = predict_run_out(120.0, &profile, at(54), Duration::days(7));
+        assert_eq!(forecast.expected, Some(at(66) + Duration::minutes(24)));
+        // Steady consumption gives no spread
+        assert_eq!(forecast.earliest, forecast.expected);
+        assert_eq!(forecast.latest, forecast.expected);
+
+        let never = predict_run_out(5000.0, &profile, at(54), Duration::days(7));
+        assert_eq!(never.expected, None);
+        assert_eq!(
+            predict_run_out(0.0, &profile, at(54), Duration::days(7)).expected,
+            Some(at(54))
+        );
+    }
+
+    #[test]
+    fn it_widens_the_band_with_uneven_days() {
+        let mut data = history();
+        data.push(reading(Action::Served, 150.0, 36));
+        let profile = consumption_profile(&data, 2);
+        assert!(profile.daily_std_dev > 0.0);
+
+        let forecast = predict_run_out(500.0, &profile, at(48), Duration::days(7));
+        assert!(forecast.earliest < forecast.expected);
+        assert!(forecast.expected < forecast.latest);
+    }
+
+    #[test]
+    fn it_backtests_forecasts_against_actual_run_outs() {
+        let mut data = history();
+        data.push(reading(Action::Served, 50.0, 60));
+        data.push(reading(Action::Served, 50.0, 60));
+        let served = group_served_by_scale(data);
+        let resets = vec![
+            reading(Action::Refilled, 150.0, 48),
+            reading(Action::RanOut, 0.0, 67),
+            // A run-out without a refill before it has nothing to compare
+            reading(Action::RanOut, 0.0, 70),
+        ];
+
+        let report = backtest(
+            &served,
+            &resets,
+            at(0),
+            Duration::days(2),
+            Duration::days(7),
+        );
+        assert_eq!(report.cases.len(), 1);
+        let case = &report.cases[0];
+        assert_eq!(case.forecast.expected, Some(at(67)));
+        assert_eq!(case.error_hours, Some(0.0));
+        assert!(case.within_band);
+        assert_eq!(report.mean_absolute_error_hours, Some(0.0));
+        assert_eq!(report.band_coverage, Some(1.0));
+    }
+
+    #[test]
+    fn it_backtests_each_scale_against_its_own_servings() {
+        // A second scale at the location serves just as much
+        let mut data = history();
+        data.extend(history().into_iter().map(|mut data| {
+            data.device.serial_number = String::from("Lib298191");
+            data
+        }));
+        let served = group_served_by_scale(data);
+        let resets = vec![
+            reading(Action::Refilled, 150.0, 48),
+            reading(Action::RanOut, 0.0, 67),
+        ];
+
+        let report = backtest(
+            &served,
+            &resets,
+            at(0),
+            Duration::days(2),
+            Duration::days(7),
+        );
+        assert_eq!(report.cases[0].forecast.expected, Some(at(67)));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::processing::hours::LocationHours;
+use crate::processing::timestamp_utc;
+use chrono::{Datelike, Timelike};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Firestore cannot nest arrays, so the 7×24 matrix is stored as a map from
+/// weekday to its 24 hours.
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Location and ingredient.
+pub type HeatmapKey = (String, String);
+
+/// Serves of one ingredient at one location per local weekday and hour.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub location: String,
+    pub ingredient: String,
+    pub serves: BTreeMap<String, Vec<usize>>,
+}
+
+impl Heatmap {
+    pub fn new(location: &str, ingredient: &str) -> Self {
+        Heatmap {
+            location: location.to_string(),
+            ingredient: ingredient.to_string(),
+            serves: WEEKDAYS
+                .iter()
+                .map(|weekday| (weekday.to_string(), vec![0; 24]))
+                .collect(),
+        }
+    }
+
+    /// Rows Monday to Sunday of 24 hours each.
+    pub fn matrix(&self) -> Vec<Vec<usize>> {
+        WEEKDAYS
+            .iter()
+            .map(|weekday| {
+                let mut row = self.serves.get(*weekday).cloned().unwrap_or_default();
+                row.resize(24, 0);
+                row
+            })
+            .collect()
+    }
+
+    fn add(&mut self, weekday: usize, hour: usize) {
+        let row = self
+            .serves
+            .entry(WEEKDAYS[weekday].to_string())
+            .or_default();
+        row.resize(24, 0);
+        row[hour] += 1;
+    }
+}
+
+/// Adds `Served` readings to the heatmaps of their location and ingredient, by
+/// weekday and hour in the location's timezone, returning the heatmaps changed.
+pub fn aggregate_heatmap(
+    data: &[LibraData],
+    hours: &LocationHours,
+    past_heatmaps: &HashMap<HeatmapKey, Heatmap>,
+) -> HashMap<HeatmapKey, Heatmap> {
+    data.iter()
+        .filter(|data| data.data_action == Action::Served)
+        .fold(HashMap::new(), |mut heatmaps, data| {
+            let key = (data.location.clone(), data.ingredient.clone());
+            let local = timestamp_utc(data).with_timezone(&hours.get(&data.location).timezone);
+            heatmaps
+                .entry(key)
+                .or_insert_with_key(|key| {
+                    past_heatmaps
+                        .get(key)
+                        .cloned()
+                        .unwrap_or_else(|| Heatmap::new(&key.0, &key.1))
+                })
+                .add(
+                    local.weekday().num_days_from_monday() as usize,
+                    local.hour() as usize,
+                );
+            heatmaps
+        })
+}
+
+/// A heatmap as charting libraries take it: `values[weekday][hour]` with the
+/// axis labels alongside.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeatmapChart {
+    pub location: String,
+    /// Absent when the chart covers every ingredient at the location.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingredient: Option<String>,
+    pub weekdays: Vec<&'static str>,
+    pub hours: Vec<u8>,
+    pub values: Vec<Vec<usize>>,
+    pub max: usize,
+}
+
+impl HeatmapChart {
+    pub fn new(location: String, ingredient: Option<String>, values: Vec<Vec<usize>>) -> Self {
+        HeatmapChart {
+            location,
+            ingredient,
+            weekdays: vec!["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"],
+            hours: (0..24).collect(),
+            max: values.iter().flatten().copied().max().unwrap_or(0),
+            values,
+        }
+    }
+
+    /// One chart per location with every ingredient's serves added up.
+    pub fn by_location<'a>(heatmaps: impl IntoIterator<Item = &'a Heatmap>) -> Vec<Self> {
+        let mut totals: BTreeMap<&str, Vec<Vec<usize>>> = BTreeMap::new();
+        for heatmap in heatmaps {
+            let total = totals
+                .entry(heatmap.location.as_str())
+                .or_insert_with(|| vec![vec![0; 24]; 7]);
+            for (row, counts) in total.iter_mut().zip(heatmap.matrix()) {
+                for (cell, count) in row.iter_mut().zip(counts) {
+                    *cell += count;
+                }
+            }
+        }
+        totals
+            .into_iter()
+            .map(|(location, values)| HeatmapChart::new(location.to_string(), None, values))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z, a Monday
+
+    fn reading(action: Action, location: &str, ingredient: &str, hours: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: location.to_string(),
+            ingredient: ingredient.to_string(),
+            data_action: action,
+            amount: 50.0,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + hours * 3600).unwrap(),
+        }
+    }
+
+    fn office_key() -> HeatmapKey {
+        ("Caldo Office".to_string(), "Popcorn".to_string())
+    }
+
+    #[test]
+    fn it_counts_serves_by_local_weekday_and_hour() {
+        let hours: LocationHours = "Caldo Office=America/Los_Angeles".parse().unwrap();
+        let data = vec![
+            // Monday 12:00 UTC is Monday 05:00 in Los Angeles
+            reading(Action::Served, "Caldo Office", "Popcorn", 12),
+            reading(Action::Served, "Caldo Office", "Popcorn", 12),
+            // Monday 03:00 UTC is still Sunday 20:00 there
+            reading(Action::Served, "Caldo Office", "Popcorn", 3),
+            reading(Action::RanOut, "Caldo Office", "Popcorn", 12),
+            // Saturday 18:00 in UTC
+            reading(Action::Served, "Google", "Almonds", 5 * 24 + 18),
+        ];
+        let heatmaps = aggregate_heatmap(&data, &hours, &HashMap::new());
+        let office = &heatmaps[&office_key()];
+        let matrix = office.matrix();
+        assert_eq!(matrix.len(), 7);
+        assert_eq!(matrix[0][5], 2);
+        assert_eq!(matrix[6][20], 1);
+        assert_eq!(matrix.iter().flatten().sum::<usize>(), 3);
+        let google = &heatmaps[&("Google".to_string(), "Almonds".to_string())];
+        assert_eq!(google.matrix()[5][18], 1);
+
+        // Later runs add to what is stored
+        let more = aggregate_heatmap(
+            &[reading(Action::Served, "Caldo Office", "Popcorn", 12)],
+            &hours,
+            &heatmaps,
+        );
+        assert_eq!(more.len(), 1);
+        assert_eq!(more[&office_key()].matrix()[0][5], 3);
+    }
+
+    #[test]
+    fn it_adds_up_ingredients_per_location() {
+        let data = vec![
+            reading(Action::Served, "Caldo Office", "Popcorn", 12),
+            reading(Action::Served, "Caldo Office", "Almonds", 12),
+            reading(Action::Served, "Caldo Office", "Almonds", 13),
+        ];
+        let heatmaps = aggregate_heatmap(&data, &LocationHours::default(), &HashMap::new());
+        let charts = HeatmapChart::by_location(heatmaps.values());
+        assert_eq!(charts.len(), 1);
+        let chart = &charts[0];
+        assert_eq!(chart.ingredient, None);
+        assert_eq!(chart.values[0][12], 2);
+        assert_eq!(chart.values[0][13], 1);
+        assert_eq!(chart.max, 2);
+        assert_eq!(chart.weekdays[0], "Mon");
+        assert_eq!(chart.hours.len(), 24);
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// When a location is open each day, in its own timezone. Opening and closing
+/// at the same time means open around the clock; closing before opening means
+/// closing after midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpeningHours {
+    pub timezone: Tz,
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+}
+
+impl Default for OpeningHours {
+    fn default() -> Self {
+        OpeningHours {
+            timezone: Tz::UTC,
+            open: NaiveTime::MIN,
+            close: NaiveTime::MIN,
+        }
+    }
+}
+
+impl FromStr for OpeningHours {
+    type Err = String;
+
+    /// Parses `Timezone HH:MM-HH:MM`, like `America/Los_Angeles 08:00-18:00`. The
+    /// hours may be left out for a location that is always open.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timezone, hours) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+        let timezone = timezone
+            .parse()
+            .map_err(|_| format!("Unknown timezone {timezone}"))?;
+        if hours.trim().is_empty() {
+            return Ok(OpeningHours {
+                timezone,
+                ..OpeningHours::default()
+            });
+        }
+        let (open, close) = hours
+            .trim()
+            .split_once('-')
+            .ok_or(format!("Expected HH:MM-HH:MM, got {hours}"))?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("Expected HH:MM, got {time}"))
+        };
+        Ok(OpeningHours {
+            timezone,
+            open: time(open)?,
+            close: time(close)?,
+        })
+    }
+}
+
+impl OpeningHours {
+    /// The local date `at` falls on.
+    pub fn local_date(&self, at: DateTime<Utc>) -> NaiveDate {
+        at.with_timezone(&self.timezone).date_naive()
+    }
+
+    /// Start of a local date, or the first moment after it if a DST change
+    /// skips midnight.
+    pub fn start_of(&self, date: NaiveDate) -> DateTime<Utc> {
+        self.local_time(date, NaiveTime::MIN)
+    }
+
+    fn local_time(&self, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+        self.to_utc(date.and_time(time))
+    }
+
+    /// The instant of a local time, or of the hour after it if a DST change
+    /// skips it.
+    pub fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        self.timezone
+            .from_local_datetime(&local)
+            .earliest()
+            // Skipped by a DST change: the hour after is the first that exists
+            .or_else(|| {
+                self.timezone
+                    .from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|local| local.with_timezone(&Utc))
+            .unwrap_or_else(|| local.and_utc())
+    }
+
+    /// The opening period that starts on a local date.
+    fn period(&self, date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        let close_date = if self.close <= self.open {
+            date + Duration::days(1)
+        } else {
+            date
+        };
+        let start = self.local_time(date, self.open);
+        let end = if self.open == self.close {
+            self.start_of(close_date)
+        } else {
+            self.local_time(close_date, self.close)
+        };
+        (start, end)
+    }
+
+    /// How much of `[from, to)` falls within opening hours.
+    pub fn open_time(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Duration {
+        if to <= from {
+            return Duration::zero();
+        }
+        // Periods that start the day before may run past midnight into `from`
+        let mut date = self.local_date(from) - Duration::days(1);
+        let last = self.local_date(to);
+        let mut total = Duration::zero();
+        while date <= last {
+            let (start, end) = self.period(date);
+            let overlap = end.min(to) - start.max(from);
+            if overlap > Duration::zero() {
+                total += overlap;
+            }
+            date += Duration::days(1);
+        }
+        total
+    }
+}
+
+/// Opening hours of each location; locations without an entry are always open
+/// and report in UTC.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocationHours(pub HashMap<String, OpeningHours>);
+
+impl FromStr for LocationHours {
+    type Err = String;
+
+    /// Parses a semicolon separated list of `Location=Timezone HH:MM-HH:MM`, like
+    /// `Caldo Office=America/Los_Angeles 08:00-18:00;Google=America/New_York`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (location, hours) = entry.split_once('=').ok_or(format!(
+                    "Expected Location=Timezone HH:MM-HH:MM, got {entry}"
+                ))?;
+                Ok((location.trim().to_string(), hours.parse()?))
+            })
+            .collect::<Result<_, _>>()
+            .map(LocationHours)
+    }
+}
+
+impl LocationHours {
+    pub fn get(&self, location: &str) -> OpeningHours {
+        self.0.get(location).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn it_parses_hours_per_location() {
+        let hours: LocationHours = "Caldo Office=America/Los_Angeles 08:00-18:00; Google=UTC"
+            .parse()
+            .unwrap();
+        let office = hours.get("Caldo Office");
+        assert_eq!(office.timezone, chrono_tz::America::Los_Angeles);
+        assert_eq!(office.open, NaiveTime::from_hms_opt(8, 0, 0).unwrap());
+        assert_eq!(hours.get("Google"), OpeningHours::default());
+        assert_eq!(hours.get("Elsewhere"), OpeningHours::default());
+        assert!("Caldo Office=Mars/Olympus"
+            .parse::<LocationHours>()
+            .is_err());
+        assert!("Caldo Office=UTC 8-18".parse::<LocationHours>().is_err());
+    }
+
+    #[test]
+    fn it_counts_only_time_within_opening_hours() {
+        let office: OpeningHours = "America/Los_Angeles 08:00-18:00".parse().unwrap();
+        // 06:00 to 12:00 local, of which four hours are open
+        let open = office.open_time(at("2025-06-16T13:00:00Z"), at("2025-06-16T19:00:00Z"));
+        assert_eq!(open, Duration::hours(4));
+        // A whole week is seven ten hour days
+        let open = office.open_time(at("2025-06-16T07:00:00Z"), at("2025-06-23T07:00:00Z"));
+        assert_eq!(open, Duration::hours(70));
+
+        let bar: OpeningHours = "UTC 18:00-02:00".parse().unwrap();
+        let open = bar.open_time(at("2025-06-16T00:00:00Z"), at("2025-06-17T00:00:00Z"));
+        assert_eq!(open, Duration::hours(8));
+
+        let always = OpeningHours::default();
+        let open = always.open_time(at("2025-06-16T00:30:00Z"), at("2025-06-18T00:00:00Z"));
+        assert_eq!(open, Duration::minutes(47 * 60 + 30));
+    }
+}
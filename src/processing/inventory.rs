@@ -0,0 +1,155 @@
+use crate::processing::timestamp_utc;
+use chrono::{DateTime, Utc};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Serial number and ingredient.
+pub type InventoryKey = (String, String);
+
+/// Estimated stock of one ingredient on one scale.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StockLevel {
+    pub serial_number: String,
+    pub location: String,
+    pub ingredient: String,
+    pub remaining: f64,
+    /// Amount put in at the last refill, zero if the scale has only run out.
+    pub refilled: f64,
+    /// Time of the last `Refilled` or `RanOut`. Readings from before it are
+    /// already accounted for by the reset.
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub reset_at: DateTime<Utc>,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl StockLevel {
+    /// Whether at most `fraction` of the last refill is left.
+    pub fn is_low(&self, fraction: f64) -> bool {
+        self.remaining <= self.refilled * fraction
+    }
+}
+
+/// Applies readings to the stock levels, returning the levels they changed.
+/// `Refilled` resets the level to its amount, `Served` takes its amount off and
+/// `RanOut` zeroes it. Levels start at the first reset seen; readings that
+/// arrive after a later reset was applied are ignored.
+pub fn aggregate_inventory(
+    data: &[LibraData],
+    past_levels: &HashMap<InventoryKey, StockLevel>,
+) -> HashMap<InventoryKey, StockLevel> {
+    let mut sorted = data.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|data| data.timestamp);
+
+    sorted.into_iter().fold(HashMap::new(), |mut levels, data| {
+        let at = timestamp_utc(data);
+        let key = (data.device.serial_number.clone(), data.ingredient.clone());
+        let current = levels.get(&key).or_else(|| past_levels.get(&key));
+        if current.is_some_and(|level| level.reset_at > at) {
+            return levels;
+        }
+        let current = current.cloned();
+        let reset = |remaining, refilled| StockLevel {
+            serial_number: key.0.clone(),
+            location: data.location.clone(),
+            ingredient: key.1.clone(),
+            remaining,
+            refilled,
+            reset_at: at,
+            updated_at: at,
+        };
+        let next = match (&data.data_action, current) {
+            (Action::Refilled, _) => Some(reset(data.amount, data.amount)),
+            (Action::RanOut, Some(level)) => Some(StockLevel {
+                remaining: 0.0,
+                reset_at: at,
+                updated_at: at,
+                ..level
+            }),
+            (Action::RanOut, None) => Some(reset(0.0, 0.0)),
+            (Action::Served, Some(level)) => Some(StockLevel {
+                remaining: (level.remaining - data.amount).max(0.0),
+                updated_at: level.updated_at.max(at),
+                ..level
+            }),
+            _ => None,
+        };
+        if let Some(level) = next {
+            levels.insert(key, level);
+        }
+        levels
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    fn reading(action: Action, amount: f64, minute: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: String::from("Caldo Office"),
+            ingredient: String::from("Popcorn"),
+            data_action: action,
+            amount,
+            timestamp: OffsetDateTime::from_unix_timestamp(1_750_000_000 + minute * 60).unwrap(),
+        }
+    }
+
+    fn key() -> InventoryKey {
+        (String::from("Lib298190"), String::from("Popcorn"))
+    }
+
+    #[test]
+    fn it_tracks_stock_through_refills_servings_and_run_outs() {
+        let data = vec![
+            reading(Action::Served, 10.0, 2),
+            reading(Action::Refilled, 500.0, 1),
+            reading(Action::Served, 120.0, 3),
+            reading(Action::Heartbeat, 0.0, 4),
+        ];
+        let levels = aggregate_inventory(&data, &HashMap::new());
+        let level = &levels[&key()];
+        assert_eq!(level.remaining, 370.0);
+        assert_eq!(level.refilled, 500.0);
+        assert!(!level.is_low(0.2));
+
+        let data = vec![
+            reading(Action::Served, 300.0, 5),
+            reading(Action::Served, 300.0, 6),
+        ];
+        let levels = aggregate_inventory(&data, &levels);
+        assert_eq!(levels[&key()].remaining, 0.0);
+        assert!(levels[&key()].is_low(0.2));
+
+        let levels = aggregate_inventory(&[reading(Action::RanOut, 0.0, 7)], &levels);
+        assert_eq!(levels[&key()].remaining, 0.0);
+        assert_eq!(levels[&key()].refilled, 500.0);
+    }
+
+    #[test]
+    fn it_ignores_readings_superseded_by_a_later_reset() {
+        let past = aggregate_inventory(&[reading(Action::Refilled, 400.0, 10)], &HashMap::new());
+
+        // Served and refilled before the last refill, but only arriving now
+        let late = aggregate_inventory(
+            &[
+                reading(Action::Served, 50.0, 5),
+                reading(Action::Refilled, 100.0, 6),
+            ],
+            &past,
+        );
+        assert!(late.is_empty());
+
+        // Servings before the first refill have nothing to count down from
+        let unknown = aggregate_inventory(&[reading(Action::Served, 50.0, 5)], &HashMap::new());
+        assert!(unknown.is_empty());
+    }
+}
@@ -1,3 +1,23 @@
 pub mod action;
+pub mod anomaly;
 pub mod category;
+pub mod compliance;
+pub mod dedup;
+pub mod forecast;
+pub mod heatmap;
+pub mod hours;
+pub mod inventory;
+pub mod portion;
+pub mod rollup;
+pub mod skew;
+pub mod stockout;
 pub mod time;
+pub mod uptime;
+
+use chrono::{DateTime, Utc};
+use menu::libra_data::LibraData;
+
+pub(crate) fn timestamp_utc(data: &LibraData) -> DateTime<Utc> {
+    DateTime::from_timestamp(data.timestamp.unix_timestamp(), data.timestamp.nanosecond())
+        .unwrap_or_default()
+}
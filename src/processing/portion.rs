@@ -0,0 +1,424 @@
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+/// Relative error of the sketch's quantiles.
+const SKETCH_ACCURACY: f64 = 0.01;
+/// Portions lighter than this many grams count as zero, which keeps the sketch
+/// from growing buckets for readings near zero.
+const MIN_AMOUNT: f64 = 0.1;
+
+/// Upper edges of the histogram bins in grams, in increasing order. Portions
+/// below the first edge go in the first bin and those from the last edge up in
+/// an extra last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortionBins(pub Vec<f64>);
+
+impl FromStr for PortionBins {
+    type Err = String;
+
+    /// Parses a comma separated list of grams, like `25,50,100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let edges = s
+            .split(',')
+            .filter(|edge| !edge.trim().is_empty())
+            .map(|edge| {
+                edge.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|edge| edge.is_finite() && *edge > 0.0)
+                    .ok_or(format!("Bin edge {edge} is not a positive number"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if edges.is_empty() {
+            return Err(String::from("Expected at least one bin edge"));
+        }
+        if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!("Bin edges {s} are not increasing"));
+        }
+        Ok(PortionBins(edges))
+    }
+}
+
+/// A DDSketch: counts of values in logarithmic buckets, so every quantile is
+/// within `SKETCH_ACCURACY` of a value in it, and sketches of disjoint readings
+/// merge into exactly the sketch of all of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PortionSketch {
+    pub count: usize,
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Values below `MIN_AMOUNT`.
+    pub zero_count: usize,
+    /// Index of the bucket `counts` starts at.
+    pub offset: i32,
+    pub counts: Vec<usize>,
+}
+
+fn gamma() -> f64 {
+    (1.0 + SKETCH_ACCURACY) / (1.0 - SKETCH_ACCURACY)
+}
+
+fn bucket_index(value: f64) -> i32 {
+    (value.ln() / gamma().ln()).ceil() as i32
+}
+
+/// The value a bucket stands for, within `SKETCH_ACCURACY` of all in it.
+fn bucket_value(index: i32) -> f64 {
+    2.0 * gamma().powi(index) / (gamma() + 1.0)
+}
+
+impl PortionSketch {
+    pub fn add(&mut self, value: f64) {
+        let value = value.max(0.0);
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        if value < MIN_AMOUNT {
+            self.zero_count += 1;
+        } else {
+            self.add_to_bucket(bucket_index(value), 1);
+        }
+    }
+
+    fn add_to_bucket(&mut self, index: i32, count: usize) {
+        if self.counts.is_empty() {
+            self.offset = index;
+        } else if index < self.offset {
+            let missing = (self.offset - index) as usize;
+            self.counts.splice(0..0, std::iter::repeat_n(0, missing));
+            self.offset = index;
+        }
+        let position = (index - self.offset) as usize;
+        if position >= self.counts.len() {
+            self.counts.resize(position + 1, 0);
+        }
+        self.counts[position] += count;
+    }
+
+    pub fn merge(&mut self, other: &PortionSketch) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = [self.min, other.min].into_iter().flatten().reduce(f64::min);
+        self.max = [self.max, other.max].into_iter().flatten().reduce(f64::max);
+        self.zero_count += other.zero_count;
+        for (index, count) in other.buckets() {
+            self.add_to_bucket(index, count);
+        }
+    }
+
+    /// Non-empty buckets with their indexes, lowest first.
+    fn buckets(&self) -> impl Iterator<Item = (i32, usize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(position, count)| (self.offset + position as i32, *count))
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// The value at quantile `q` between 0 and 1.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let (min, max) = (self.min?, self.max?);
+        let rank = q.clamp(0.0, 1.0) * (self.count - 1) as f64;
+        let mut seen = self.zero_count;
+        if seen as f64 > rank {
+            return Some(min);
+        }
+        for (index, count) in self.buckets() {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(bucket_value(index).clamp(min, max));
+            }
+        }
+        Some(max)
+    }
+}
+
+/// Counts of portions per bin, with `counts` one longer than `edges`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub edges: Vec<f64>,
+    pub counts: Vec<usize>,
+}
+
+impl Histogram {
+    pub fn new(bins: &PortionBins) -> Self {
+        Histogram {
+            edges: bins.0.clone(),
+            counts: vec![0; bins.0.len() + 1],
+        }
+    }
+
+    /// Counts the sketch's values in `bins`, each bucket by the value it stands
+    /// for. Portions within `SKETCH_ACCURACY` of an edge may land on either side.
+    pub fn from_sketch(bins: &PortionBins, sketch: &PortionSketch) -> Self {
+        let mut histogram = Histogram::new(bins);
+        histogram.counts[0] += sketch.zero_count;
+        for (index, count) in sketch.buckets() {
+            histogram.add(bucket_value(index), count);
+        }
+        histogram
+    }
+
+    fn add(&mut self, value: f64, count: usize) {
+        let bin = self.edges.partition_point(|edge| *edge <= value);
+        self.counts[bin] += count;
+    }
+
+    /// Adds another histogram with the same edges.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+}
+
+/// Location and ingredient.
+pub type PortionKey = (String, String);
+
+/// How much was served per `Served` reading of one ingredient at one location.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortionDistribution {
+    pub location: String,
+    pub ingredient: String,
+    pub histogram: Histogram,
+    pub sketch: PortionSketch,
+}
+
+impl PortionDistribution {
+    pub fn new(location: &str, ingredient: &str, bins: &PortionBins) -> Self {
+        PortionDistribution {
+            location: location.to_string(),
+            ingredient: ingredient.to_string(),
+            histogram: Histogram::new(bins),
+            sketch: PortionSketch::default(),
+        }
+    }
+
+    /// The histogram in `bins`, rebuilt from the sketch if it was kept in
+    /// others.
+    pub fn histogram_in(&self, bins: &PortionBins) -> Histogram {
+        if self.histogram.edges == bins.0 {
+            self.histogram.clone()
+        } else {
+            Histogram::from_sketch(bins, &self.sketch)
+        }
+    }
+}
+
+/// Adds the amounts of `Served` readings to the distributions of their
+/// location and ingredient, continuing from `past_distributions`, and returns
+/// the distributions changed. Histograms kept in other bins are rebuilt in
+/// `bins` first.
+pub fn aggregate_portions(
+    data: &[LibraData],
+    bins: &PortionBins,
+    past_distributions: &HashMap<PortionKey, PortionDistribution>,
+) -> HashMap<PortionKey, PortionDistribution> {
+    data.iter()
+        .filter(|data| data.data_action == Action::Served)
+        .fold(HashMap::new(), |mut distributions, data| {
+            let key = (data.location.clone(), data.ingredient.clone());
+            let distribution = distributions.entry(key).or_insert_with_key(|key| {
+                match past_distributions.get(key) {
+                    Some(past) => PortionDistribution {
+                        histogram: past.histogram_in(bins),
+                        ..past.clone()
+                    },
+                    None => PortionDistribution::new(&key.0, &key.1, bins),
+                }
+            });
+            distribution.histogram.add(data.amount.max(0.0), 1);
+            distribution.sketch.add(data.amount);
+            distributions
+        })
+}
+
+/// A histogram bin with its bounds in grams; the first has no lower bound and
+/// the last no upper one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PortionBin {
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub count: usize,
+}
+
+/// The distribution of portions of one ingredient at one location, or at all
+/// locations together when `location` is absent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PortionSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub ingredient: String,
+    pub count: usize,
+    pub mean: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub p10: Option<f64>,
+    pub p50: Option<f64>,
+    pub p90: Option<f64>,
+    pub p99: Option<f64>,
+    pub bins: Vec<PortionBin>,
+}
+
+impl PortionSummary {
+    pub fn new(
+        location: Option<String>,
+        ingredient: String,
+        histogram: &Histogram,
+        sketch: &PortionSketch,
+    ) -> Self {
+        let lower = std::iter::once(None).chain(histogram.edges.iter().copied().map(Some));
+        let upper = histogram.edges.iter().copied().map(Some).chain([None]);
+        PortionSummary {
+            location,
+            ingredient,
+            count: sketch.count,
+            mean: sketch.mean(),
+            min: sketch.min,
+            max: sketch.max,
+            p10: sketch.quantile(0.1),
+            p50: sketch.quantile(0.5),
+            p90: sketch.quantile(0.9),
+            p99: sketch.quantile(0.99),
+            bins: lower
+                .zip(upper)
+                .zip(&histogram.counts)
+                .map(|((from, to), count)| PortionBin {
+                    from,
+                    to,
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+
+    /// One summary per ingredient with the distributions at every location
+    /// merged.
+    pub fn by_ingredient<'a>(
+        distributions: impl IntoIterator<Item = &'a PortionDistribution>,
+        bins: &PortionBins,
+    ) -> Vec<Self> {
+        let mut merged: BTreeMap<&str, (Histogram, PortionSketch)> = BTreeMap::new();
+        for distribution in distributions {
+            let (histogram, sketch) = merged
+                .entry(distribution.ingredient.as_str())
+                .or_insert_with(|| (Histogram::new(bins), PortionSketch::default()));
+            histogram.merge(&distribution.histogram_in(bins));
+            sketch.merge(&distribution.sketch);
+        }
+        merged
+            .into_iter()
+            .map(|(ingredient, (histogram, sketch))| {
+                PortionSummary::new(None, ingredient.to_string(), &histogram, &sketch)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z
+
+    fn served(location: &str, ingredient: &str, amount: f64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: location.to_string(),
+            ingredient: ingredient.to_string(),
+            data_action: Action::Served,
+            amount,
+            timestamp: OffsetDateTime::from_unix_timestamp(START).unwrap(),
+        }
+    }
+
+    fn within_accuracy(estimate: Option<f64>, exact: f64) -> bool {
+        estimate.is_some_and(|estimate| (estimate - exact).abs() <= SKETCH_ACCURACY * exact)
+    }
+
+    #[test]
+    fn it_parses_bins() {
+        let bins: PortionBins = "25, 50,100".parse().unwrap();
+        assert_eq!(bins, PortionBins(vec![25.0, 50.0, 100.0]));
+        assert!("50,25".parse::<PortionBins>().is_err());
+        assert!("25,abc".parse::<PortionBins>().is_err());
+        assert!("".parse::<PortionBins>().is_err());
+    }
+
+    #[test]
+    fn it_estimates_quantiles_within_the_accuracy_and_merges_exactly() {
+        let mut whole = PortionSketch::default();
+        let mut first = PortionSketch::default();
+        let mut second = PortionSketch::default();
+        for value in 1..=1000 {
+            let value = value as f64;
+            whole.add(value);
+            if value <= 400.0 {
+                first.add(value);
+            } else {
+                second.add(value);
+            }
+        }
+        assert!(within_accuracy(whole.quantile(0.5), 500.0));
+        assert!(within_accuracy(whole.quantile(0.99), 990.0));
+        assert_eq!(whole.quantile(0.0), Some(1.0));
+        assert!(within_accuracy(whole.quantile(1.0), 1000.0));
+        assert_eq!(whole.mean(), Some(500.5));
+
+        // Sketches of separate runs add up to the sketch of all readings
+        second.merge(&first);
+        assert_eq!(second, whole);
+        assert_eq!(PortionSketch::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn it_bins_portions_per_location_and_ingredient() {
+        let bins: PortionBins = "25,50".parse().unwrap();
+        let data = vec![
+            served("Caldo Office", "Popcorn", 10.0),
+            served("Caldo Office", "Popcorn", 30.0),
+            served("Caldo Office", "Popcorn", 50.0),
+            served("Google", "Popcorn", 40.0),
+        ];
+        let distributions = aggregate_portions(&data, &bins, &HashMap::new());
+        let office = &distributions[&("Caldo Office".to_string(), "Popcorn".to_string())];
+        assert_eq!(office.histogram.counts, vec![1, 1, 1]);
+        assert_eq!(office.sketch.count, 3);
+
+        // Later runs continue, and stored histograms follow new bins
+        let bins: PortionBins = "20,45".parse().unwrap();
+        let more = aggregate_portions(
+            &[served("Caldo Office", "Popcorn", 35.0)],
+            &bins,
+            &distributions,
+        );
+        let office = &more[&("Caldo Office".to_string(), "Popcorn".to_string())];
+        assert_eq!(office.histogram.edges, vec![20.0, 45.0]);
+        assert_eq!(office.histogram.counts, vec![1, 2, 1]);
+
+        let summaries = PortionSummary::by_ingredient(distributions.values(), &bins);
+        assert_eq!(summaries.len(), 1);
+        let popcorn = &summaries[0];
+        assert_eq!(popcorn.count, 4);
+        assert_eq!(popcorn.bins.len(), 3);
+        assert_eq!(popcorn.bins[0].from, None);
+        assert_eq!(popcorn.bins[1].to, Some(45.0));
+        assert_eq!(popcorn.bins[1].count, 2);
+        assert_eq!(popcorn.bins[2].to, None);
+        assert_eq!(popcorn.min, Some(10.0));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::processing::hours::LocationHours;
+use crate::processing::timestamp_utc;
+use chrono::{Datelike, Duration, Months, NaiveDate};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Calendar periods servings are rolled up into, by local date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    /// ISO 8601 week, Monday to Sunday.
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    pub const ALL: [Period; 4] = [Period::Day, Period::Week, Period::Month, Period::Year];
+
+    /// First date of the period `date` falls in.
+    pub fn start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            Period::Month => date.with_day(1).unwrap(),
+            Period::Year => date.with_ordinal(1).unwrap(),
+        }
+    }
+
+    /// Last date of the period `date` falls in.
+    pub fn end(&self, date: NaiveDate) -> NaiveDate {
+        let start = self.start(date);
+        match self {
+            Period::Day => start,
+            Period::Week => start + Duration::days(6),
+            Period::Month => start
+                .checked_add_months(Months::new(1))
+                .unwrap()
+                .pred_opt()
+                .unwrap(),
+            Period::Year => NaiveDate::from_ymd_opt(start.year(), 12, 31).unwrap(),
+        }
+    }
+
+    /// Name of the period `date` falls in, like `2025-06-16`, `2025-W25`,
+    /// `2025-06` or `2025`. Weeks are numbered within their ISO year, which can
+    /// differ from the calendar year around New Year.
+    pub fn label(&self, date: NaiveDate) -> String {
+        match self {
+            Period::Day => date.format("%Y-%m-%d").to_string(),
+            Period::Week => {
+                let week = date.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            Period::Month => date.format("%Y-%m").to_string(),
+            Period::Year => date.format("%Y").to_string(),
+        }
+    }
+}
+
+/// Location, period and its first date.
+pub type RollupKey = (String, Period, NaiveDate);
+
+/// Servings at one location over one calendar period in its timezone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollup {
+    pub location: String,
+    pub period: Period,
+    pub label: String,
+    /// First and last local date of the period.
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub served: usize,
+    /// Grams served.
+    pub amount: f64,
+}
+
+impl Rollup {
+    pub fn new(location: &str, period: Period, date: NaiveDate) -> Self {
+        Rollup {
+            location: location.to_string(),
+            period,
+            label: period.label(date),
+            start: period.start(date),
+            end: period.end(date),
+            served: 0,
+            amount: 0.0,
+        }
+    }
+
+    pub fn key(&self) -> RollupKey {
+        (self.location.clone(), self.period, self.start)
+    }
+}
+
+/// Adds `Served` readings to the day, week, month and year of their local date
+/// at their location, continuing from `past_rollups`, and returns the rollups
+/// changed.
+pub fn aggregate_rollups(
+    data: &[LibraData],
+    hours: &LocationHours,
+    past_rollups: &HashMap<RollupKey, Rollup>,
+) -> HashMap<RollupKey, Rollup> {
+    let mut rollups = HashMap::new();
+    for reading in data
+        .iter()
+        .filter(|data| data.data_action == Action::Served)
+    {
+        let date = hours
+            .get(&reading.location)
+            .local_date(timestamp_utc(reading));
+        for period in Period::ALL {
+            let key = (reading.location.clone(), period, period.start(date));
+            let rollup = rollups.entry(key).or_insert_with_key(|key| {
+                past_rollups
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| Rollup::new(&reading.location, period, date))
+            });
+            rollup.served += 1;
+            rollup.amount += reading.amount;
+        }
+    }
+    rollups
+}
+
+/// The earliest local date of any reading in `data`; rollups of periods ending
+/// before it are unaffected by them.
+pub fn earliest_local_date(data: &[LibraData], hours: &LocationHours) -> Option<NaiveDate> {
+    data.iter()
+        .map(|data| hours.get(&data.location).local_date(timestamp_utc(data)))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_735_516_800; // 2024-12-30T00:00:00Z, a Monday
+
+    fn served(location: &str, hours: i64, amount: f64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: location.to_string(),
+            ingredient: String::from("Popcorn"),
+            data_action: Action::Served,
+            amount,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + hours * 3600).unwrap(),
+        }
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn it_names_and_bounds_periods() {
+        // New Year's Eve 2024 is in the first ISO week of 2025
+        let eve = date("2024-12-31");
+        assert_eq!(Period::Week.label(eve), "2025-W01");
+        assert_eq!(Period::Week.start(eve), date("2024-12-30"));
+        assert_eq!(Period::Week.end(eve), date("2025-01-05"));
+        assert_eq!(Period::Month.label(eve), "2024-12");
+        assert_eq!(Period::Month.start(eve), date("2024-12-01"));
+        assert_eq!(Period::Year.label(eve), "2024");
+        assert_eq!(Period::Month.end(date("2024-02-10")), date("2024-02-29"));
+        assert_eq!(Period::Day.end(eve), eve);
+    }
+
+    #[test]
+    fn it_rolls_up_by_local_date() {
+        let hours: LocationHours = "Caldo Office=America/Los_Angeles".parse().unwrap();
+        let data = vec![
+            // 2024-12-31T12:00Z
+            served("Caldo Office", 36, 40.0),
+            // 2025-01-01T05:00Z is still New Year's Eve in Los Angeles
+            served("Caldo Office", 53, 60.0),
+            // 2025-01-01T12:00Z
+            served("Caldo Office", 60, 50.0),
+        ];
+        let rollups = aggregate_rollups(&data, &hours, &HashMap::new());
+        let get =
+            |period, start: &str| &rollups[&("Caldo Office".to_string(), period, date(start))];
+
+        assert_eq!(get(Period::Day, "2024-12-31").served, 2);
+        assert_eq!(get(Period::Month, "2024-12-01").amount, 100.0);
+        assert_eq!(get(Period::Year, "2025-01-01").served, 1);
+        let week = get(Period::Week, "2024-12-30");
+        assert_eq!(week.served, 3);
+        assert_eq!(week.label, "2025-W01");
+        assert_eq!(earliest_local_date(&data, &hours), Some(date("2024-12-31")));
+
+        // Later runs add to what is stored
+        let more = aggregate_rollups(&[served("Caldo Office", 70, 10.0)], &hours, &rollups);
+        assert_eq!(more.len(), 4);
+        let week = &more[&("Caldo Office".to_string(), Period::Week, date("2024-12-30"))];
+        assert_eq!(week.served, 4);
+        assert_eq!(week.amount, 160.0);
+    }
+}
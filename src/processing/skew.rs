@@ -0,0 +1,161 @@
+use crate::firestore::client::FirestoreLibraData;
+use chrono::Duration;
+use std::collections::HashMap;
+
+/// Per device, the median of how far arrival time is ahead of the device
+/// timestamp across `entries`, in milliseconds, and how many readings it is
+/// based on. Readings without an arrival time are ignored.
+pub fn estimate_offsets(entries: &[FirestoreLibraData]) -> HashMap<String, (i64, usize)> {
+    let mut offsets: HashMap<String, Vec<i64>> = HashMap::new();
+    for entry in entries {
+        if let Some(received_at) = entry.received_at {
+            offsets
+                .entry(entry.device.serial_number.clone())
+                .or_default()
+                .push((received_at - entry.timestamp).num_milliseconds());
+        }
+    }
+    offsets
+        .into_iter()
+        .map(|(serial_number, mut offsets)| {
+            offsets.sort_unstable();
+            let mid = offsets.len() / 2;
+            let median = if offsets.len() % 2 == 0 {
+                (offsets[mid - 1] + offsets[mid]) / 2
+            } else {
+                offsets[mid]
+            };
+            (serial_number, (median, offsets.len()))
+        })
+        .collect()
+}
+
+pub fn is_skewed(offset_ms: i64, threshold: Duration) -> bool {
+    offset_ms.abs() > threshold.num_milliseconds()
+}
+
+/// Shifts the timestamps of readings from devices whose clock is off by more
+/// than `threshold` by that device's offset, returning how many were shifted.
+pub fn correct_timestamps(
+    entries: &mut [FirestoreLibraData],
+    offsets: &HashMap<String, i64>,
+    threshold: Duration,
+) -> usize {
+    let mut corrected = 0;
+    for entry in entries {
+        match offsets.get(&entry.device.serial_number) {
+            Some(&offset_ms) if is_skewed(offset_ms, threshold) => {
+                entry.timestamp += Duration::milliseconds(offset_ms);
+                corrected += 1;
+            }
+            _ => {}
+        }
+    }
+    corrected
+}
+
+/// Sets readings timestamped more than `max_future_skew` after they arrived to
+/// their arrival time, returning how many were set. Unlike readings that look
+/// late, which may have been buffered while a device was offline, these can
+/// only come from a fast clock, so the arrival time is the better estimate even
+/// for devices `correct_timestamps` leaves alone.
+pub fn correct_future_timestamps(
+    entries: &mut [FirestoreLibraData],
+    max_future_skew: Duration,
+) -> usize {
+    let mut corrected = 0;
+    for entry in entries {
+        match entry.received_at {
+            Some(received_at) if entry.timestamp > received_at + max_future_skew => {
+                entry.timestamp = received_at;
+                corrected += 1;
+            }
+            _ => {}
+        }
+    }
+    corrected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::client::FirestoreDevice;
+    use crate::firestore::schema::CURRENT_SCHEMA_VERSION;
+    use chrono::{DateTime, Utc};
+    use menu::action::Action;
+    use menu::device::Model;
+
+    fn reading(
+        serial_number: &str,
+        timestamp: DateTime<Utc>,
+        received_at: Option<DateTime<Utc>>,
+    ) -> FirestoreLibraData {
+        FirestoreLibraData {
+            device: FirestoreDevice {
+                model: Model::LibraV0,
+                serial_number: serial_number.to_string(),
+            },
+            location: "Caldo Office".to_string(),
+            ingredient: "Popcorn".to_string(),
+            data_action: Action::Served,
+            amount: 12.5,
+            timestamp,
+            schema_version: CURRENT_SCHEMA_VERSION,
+            received_at,
+        }
+    }
+
+    #[test]
+    fn it_estimates_the_median_offset_per_device() {
+        let now = Utc::now();
+        let epoch = DateTime::UNIX_EPOCH;
+        let entries = vec![
+            reading("good", now - Duration::seconds(2), Some(now)),
+            reading("good", now - Duration::seconds(4), Some(now)),
+            // A reading buffered while offline does not move the median much
+            reading("good", now - Duration::hours(3), Some(now)),
+            reading("dead-rtc", epoch, Some(now)),
+            reading("dead-rtc", epoch + Duration::seconds(10), Some(now)),
+            reading("legacy", epoch, None),
+        ];
+
+        let offsets = estimate_offsets(&entries);
+        assert_eq!(offsets["good"], (4000, 3));
+        let (offset, samples) = offsets["dead-rtc"];
+        assert_eq!(samples, 2);
+        assert_eq!(offset, (now - epoch).num_milliseconds() - 5000);
+        assert!(!offsets.contains_key("legacy"));
+    }
+
+    #[test]
+    fn it_only_corrects_devices_past_the_threshold() {
+        let now = Utc::now();
+        let mut entries = vec![
+            reading("good", now - Duration::seconds(4), None),
+            reading("fast", now + Duration::hours(1), None),
+        ];
+        let offsets = HashMap::from([("good".to_string(), 4000), ("fast".to_string(), -3_600_000)]);
+
+        let corrected = correct_timestamps(&mut entries, &offsets, Duration::minutes(5));
+        assert_eq!(corrected, 1);
+        assert_eq!(entries[0].timestamp, now - Duration::seconds(4));
+        assert_eq!(entries[1].timestamp, now);
+    }
+
+    #[test]
+    fn it_sets_readings_from_the_future_to_their_arrival() {
+        let now = Utc::now();
+        let mut entries = vec![
+            reading("fast", now + Duration::days(400), Some(now)),
+            reading("good", now + Duration::seconds(1), Some(now)),
+            reading("legacy", now + Duration::days(400), None),
+        ];
+
+        assert_eq!(
+            correct_future_timestamps(&mut entries, Duration::minutes(5)),
+            1
+        );
+        assert_eq!(entries[0].timestamp, now);
+        assert_eq!(entries[1].timestamp, now + Duration::seconds(1));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::processing::hours::{LocationHours, OpeningHours};
+use crate::processing::inventory::{InventoryKey, StockLevel};
+use crate::processing::timestamp_utc;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// A time one ingredient on one scale was empty, from a `RanOut` to the next
+/// `Refilled`. Unresolved while the scale has not been refilled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stockout {
+    pub serial_number: String,
+    pub location: String,
+    pub ingredient: String,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub ran_out_at: DateTime<Utc>,
+    #[serde(default, with = "firestore::serialize_as_optional_timestamp")]
+    pub refilled_at: Option<DateTime<Utc>>,
+    pub resolved: bool,
+}
+
+impl Stockout {
+    pub fn key(&self) -> InventoryKey {
+        (self.serial_number.clone(), self.ingredient.clone())
+    }
+
+    /// When the stockout ended, or `now` while it has not.
+    pub fn end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.refilled_at.unwrap_or(now)
+    }
+}
+
+/// Pairs each `RanOut` with the next `Refilled` on the same scale and
+/// ingredient, continuing the `open` stockouts of earlier runs, and returns the
+/// stockouts started or resolved. Another `RanOut` while already out of stock
+/// changes nothing, and a `RanOut` from before the scale's last reset in
+/// `past_levels` arrived too late to pair.
+pub fn pair_stockouts(
+    data: &[LibraData],
+    open: &HashMap<InventoryKey, Stockout>,
+    past_levels: &HashMap<InventoryKey, StockLevel>,
+) -> Vec<Stockout> {
+    let mut sorted = data.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|data| data.timestamp);
+
+    let mut open = open.clone();
+    let mut changed: HashMap<(InventoryKey, DateTime<Utc>), Stockout> = HashMap::new();
+    for data in sorted {
+        let at = timestamp_utc(data);
+        let key = (data.device.serial_number.clone(), data.ingredient.clone());
+        match data.data_action {
+            Action::RanOut => {
+                let superseded = past_levels
+                    .get(&key)
+                    .is_some_and(|level| at < level.reset_at);
+                if open.contains_key(&key) || superseded {
+                    continue;
+                }
+                let stockout = Stockout {
+                    serial_number: key.0.clone(),
+                    location: data.location.clone(),
+                    ingredient: key.1.clone(),
+                    ran_out_at: at,
+                    refilled_at: None,
+                    resolved: false,
+                };
+                changed.insert((key.clone(), at), stockout.clone());
+                open.insert(key, stockout);
+            }
+            Action::Refilled => {
+                // Refills before the stockout started do not end it
+                if open
+                    .get(&key)
+                    .is_none_or(|stockout| at < stockout.ran_out_at)
+                {
+                    continue;
+                }
+                let mut stockout = open.remove(&key).unwrap();
+                stockout.refilled_at = Some(at);
+                stockout.resolved = true;
+                changed.insert((key, stockout.ran_out_at), stockout);
+            }
+            _ => {}
+        }
+    }
+    changed.into_values().collect()
+}
+
+/// Stockout metrics of one location over a period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocationStockouts {
+    pub location: String,
+    pub stockouts: usize,
+    /// Stockouts not refilled yet, counted up to now.
+    pub unresolved: usize,
+    /// Percentiles of how long resolved stockouts lasted.
+    pub p50_minutes: Option<f64>,
+    pub p95_minutes: Option<f64>,
+    /// Minutes out of stock per local date, summed over ingredients.
+    pub daily_minutes: BTreeMap<NaiveDate, f64>,
+    /// Share of the opening hours in the period with at least one ingredient
+    /// out of stock.
+    pub opening_hours_share: Option<f64>,
+}
+
+/// Nearest-rank percentile of sorted values.
+pub fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn minutes(duration: Duration) -> f64 {
+    duration.num_seconds() as f64 / 60.0
+}
+
+/// Adds the part of `[start, end)` on each local date to `daily`.
+fn split_by_date(
+    daily: &mut BTreeMap<NaiveDate, f64>,
+    hours: &OpeningHours,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) {
+    let mut at = start;
+    while at < end {
+        let date = hours.local_date(at);
+        let next = hours.start_of(date + Duration::days(1)).min(end);
+        *daily.entry(date).or_insert(0.0) += minutes(next - at);
+        at = next;
+    }
+}
+
+/// Metrics per location of the stockouts overlapping `[from, to)`, clipped to it.
+/// Percentiles count each stockout's full duration.
+pub fn summarize_stockouts(
+    stockouts: &[Stockout],
+    hours: &LocationHours,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<LocationStockouts> {
+    let mut by_location: BTreeMap<&str, Vec<&Stockout>> = BTreeMap::new();
+    for stockout in stockouts {
+        if stockout.ran_out_at < to && stockout.end(now) > from {
+            by_location
+                .entry(stockout.location.as_str())
+                .or_default()
+                .push(stockout);
+        }
+    }
+
+    by_location
+        .into_iter()
+        .map(|(location, mut stockouts)| {
+            let opening = hours.get(location);
+            let mut durations = stockouts
+                .iter()
+                .filter_map(|stockout| {
+                    stockout
+                        .refilled_at
+                        .map(|end| minutes(end - stockout.ran_out_at))
+                })
+                .collect::<Vec<_>>();
+            durations.sort_by(f64::total_cmp);
+
+            let mut daily_minutes = BTreeMap::new();
+            for stockout in &stockouts {
+                let start = stockout.ran_out_at.max(from);
+                let end = stockout.end(now).min(to);
+                split_by_date(&mut daily_minutes, &opening, start, end);
+            }
+
+            // Open time with anything out of stock, merging overlapping stockouts
+            stockouts.sort_by_key(|stockout| stockout.ran_out_at);
+            let mut out_of_stock = Duration::zero();
+            let mut current: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+            for stockout in &stockouts {
+                let (start, end) = (stockout.ran_out_at.max(from), stockout.end(now).min(to));
+                current = match current {
+                    Some((first, last)) if start <= last => Some((first, last.max(end))),
+                    Some((first, last)) => {
+                        out_of_stock += opening.open_time(first, last);
+                        Some((start, end))
+                    }
+                    None => Some((start, end)),
+                };
+            }
+            if let Some((first, last)) = current {
+                out_of_stock += opening.open_time(first, last);
+            }
+            let open = opening.open_time(from, to.min(now));
+            let opening_hours_share = (open > Duration::zero())
+                .then(|| out_of_stock.num_seconds() as f64 / open.num_seconds() as f64);
+
+            LocationStockouts {
+                location: location.to_string(),
+                stockouts: stockouts.len(),
+                unresolved: stockouts
+                    .iter()
+                    .filter(|stockout| !stockout.resolved)
+                    .count(),
+                p50_minutes: percentile(&durations, 50.0),
+                p95_minutes: percentile(&durations, 95.0),
+                daily_minutes,
+                opening_hours_share,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z
+
+    fn reading(action: Action, ingredient: &str, minutes: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: String::from("Caldo Office"),
+            ingredient: ingredient.to_string(),
+            data_action: action,
+            amount: 0.0,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + minutes * 60).unwrap(),
+        }
+    }
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(START + minutes * 60, 0).unwrap()
+    }
+
+    fn key(ingredient: &str) -> InventoryKey {
+        (String::from("Lib298190"), ingredient.to_string())
+    }
+
+    #[test]
+    fn it_pairs_run_outs_with_refills_across_runs() {
+        let changed = pair_stockouts(
+            &[
+                reading(Action::RanOut, "Popcorn", 10),
+                reading(Action::RanOut, "Popcorn", 15),
+                reading(Action::Refilled, "Popcorn", 40),
+                reading(Action::RanOut, "Almonds", 50),
+            ],
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        assert_eq!(changed.len(), 2);
+        let popcorn = changed.iter().find(|s| s.ingredient == "Popcorn").unwrap();
+        assert_eq!(popcorn.ran_out_at, at(10));
+        assert_eq!(popcorn.refilled_at, Some(at(40)));
+        let almonds = changed.iter().find(|s| s.ingredient == "Almonds").unwrap();
+        assert!(!almonds.resolved);
+
+        // The next run resolves the stockout left open
+        let open = HashMap::from([(key("Almonds"), almonds.clone())]);
+        let changed = pair_stockouts(
+            &[reading(Action::Refilled, "Almonds", 200)],
+            &open,
+            &HashMap::new(),
+        );
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].ran_out_at, at(50));
+        assert_eq!(changed[0].refilled_at, Some(at(200)));
+        assert!(changed[0].resolved);
+
+        // A refill without a stockout before it is not one
+        let changed = pair_stockouts(
+            &[reading(Action::Refilled, "Popcorn", 300)],
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn it_summarizes_durations_and_opening_hours_share() {
+        let stockout = |ingredient: &str, start: i64, end: Option<i64>| Stockout {
+            serial_number: String::from("Lib298190"),
+            location: String::from("Caldo Office"),
+            ingredient: ingredient.to_string(),
+            ran_out_at: at(start),
+            refilled_at: end.map(at),
+            resolved: end.is_some(),
+        };
+        let stockouts = vec![
+            stockout("Popcorn", 9 * 60, Some(10 * 60)),
+            // Overlaps the first, so only adds its last half hour to the share
+            stockout("Almonds", 9 * 60 + 30, Some(10 * 60 + 30)),
+            stockout("Popcorn", 23 * 60, Some(25 * 60)),
+            stockout("Cashews", 47 * 60, None),
+        ];
+        let hours: LocationHours = "Caldo Office=UTC 08:00-18:00".parse().unwrap();
+
+        let summary = summarize_stockouts(&stockouts, &hours, at(0), at(48 * 60), at(48 * 60));
+        assert_eq!(summary.len(), 1);
+        let office = &summary[0];
+        assert_eq!(office.stockouts, 4);
+        assert_eq!(office.unresolved, 1);
+        assert_eq!(office.p50_minutes, Some(60.0));
+        assert_eq!(office.p95_minutes, Some(120.0));
+        let first = at(0).date_naive();
+        assert_eq!(office.daily_minutes[&first], 180.0);
+        assert_eq!(office.daily_minutes[&(first + Duration::days(1))], 120.0);
+        // 90 minutes of 20 opening hours; the night stockouts were while closed
+        assert_eq!(office.opening_hours_share, Some(90.0 / (20.0 * 60.0)));
+    }
+}
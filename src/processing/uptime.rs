@@ -0,0 +1,362 @@
+use crate::processing::timestamp_utc;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use menu::action::Action;
+use menu::libra_data::LibraData;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Serial number and UTC date.
+pub type UptimeKey = (String, NaiveDate);
+
+/// How far back from the time of a run readings are counted. Readings dated
+/// earlier, or after the run, come from a device clock that is off, e.g. a dead
+/// RTC reporting 1970, and would otherwise fill every day since with intervals.
+pub const MAX_LOOKBACK: Duration = Duration::days(31);
+
+/// Where a device's availability was left by the last run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub serial_number: String,
+    pub location: String,
+    pub online: bool,
+    /// When the device last came online or went offline.
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub changed_at: DateTime<Utc>,
+    /// Last reading that showed the device online.
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub last_seen: DateTime<Utc>,
+    /// Time up to which the device's intervals are counted in its days.
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub counted_until: DateTime<Utc>,
+}
+
+/// Availability of one device over one UTC day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyUptime {
+    pub serial_number: String,
+    pub location: String,
+    pub date: NaiveDate,
+    pub online_ms: i64,
+    pub offline_ms: i64,
+    /// Outages that started on the day.
+    pub outages: usize,
+    /// Outages that ended on the day, and how long they lasted in total.
+    pub recoveries: usize,
+    pub recovery_ms: i64,
+}
+
+impl DailyUptime {
+    fn new(serial_number: &str, location: &str, date: NaiveDate) -> Self {
+        DailyUptime {
+            serial_number: serial_number.to_string(),
+            location: location.to_string(),
+            date,
+            online_ms: 0,
+            offline_ms: 0,
+            outages: 0,
+            recoveries: 0,
+            recovery_ms: 0,
+        }
+    }
+
+    /// Adds another day's counts, e.g. of another device at the same location.
+    pub fn merge(&mut self, other: &DailyUptime) {
+        self.online_ms += other.online_ms;
+        self.offline_ms += other.offline_ms;
+        self.outages += other.outages;
+        self.recoveries += other.recoveries;
+        self.recovery_ms += other.recovery_ms;
+    }
+
+    /// Share of the observed time the device was online, `None` if it was not
+    /// observed at all.
+    pub fn uptime_percent(&self) -> Option<f64> {
+        let observed = self.online_ms + self.offline_ms;
+        (observed > 0).then(|| self.online_ms as f64 * 100.0 / observed as f64)
+    }
+
+    /// Mean time to recovery of the outages that ended on the day.
+    pub fn mttr_secs(&self) -> Option<f64> {
+        (self.recoveries > 0).then(|| self.recovery_ms as f64 / 1000.0 / self.recoveries as f64)
+    }
+}
+
+struct Fold<'a> {
+    past_days: &'a HashMap<UptimeKey, DailyUptime>,
+    days: HashMap<UptimeKey, DailyUptime>,
+    /// Time before which nothing is counted.
+    floor: DateTime<Utc>,
+}
+
+impl Fold<'_> {
+    fn day(&mut self, state: &DeviceState, date: NaiveDate) -> &mut DailyUptime {
+        let key = (state.serial_number.clone(), date);
+        let past = self.past_days.get(&key);
+        let day = self.days.entry(key).or_insert_with(|| {
+            past.cloned()
+                .unwrap_or_else(|| DailyUptime::new(&state.serial_number, &state.location, date))
+        });
+        day.location.clone_from(&state.location);
+        day
+    }
+
+    /// Counts the time from `counted_until` to `until` as the state's current
+    /// status, split at midnight.
+    fn count(&mut self, state: &mut DeviceState, until: DateTime<Utc>) {
+        state.counted_until = state.counted_until.max(self.floor);
+        while state.counted_until < until {
+            let date = state.counted_until.date_naive();
+            let midnight = (date + Duration::days(1))
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                .and_utc();
+            let end = until.min(midnight);
+            let ms = (end - state.counted_until).num_milliseconds();
+            let online = state.online;
+            let day = self.day(state, date);
+            if online {
+                day.online_ms += ms;
+            } else {
+                day.offline_ms += ms;
+            }
+            state.counted_until = end;
+        }
+    }
+
+    fn go_offline(&mut self, state: &mut DeviceState, at: DateTime<Utc>) {
+        self.count(state, at);
+        state.online = false;
+        state.changed_at = at;
+        self.day(state, at.date_naive()).outages += 1;
+    }
+
+    fn go_online(&mut self, state: &mut DeviceState, at: DateTime<Utc>) {
+        self.count(state, at);
+        let outage = (at - state.changed_at).num_milliseconds();
+        let day = self.day(state, at.date_naive());
+        day.recoveries += 1;
+        day.recovery_ms += outage;
+        state.online = true;
+        state.changed_at = at;
+    }
+
+    /// A device that has not been seen for `timeout` went offline when it
+    /// last could have been expected.
+    fn time_out(&mut self, state: &mut DeviceState, at: DateTime<Utc>, timeout: Duration) {
+        if state.online && at - state.last_seen > timeout {
+            let offline_at = (state.last_seen + timeout).max(state.counted_until);
+            self.go_offline(state, offline_at);
+        }
+    }
+}
+
+/// Reconstructs each device's online and offline intervals from its readings
+/// and counts them into daily uptime, returning the new device states and the
+/// days they changed. `Offline` takes a device offline and every other action
+/// brings it online; a device that sends nothing for `timeout` is offline from
+/// then on. Readings from before time already counted only extend the last
+/// time an online device was seen. Every device is counted up to `now`, from
+/// no earlier than `MAX_LOOKBACK` before it; readings outside that are skipped.
+pub fn aggregate_uptime(
+    data: &[LibraData],
+    past_states: &HashMap<String, DeviceState>,
+    past_days: &HashMap<UptimeKey, DailyUptime>,
+    timeout: Duration,
+    now: DateTime<Utc>,
+) -> (
+    HashMap<String, DeviceState>,
+    HashMap<UptimeKey, DailyUptime>,
+) {
+    let floor = now - MAX_LOOKBACK;
+    let mut sorted = data
+        .iter()
+        .filter(|data| (floor..=now).contains(&timestamp_utc(data)))
+        .collect::<Vec<_>>();
+    sorted.sort_by_key(|data| data.timestamp);
+
+    let mut fold = Fold {
+        past_days,
+        days: HashMap::new(),
+        floor,
+    };
+    let mut states = past_states.clone();
+    for data in sorted {
+        let at = timestamp_utc(data);
+        let online = data.data_action != Action::Offline;
+        let Some(state) = states.get_mut(&data.device.serial_number) else {
+            let mut state = DeviceState {
+                serial_number: data.device.serial_number.clone(),
+                location: data.location.clone(),
+                online: true,
+                changed_at: at,
+                last_seen: at,
+                counted_until: at,
+            };
+            if !online {
+                fold.go_offline(&mut state, at);
+            }
+            states.insert(state.serial_number.clone(), state);
+            continue;
+        };
+        state.location.clone_from(&data.location);
+        if at < state.counted_until {
+            if online && state.online {
+                state.last_seen = state.last_seen.max(at);
+            }
+            continue;
+        }
+        fold.time_out(state, at, timeout);
+        match (online, state.online) {
+            (true, true) => {}
+            (true, false) => fold.go_online(state, at),
+            (false, true) => fold.go_offline(state, at),
+            (false, false) => continue,
+        }
+        if online {
+            state.last_seen = at;
+        }
+    }
+
+    for state in states.values_mut() {
+        fold.time_out(state, now, timeout);
+        fold.count(state, now);
+    }
+    (states, fold.days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::device::{Device, Model};
+    use time::OffsetDateTime;
+
+    const START: i64 = 1_750_032_000; // 2025-06-16T00:00:00Z
+
+    fn reading(action: Action, minutes: i64) -> LibraData {
+        LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: String::from("Lib298190"),
+            },
+            location: String::from("Caldo Office"),
+            ingredient: String::from("Popcorn"),
+            data_action: action,
+            amount: 0.0,
+            timestamp: OffsetDateTime::from_unix_timestamp(START + minutes * 60).unwrap(),
+        }
+    }
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(START + minutes * 60, 0).unwrap()
+    }
+
+    fn key(day: i64) -> UptimeKey {
+        (String::from("Lib298190"), at(day * 24 * 60).date_naive())
+    }
+
+    #[test]
+    fn it_counts_outages_and_recovery() {
+        let data = vec![
+            reading(Action::Starting, 0),
+            reading(Action::Heartbeat, 10),
+            reading(Action::Offline, 20),
+            reading(Action::Starting, 50),
+            reading(Action::Heartbeat, 60),
+        ];
+        let (states, days) = aggregate_uptime(
+            &data,
+            &HashMap::new(),
+            &HashMap::new(),
+            Duration::minutes(15),
+            at(60),
+        );
+        let day = &days[&key(0)];
+        assert_eq!(day.online_ms, 30 * 60_000);
+        assert_eq!(day.offline_ms, 30 * 60_000);
+        assert_eq!(day.outages, 1);
+        assert_eq!(day.mttr_secs(), Some(30.0 * 60.0));
+        assert_eq!(day.uptime_percent(), Some(50.0));
+        assert!(states["Lib298190"].online);
+    }
+
+    #[test]
+    fn it_times_out_silent_devices_across_runs_and_midnight() {
+        let (states, days) = aggregate_uptime(
+            &[
+                reading(Action::Starting, 23 * 60),
+                reading(Action::Heartbeat, 23 * 60 + 10),
+                reading(Action::Heartbeat, 23 * 60 + 20),
+                reading(Action::Heartbeat, 23 * 60 + 30),
+            ],
+            &HashMap::new(),
+            &HashMap::new(),
+            Duration::minutes(15),
+            at(23 * 60 + 30),
+        );
+        assert_eq!(days[&key(0)].online_ms, 30 * 60_000);
+
+        // Nothing more arrives: offline 15 minutes after it was last seen
+        let (states, days) =
+            aggregate_uptime(&[], &states, &days, Duration::minutes(15), at(25 * 60));
+        let state = &states["Lib298190"];
+        assert!(!state.online);
+        assert_eq!(state.changed_at, at(23 * 60 + 45));
+        let first = &days[&key(0)];
+        assert_eq!(first.online_ms, 45 * 60_000);
+        assert_eq!(first.offline_ms, 15 * 60_000);
+        assert_eq!(first.outages, 1);
+        assert_eq!(days[&key(1)].offline_ms, 60 * 60_000);
+
+        // A heartbeat from before the last run cannot undo the outage
+        let (states, days) = aggregate_uptime(
+            &[
+                reading(Action::Heartbeat, 23 * 60 + 50),
+                reading(Action::Heartbeat, 26 * 60),
+            ],
+            &states,
+            &days,
+            Duration::minutes(15),
+            at(26 * 60),
+        );
+        assert!(states["Lib298190"].online);
+        let second = &days[&key(1)];
+        assert_eq!(second.offline_ms, 2 * 60 * 60_000);
+        assert_eq!(second.recoveries, 1);
+        assert_eq!(second.mttr_secs(), Some(2.25 * 60.0 * 60.0));
+    }
+
+    #[test]
+    fn it_skips_readings_from_clocks_that_are_far_off() {
+        let mut dead_rtc = reading(Action::Starting, 0);
+        dead_rtc.timestamp = OffsetDateTime::UNIX_EPOCH;
+        let (states, days) = aggregate_uptime(
+            &[dead_rtc, reading(Action::Heartbeat, 5 * 24 * 60)],
+            &HashMap::new(),
+            &HashMap::new(),
+            Duration::minutes(15),
+            at(4 * 24 * 60),
+        );
+        assert!(states.is_empty());
+        assert!(days.is_empty());
+
+        // A state left far back by an earlier run is only counted from the window
+        let state = DeviceState {
+            serial_number: String::from("Lib298190"),
+            location: String::from("Caldo Office"),
+            online: false,
+            changed_at: DateTime::UNIX_EPOCH,
+            last_seen: DateTime::UNIX_EPOCH,
+            counted_until: DateTime::UNIX_EPOCH,
+        };
+        let (_, days) = aggregate_uptime(
+            &[],
+            &HashMap::from([(state.serial_number.clone(), state)]),
+            &HashMap::new(),
+            Duration::minutes(15),
+            at(0),
+        );
+        assert_eq!(days.len(), 31);
+        let offline_ms: i64 = days.values().map(|day| day.offline_ms).sum();
+        assert_eq!(offline_ms, MAX_LOOKBACK.num_milliseconds());
+    }
+}
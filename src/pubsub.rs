@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::ingest::{registered_devices, validate, write_reading, Invalid, Written};
+use crate::metrics::record_ingested;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use firestore::FirestoreDb;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Body of a Pub/Sub push-subscription request.
+#[derive(Debug, Deserialize)]
+pub struct PushEnvelope {
+    pub message: PushMessage,
+    pub subscription: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushMessage {
+    /// Base64-encoded `LibraData` JSON.
+    pub data: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushStatus {
+    Stored,
+    /// A redelivery, or another message with a reading that was already stored.
+    Duplicate,
+    /// Could not be decoded or failed validation. Acknowledged anyway, since
+    /// redelivering it would fail the same way.
+    Rejected,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushResult {
+    /// Missing when the request body was not a push envelope.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    pub status: PushStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PushMessage {
+    pub fn reading(&self) -> Result<Value, Invalid> {
+        let bytes = STANDARD
+            .decode(&self.data)
+            .map_err(|e| Invalid::Schema(format!("message data is not base64: {e}")))?;
+        serde_json::from_slice(&bytes).map_err(|e| Invalid::Schema(e.to_string()))
+    }
+}
+
+/// Parses the body of a push request.
+pub fn parse_envelope(body: &[u8]) -> Result<PushEnvelope, Invalid> {
+    serde_json::from_slice(body)
+        .map_err(|e| Invalid::Schema(format!("request body is not a push envelope: {e}")))
+}
+
+/// Handles a push request body. Pub/Sub redelivers every message it gets a
+/// non-2xx response for, so a body that is not an envelope is acknowledged as
+/// rejected and counted rather than refused, or it would come back forever.
+pub async fn handle_push_body(
+    db: &FirestoreDb,
+    body: &[u8],
+    max_future_skew: Duration,
+) -> Result<PushResult, Error> {
+    match parse_envelope(body) {
+        Ok(envelope) => handle_push(db, envelope, max_future_skew).await,
+        Err(invalid) => {
+            tracing::warn!(reason = %invalid, "Acknowledging undecodable push request");
+            record_ingested("undecodable");
+            Ok(PushResult {
+                message_id: None,
+                status: PushStatus::Rejected,
+                error: Some(invalid.to_string()),
+            })
+        }
+    }
+}
+
+/// Decodes, validates and stores the reading in a push message. Only returns an
+/// error, and so a non-2xx response that makes Pub/Sub redeliver, when retrying
+/// could succeed.
+#[tracing::instrument(
+    name = "pubsub_push",
+    skip_all,
+    fields(message_id = %envelope.message.message_id, subscription = %envelope.subscription)
+)]
+pub async fn handle_push(
+    db: &FirestoreDb,
+    envelope: PushEnvelope,
+    max_future_skew: Duration,
+) -> Result<PushResult, Error> {
+    let message = envelope.message;
+    let registered = registered_devices(db).await?;
+    let validated = message
+        .reading()
+        .and_then(|reading| validate(reading, &registered, max_future_skew, Utc::now()));
+
+    let (status, error) = match validated {
+        // Readings are stored under their content id, so a redelivered message,
+        // or the same reading published twice, maps to the existing document
+        Ok(data) => match write_reading(db, &data.content_id(), &data).await {
+            Ok(Written::New) => (PushStatus::Stored, None),
+            Ok(Written::Duplicate) => {
+                tracing::info!("Ignoring reading that is already stored");
+                (PushStatus::Duplicate, None)
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to store pushed reading");
+                record_ingested("failed");
+                return Err(e);
+            }
+        },
+        Err(invalid) => {
+            tracing::warn!(
+                reason = %invalid,
+                attributes = ?message.attributes,
+                "Rejected pushed reading"
+            );
+            (PushStatus::Rejected, Some(invalid.to_string()))
+        }
+    };
+
+    record_ingested(match status {
+        PushStatus::Stored => "accepted",
+        PushStatus::Duplicate => "duplicate",
+        PushStatus::Rejected => "rejected",
+    });
+    Ok(PushResult {
+        message_id: Some(message.message_id),
+        status,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PushEnvelope {
+        let path = format!(
+            "{}/tests/fixtures/pubsub/{name}.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn it_decodes_recorded_envelopes() {
+        let envelope = fixture("reading");
+        assert_eq!(envelope.message.message_id, "13016573431246337");
+        assert_eq!(envelope.message.attributes["serialNumber"], "000-0");
+
+        let reading = envelope.message.reading().unwrap();
+        assert_eq!(reading["device"]["serial_number"], "000-0");
+        assert_eq!(reading["amount"], 42.5);
+    }
+
+    #[test]
+    fn it_validates_decoded_readings() {
+        let registered = ["000-0".to_string()].into();
+        let now = "2025-06-01T12:00:05Z".parse().unwrap();
+        let check = |name| {
+            fixture(name)
+                .message
+                .reading()
+                .and_then(|reading| validate(reading, &registered, Duration::minutes(5), now))
+        };
+
+        assert!(check("reading").is_ok());
+        assert_eq!(check("negative_amount").unwrap_err(), Invalid::Amount(-1.0));
+        assert!(matches!(check("malformed_data"), Err(Invalid::Schema(_))));
+    }
+
+    #[test]
+    fn it_refuses_bodies_that_are_not_envelopes() {
+        assert!(parse_envelope(br#"{"message": {"data": "e30="}}"#).is_err());
+        assert!(parse_envelope(b"not json").is_err());
+    }
+}
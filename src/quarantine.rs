@@ -0,0 +1,97 @@
+use crate::config::ingest_max_future_skew;
+use crate::error::Error;
+use crate::firestore::client::{aggregate_entries, FirestoreLibraData};
+use crate::firestore::lease::LeaseGuard;
+use crate::firestore::quarantine::{
+    delete_quarantined, fetch_quarantined, save_quarantined, QuarantinedDocument,
+};
+use crate::firestore::schema::parse_reading;
+use crate::ingest::{check_reading, registered_devices, write_reading, Written};
+use chrono::Utc;
+use firestore::FirestoreDb;
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Serialize)]
+pub struct ReplayResult {
+    /// Id of the reading in `libra`.
+    pub id: String,
+    /// The reading was already in `libra`, so nothing was written or aggregated.
+    pub duplicate: bool,
+    /// Whether the reading was folded into the aggregates now. Readings newer than
+    /// the watermark are left for the next aggregation run.
+    pub aggregated: bool,
+}
+
+async fn valid_reading(db: &FirestoreDb, document: Value) -> Result<FirestoreLibraData, Error> {
+    let data = parse_reading(document).map_err(Error::InvalidDocument)?;
+    let registered = registered_devices(db).await?;
+    check_reading(&data, &registered, ingest_max_future_skew(), Utc::now())
+        .map_err(|invalid| Error::InvalidDocument(invalid.to_string()))?;
+    Ok(data)
+}
+
+/// Replaces a quarantined document with a corrected version, which must be a
+/// valid reading in any supported `libra` schema version.
+pub async fn fix(
+    db: &FirestoreDb,
+    id: &str,
+    document: Value,
+) -> Result<Option<QuarantinedDocument>, Error> {
+    let Some(mut record) = fetch_quarantined(db, id).await? else {
+        return Ok(None);
+    };
+    valid_reading(db, document.clone()).await?;
+
+    record.document = document;
+    record.fixed_at = Some(Utc::now());
+    save_quarantined(db, &record).await?;
+    tracing::info!(document_id = %id, "Fixed quarantined document");
+    Ok(Some(record))
+}
+
+/// Moves a quarantined document back into `libra` and counts it in the
+/// aggregates. Takes the aggregation lease, so it fails with `Error::LeaseHeld`
+/// while a run is in progress.
+pub async fn replay(db: &FirestoreDb, id: &str) -> Result<Option<ReplayResult>, Error> {
+    let Some(record) = fetch_quarantined(db, id).await? else {
+        return Ok(None);
+    };
+    let data = valid_reading(db, record.document.clone()).await?;
+
+    let lease = LeaseGuard::acquire(db, &format!("replay-{id}")).await?;
+    let result = replay_locked(db, &lease, &record, data).await;
+    if let Err(e) = lease.release(db).await {
+        tracing::warn!(error = ?e, "Failed to release aggregation lease, it will expire instead");
+    }
+    result.map(Some)
+}
+
+async fn replay_locked(
+    db: &FirestoreDb,
+    lease: &LeaseGuard,
+    record: &QuarantinedDocument,
+    data: FirestoreLibraData,
+) -> Result<ReplayResult, Error> {
+    let id = data.content_id();
+    let result = match write_reading(db, &id, &data).await? {
+        Written::New => ReplayResult {
+            id,
+            duplicate: false,
+            aggregated: aggregate_entries(db, lease, vec![data]).await? > 0,
+        },
+        Written::Duplicate => ReplayResult {
+            id,
+            duplicate: true,
+            aggregated: false,
+        },
+    };
+    delete_quarantined(db, &record.id).await?;
+    tracing::info!(
+        document_id = %record.id,
+        reading_id = %result.id,
+        aggregated = result.aggregated,
+        "Replayed quarantined document"
+    );
+    Ok(result)
+}
@@ -1,9 +1,34 @@
 use crate::error::Error;
 use crate::error::Error::FirestoreError;
+use crate::firestore::anomalies::fetch_anomalies;
 use crate::firestore::client::FirestoreLibraData;
-use firestore::{FirestoreDb, FirestoreQueryDirection};
+use crate::firestore::compliance::fetch_compliance_days;
+use crate::firestore::heatmaps::fetch_heatmaps;
+use crate::firestore::inventory::fetch_inventory;
+use crate::firestore::portions::fetch_portions;
+use crate::firestore::quarantine::parse_or_skip;
+use crate::firestore::rollups::fetch_rollups;
+use crate::firestore::stockouts::fetch_stockouts_since;
+use crate::firestore::uptime::fetch_uptime_days;
+use crate::metrics::observe_firestore;
+use crate::processing::anomaly::{Anomaly, Granularity, Severity};
+use crate::processing::compliance::{
+    summarize_compliance, ComplianceSummary, PortionFilter, PortionTargets,
+};
+use crate::processing::heatmap::HeatmapChart;
+use crate::processing::hours::LocationHours;
+use crate::processing::inventory::StockLevel;
+use crate::processing::portion::{PortionBins, PortionSummary};
+use crate::processing::rollup::{Period, Rollup};
+use crate::processing::stockout::{summarize_stockouts, LocationStockouts};
+use crate::processing::uptime::DailyUptime;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use firestore::{
+    FirestoreDb, FirestoreQueryCursor, FirestoreQueryDirection, FirestoreQueryFilter,
+    FirestoreQueryFilterBuilder, FirestoreReference, FirestoreTimestamp,
+};
 use menu::action::Action;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 #[derive(Deserialize)]
@@ -29,54 +54,550 @@ pub struct DataQuery {
     pub start_date: Option<chrono::DateTime<chrono::Utc>>,
     pub end_date: Option<chrono::DateTime<chrono::Utc>>,
     pub limit: Option<usize>,
+    /// Only `Served` readings of ingredients with a target portion that
+    /// compared to it like this.
+    pub portion: Option<PortionFilter>,
 }
+
+/// Readings fetched per page while looking for servings that match a portion
+/// filter.
+const PORTION_PAGE_SIZE: usize = 500;
+
+/// Most values Firestore accepts in one `in` filter.
+const MAX_IN_VALUES: usize = 30;
+
 impl DataQuery {
-    pub async fn run_query(&self, db: &FirestoreDb) -> Result<Vec<FirestoreLibraData>, Error> {
+    #[tracing::instrument(
+        name = "data_query",
+        skip_all,
+        fields(
+            location = self.location.as_deref(),
+            serial_number = self.serial_number.as_deref(),
+            ingredient = self.ingredient.as_deref(),
+            action = self.action.as_ref().map(tracing::field::debug),
+            start_date = self.start_date.map(tracing::field::display),
+            end_date = self.end_date.map(tracing::field::display),
+            limit = self.limit.map(|limit| limit as u64),
+            portion = self.portion.as_ref().map(tracing::field::debug),
+            results = tracing::field::Empty,
+        )
+    )]
+    pub async fn run_query(
+        &self,
+        db: &FirestoreDb,
+        targets: &PortionTargets,
+    ) -> Result<Vec<FirestoreLibraData>, Error> {
+        let valid_data = match self.portion {
+            Some(portion) => self.fetch_servings(db, targets, portion).await?,
+            None => {
+                let documents = self.fetch_page(db, None, self.limit, None).await?;
+                parse_or_skip(documents).0
+            }
+        };
+        tracing::Span::current().record("results", valid_data.len());
+        Ok(valid_data)
+    }
+
+    /// `Served` readings that compare to their target like `portion`. Whether
+    /// they do is only known once fetched, so this pages through the matches of
+    /// the rest of the query until it has `limit` of them. Pages start after the
+    /// last document of the one before, so Firestore does not scan past readings
+    /// again as an offset would.
+    async fn fetch_servings(
+        &self,
+        db: &FirestoreDb,
+        targets: &PortionTargets,
+        portion: PortionFilter,
+    ) -> Result<Vec<FirestoreLibraData>, Error> {
+        if self
+            .action
+            .as_ref()
+            .is_some_and(|action| *action != Action::Served)
+        {
+            return Ok(Vec::new());
+        }
+        // Only ingredients with a target can match
+        let ingredients = match &self.ingredient {
+            Some(_) => None,
+            None => Some(targets.ingredients()).filter(|names| names.len() <= MAX_IN_VALUES),
+        };
+        if ingredients.as_ref().is_some_and(Vec::is_empty) {
+            return Ok(Vec::new());
+        }
+
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut servings = Vec::new();
+        let mut after = None;
+        while servings.len() < limit {
+            let documents = self
+                .fetch_page(db, ingredients.as_deref(), Some(PORTION_PAGE_SIZE), after)
+                .await?;
+            let fetched = documents.len();
+            after = documents
+                .last()
+                .and_then(|document| cursor_after(db, document));
+            let (page, _) = parse_or_skip(documents);
+            servings.extend(page.into_iter().filter(|entry| {
+                entry.data_action == Action::Served
+                    && targets
+                        .get(&entry.location, &entry.ingredient)
+                        .is_some_and(|target| portion.matches(target.check(entry.amount)))
+            }));
+            if fetched < PORTION_PAGE_SIZE {
+                break;
+            }
+            if after.is_none() {
+                tracing::warn!("Stopped paging at a document without a timestamp or id");
+                break;
+            }
+        }
+        servings.truncate(limit);
+        Ok(servings)
+    }
+
+    async fn fetch_page(
+        &self,
+        db: &FirestoreDb,
+        ingredients: Option<&[String]>,
+        limit: Option<usize>,
+        after: Option<FirestoreQueryCursor>,
+    ) -> Result<Vec<Value>, Error> {
+        // Ties on the timestamp are broken by document id, so a cursor can
+        // point between any two documents
         let mut query = db
             .fluent()
             .select()
             .from("libra")
-            .filter(|q| {
-                q.for_all([
-                    self.location
-                        .clone()
-                        .and_then(|v| q.field("location").eq(v)),
-                    self.serial_number
-                        .clone()
-                        .and_then(|v| q.field("serial_number").eq(v)),
-                    self.ingredient
-                        .clone()
-                        .and_then(|v| q.field("ingredient").eq(v)),
-                    self.action.clone().and_then(|v| q.field("action").eq(v)),
-                    self.start_date
-                        .and_then(|v| q.field("timestamp").greater_than_or_equal(v)),
-                    self.end_date
-                        .and_then(|v| q.field("timestamp").less_than_or_equal(v)),
-                ])
-            })
-            .order_by([(
-                "timestamp",
-                match &self.order_by {
-                    Some(OrderBy::Ascending) => FirestoreQueryDirection::Ascending,
-                    _ => FirestoreQueryDirection::Descending,
-                },
-            )]);
-        if let Some(limit) = self.limit {
+            .filter(|q| self.filter(q, ingredients))
+            .order_by([
+                ("timestamp", self.direction()),
+                ("__name__", self.direction()),
+            ]);
+        if let Some(limit) = limit {
             query = query.limit(limit as u32)
         }
-        let documents = query.obj::<Value>().query().await.map_err(FirestoreError)?;
-
-        let valid_data = documents
-            .iter()
-            .filter_map(|data| {
-                if let Ok(value) = serde_json::from_value::<FirestoreLibraData>(data.clone()) {
-                    Some(value)
-                } else {
-                    eprintln!("Ignoring invalid data schema: {data}");
-                    None
+        if let Some(after) = after {
+            query = query.start_at(after)
+        }
+        observe_firestore("run_query", query.obj::<Value>().query())
+            .await
+            .map_err(FirestoreError)
+    }
+
+    fn direction(&self) -> FirestoreQueryDirection {
+        match &self.order_by {
+            Some(OrderBy::Ascending) => FirestoreQueryDirection::Ascending,
+            _ => FirestoreQueryDirection::Descending,
+        }
+    }
+
+    fn filter(
+        &self,
+        q: FirestoreQueryFilterBuilder,
+        ingredients: Option<&[String]>,
+    ) -> Option<FirestoreQueryFilter> {
+        let action = match self.portion {
+            Some(_) => Some(Action::Served),
+            None => self.action.clone(),
+        };
+        q.for_all([
+            self.location
+                .clone()
+                .and_then(|v| q.field("location").eq(v)),
+            self.serial_number
+                .clone()
+                .and_then(|v| q.field("device.serialNumber").eq(v)),
+            self.ingredient
+                .clone()
+                .and_then(|v| q.field("ingredient").eq(v)),
+            ingredients.and_then(|v| q.field("ingredient").is_in(v.to_vec())),
+            action.and_then(|v| q.field("dataAction").eq(v)),
+            self.start_date
+                .and_then(|v| q.field("timestamp").greater_than_or_equal(v)),
+            self.end_date
+                .and_then(|v| q.field("timestamp").less_than_or_equal(v)),
+        ])
+    }
+}
+
+/// Position just after `document` in the order `DataQuery` pages through
+/// `libra`, by timestamp and then document id.
+fn cursor_after(db: &FirestoreDb, document: &Value) -> Option<FirestoreQueryCursor> {
+    let id = document.get("_firestore_id")?.as_str()?;
+    let timestamp = document
+        .get("timestamp")?
+        .as_str()?
+        .parse::<DateTime<Utc>>()
+        .ok()?;
+    Some(FirestoreQueryCursor::AfterValue(vec![
+        FirestoreTimestamp::from(timestamp).into(),
+        FirestoreReference(format!("{}/libra/{id}", db.get_documents_path())).into(),
+    ]))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryQuery {
+    pub location: Option<String>,
+    pub serial_number: Option<String>,
+    pub ingredient: Option<String>,
+    /// Only levels that are, or are not, running low.
+    pub low: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InventoryItem {
+    #[serde(flatten)]
+    pub level: StockLevel,
+    pub low: bool,
+}
+
+impl InventoryQuery {
+    /// Stock levels matching the query, lowest share of the last refill first.
+    pub async fn run_query(
+        &self,
+        db: &FirestoreDb,
+        low_fraction: f64,
+    ) -> Result<Vec<InventoryItem>, Error> {
+        let mut items = fetch_inventory(db)
+            .await?
+            .into_values()
+            .filter(|level| {
+                self.location.as_ref().is_none_or(|v| *v == level.location)
+                    && self
+                        .serial_number
+                        .as_ref()
+                        .is_none_or(|v| *v == level.serial_number)
+                    && self
+                        .ingredient
+                        .as_ref()
+                        .is_none_or(|v| *v == level.ingredient)
+            })
+            .map(|level| InventoryItem {
+                low: level.is_low(low_fraction),
+                level,
+            })
+            .filter(|item| self.low.is_none_or(|low| item.low == low))
+            .collect::<Vec<_>>();
+        let share = |level: &StockLevel| {
+            if level.refilled > 0.0 {
+                level.remaining / level.refilled
+            } else {
+                0.0
+            }
+        };
+        items.sort_by(|a, b| share(&a.level).total_cmp(&share(&b.level)));
+        Ok(items)
+    }
+}
+
+/// Days reported when a query does not say.
+const DEFAULT_REPORT_DAYS: i64 = 7;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UptimeQuery {
+    pub location: Option<String>,
+    pub serial_number: Option<String>,
+    /// First and last UTC date to report, by default the last week.
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UptimeSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    pub location: String,
+    pub date: NaiveDate,
+    pub uptime_percent: Option<f64>,
+    pub outages: usize,
+    pub mttr_secs: Option<f64>,
+}
+
+impl UptimeSummary {
+    fn new(serial_number: Option<String>, day: &DailyUptime) -> Self {
+        UptimeSummary {
+            serial_number,
+            location: day.location.clone(),
+            date: day.date,
+            uptime_percent: day.uptime_percent(),
+            outages: day.outages,
+            mttr_secs: day.mttr_secs(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UptimeReport {
+    pub devices: Vec<UptimeSummary>,
+    /// Devices at the same location added together.
+    pub locations: Vec<UptimeSummary>,
+}
+
+impl UptimeQuery {
+    pub async fn run_query(&self, db: &FirestoreDb) -> Result<UptimeReport, Error> {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = self
+            .from
+            .unwrap_or(to - Duration::days(DEFAULT_REPORT_DAYS - 1));
+        let mut days = fetch_uptime_days(db, from, Some(to))
+            .await?
+            .into_values()
+            .filter(|day| {
+                self.location.as_ref().is_none_or(|v| *v == day.location)
+                    && self
+                        .serial_number
+                        .as_ref()
+                        .is_none_or(|v| *v == day.serial_number)
+            })
+            .collect::<Vec<_>>();
+        days.sort_by(|a, b| {
+            (&a.location, a.date, &a.serial_number).cmp(&(&b.location, b.date, &b.serial_number))
+        });
+
+        let mut locations: Vec<DailyUptime> = Vec::new();
+        for day in &days {
+            match locations.last_mut() {
+                Some(last) if last.location == day.location && last.date == day.date => {
+                    last.merge(day)
                 }
+                _ => locations.push(day.clone()),
+            }
+        }
+        Ok(UptimeReport {
+            devices: days
+                .iter()
+                .map(|day| UptimeSummary::new(Some(day.serial_number.clone()), day))
+                .collect(),
+            locations: locations
+                .iter()
+                .map(|day| UptimeSummary::new(None, day))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StockoutQuery {
+    pub location: Option<String>,
+    pub ingredient: Option<String>,
+    /// Period to report, by default the last week.
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl StockoutQuery {
+    pub async fn run_query(
+        &self,
+        db: &FirestoreDb,
+        hours: &LocationHours,
+    ) -> Result<Vec<LocationStockouts>, Error> {
+        let now = Utc::now();
+        let to = self.to.unwrap_or(now);
+        let from = self
+            .from
+            .unwrap_or(to - Duration::days(DEFAULT_REPORT_DAYS));
+        let stockouts = fetch_stockouts_since(db, from)
+            .await?
+            .into_iter()
+            .filter(|stockout| {
+                self.location
+                    .as_ref()
+                    .is_none_or(|v| *v == stockout.location)
+                    && self
+                        .ingredient
+                        .as_ref()
+                        .is_none_or(|v| *v == stockout.ingredient)
             })
-            .collect();
-        Ok(valid_data)
+            .collect::<Vec<_>>();
+        Ok(summarize_stockouts(&stockouts, hours, from, to, now))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AnomalyQuery {
+    pub location: Option<String>,
+    pub granularity: Option<Granularity>,
+    /// Only anomalies at least this severe.
+    pub severity: Option<Severity>,
+    /// Earliest bucket start, by default a week ago.
+    pub from: Option<DateTime<Utc>>,
+}
+
+impl AnomalyQuery {
+    pub async fn run_query(&self, db: &FirestoreDb) -> Result<Vec<Anomaly>, Error> {
+        let from = self
+            .from
+            .unwrap_or_else(|| Utc::now() - Duration::days(DEFAULT_REPORT_DAYS));
+        Ok(fetch_anomalies(db, from)
+            .await?
+            .into_iter()
+            .filter(|anomaly| {
+                self.location
+                    .as_ref()
+                    .is_none_or(|v| *v == anomaly.location)
+                    && self.granularity.is_none_or(|v| v == anomaly.granularity)
+                    && self.severity.is_none_or(|v| anomaly.severity >= v)
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeatmapQuery {
+    pub location: Option<String>,
+    /// With an ingredient, one chart per location and ingredient; without, one
+    /// per location covering every ingredient.
+    pub ingredient: Option<String>,
+}
+
+impl HeatmapQuery {
+    pub async fn run_query(&self, db: &FirestoreDb) -> Result<Vec<HeatmapChart>, Error> {
+        let mut heatmaps = fetch_heatmaps(db)
+            .await?
+            .into_values()
+            .filter(|heatmap| {
+                self.location
+                    .as_ref()
+                    .is_none_or(|v| *v == heatmap.location)
+                    && self
+                        .ingredient
+                        .as_ref()
+                        .is_none_or(|v| *v == heatmap.ingredient)
+            })
+            .collect::<Vec<_>>();
+        if self.ingredient.is_none() {
+            return Ok(HeatmapChart::by_location(&heatmaps));
+        }
+        heatmaps.sort_by(|a, b| a.location.cmp(&b.location));
+        Ok(heatmaps
+            .into_iter()
+            .map(|heatmap| {
+                let values = heatmap.matrix();
+                HeatmapChart::new(heatmap.location, Some(heatmap.ingredient), values)
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RollupQuery {
+    pub period: Period,
+    pub location: Option<String>,
+    /// Periods overlapping these local dates, by default the last year.
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl RollupQuery {
+    pub async fn run_query(&self, db: &FirestoreDb) -> Result<Vec<Rollup>, Error> {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = self.from.unwrap_or(to - Duration::days(365));
+        let mut rollups = fetch_rollups(db, self.period, from, to)
+            .await?
+            .into_iter()
+            .filter(|rollup| self.location.as_ref().is_none_or(|v| *v == rollup.location))
+            .collect::<Vec<_>>();
+        rollups.sort_by(|a, b| (&a.location, a.start).cmp(&(&b.location, b.start)));
+        Ok(rollups)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PortionQuery {
+    pub location: Option<String>,
+    pub ingredient: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PortionReport {
+    /// Each ingredient over all matching locations.
+    pub ingredients: Vec<PortionSummary>,
+    /// Each ingredient at each location.
+    pub locations: Vec<PortionSummary>,
+}
+
+impl PortionQuery {
+    pub async fn run_query(
+        &self,
+        db: &FirestoreDb,
+        bins: &PortionBins,
+    ) -> Result<PortionReport, Error> {
+        let mut distributions = fetch_portions(db)
+            .await?
+            .into_values()
+            .filter(|distribution| {
+                self.location
+                    .as_ref()
+                    .is_none_or(|v| *v == distribution.location)
+                    && self
+                        .ingredient
+                        .as_ref()
+                        .is_none_or(|v| *v == distribution.ingredient)
+            })
+            .collect::<Vec<_>>();
+        distributions
+            .sort_by(|a, b| (&a.location, &a.ingredient).cmp(&(&b.location, &b.ingredient)));
+        Ok(PortionReport {
+            ingredients: PortionSummary::by_ingredient(&distributions, bins),
+            locations: distributions
+                .iter()
+                .map(|distribution| {
+                    PortionSummary::new(
+                        Some(distribution.location.clone()),
+                        distribution.ingredient.clone(),
+                        &distribution.histogram_in(bins),
+                        &distribution.sketch,
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ComplianceQuery {
+    pub location: Option<String>,
+    pub ingredient: Option<String>,
+    /// Periods the trend is given in, by default days.
+    pub period: Option<Period>,
+    /// First and last local date to report, by default the last week.
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl ComplianceQuery {
+    pub async fn run_query(
+        &self,
+        db: &FirestoreDb,
+        targets: &PortionTargets,
+    ) -> Result<Vec<ComplianceSummary>, Error> {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = self
+            .from
+            .unwrap_or(to - Duration::days(DEFAULT_REPORT_DAYS - 1));
+        let days = fetch_compliance_days(db, from, Some(to))
+            .await?
+            .into_values()
+            .filter(|day| {
+                self.location.as_ref().is_none_or(|v| *v == day.location)
+                    && self
+                        .ingredient
+                        .as_ref()
+                        .is_none_or(|v| *v == day.ingredient)
+            })
+            .collect::<Vec<_>>();
+        Ok(summarize_compliance(
+            &days,
+            targets,
+            self.period.unwrap_or(Period::Day),
+        ))
     }
 }
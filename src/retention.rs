@@ -0,0 +1,296 @@
+use crate::error::Error;
+use crate::firestore::metadata::fetch_metadata;
+use crate::firestore::schema::take_document_id;
+use crate::metrics::observe_firestore;
+use crate::migrate::MAX_BATCH_SIZE;
+use chrono::{DateTime, Duration, Utc};
+use firestore::{FirestoreDb, FirestoreQueryDirection, FirestoreTimestamp};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::StreamExt;
+use menu::action::Action;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How long readings of each action are kept in `libra`. Actions without an
+/// entry are kept forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy(pub Vec<(Action, Duration)>);
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    /// Parses a comma separated list of `Action=days`, like `Heartbeat=30,Served=730`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (action, days) = entry
+                    .trim()
+                    .split_once('=')
+                    .ok_or(format!("Expected Action=days, got {entry}"))?;
+                let action = serde_json::from_value(Value::from(action))
+                    .map_err(|_| format!("Unknown action {action}"))?;
+                let days = days
+                    .parse()
+                    .ok()
+                    .filter(|days| *days > 0)
+                    .ok_or(format!("Retention of {days} days is not a positive number"))?;
+                Ok((action, Duration::days(days)))
+            })
+            .collect::<Result<_, _>>()
+            .map(RetentionPolicy)
+    }
+}
+
+/// Readings older than this are expired. Never later than the watermark, so
+/// readings the aggregates do not include yet are kept however old they are.
+pub fn cutoff(
+    keep_for: Duration,
+    now: DateTime<Utc>,
+    last_processed: DateTime<Utc>,
+) -> DateTime<Utc> {
+    (now - keep_for).min(last_processed)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExpiredReadings {
+    pub action: Action,
+    pub cutoff: DateTime<Utc>,
+    /// Readings archived and deleted, or that would be on a dry run.
+    pub expired: usize,
+    pub archives: Vec<PathBuf>,
+}
+
+/// Writes documents as gzipped JSON lines. The file only appears under `path`
+/// once it is complete and flushed to disk.
+pub fn write_archive(path: &Path, documents: &[Value]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&partial)?),
+        Compression::default(),
+    );
+    for document in documents {
+        serde_json::to_writer(&mut encoder, document)?;
+        encoder.write_all(b"\n")?;
+    }
+    let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(partial, path)?;
+    Ok(())
+}
+
+async fn fetch_expired(
+    db: &FirestoreDb,
+    action: &Action,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<Value>, Error> {
+    let cutoff = FirestoreTimestamp::from(cutoff);
+    let documents = observe_firestore(
+        "fetch_expired_readings",
+        db.fluent()
+            .select()
+            .from("libra")
+            .filter(|q| {
+                q.for_all([
+                    q.field("dataAction").eq(action.clone()),
+                    q.field("timestamp").less_than(cutoff.clone()),
+                ])
+            })
+            .order_by([("timestamp", FirestoreQueryDirection::Ascending)])
+            .limit(MAX_BATCH_SIZE as u32)
+            .obj()
+            .query(),
+    )
+    .await?;
+    Ok(documents)
+}
+
+async fn count_expired(
+    db: &FirestoreDb,
+    action: &Action,
+    cutoff: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let cutoff = FirestoreTimestamp::from(cutoff);
+    let mut documents = db
+        .fluent()
+        .select()
+        .from("libra")
+        .filter(|q| {
+            q.for_all([
+                q.field("dataAction").eq(action.clone()),
+                q.field("timestamp").less_than(cutoff.clone()),
+            ])
+        })
+        .obj::<Value>()
+        .stream_query_with_errors()
+        .await?;
+    let mut count = 0;
+    while let Some(document) = documents.next().await {
+        document?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+async fn delete_readings(db: &FirestoreDb, ids: &[String]) -> Result<(), Error> {
+    let writer = db.create_simple_batch_writer().await?;
+    let mut batch = writer.new_batch();
+    for id in ids {
+        db.fluent()
+            .delete()
+            .from("libra")
+            .document_id(id)
+            .add_to_batch(&mut batch)?;
+    }
+    observe_firestore("delete_expired_readings", batch.write()).await?;
+    Ok(())
+}
+
+/// Archives and deletes the expired readings of one action, a page at a time.
+/// Each page is archived before it is deleted, so a failure in between only
+/// leaves readings that the next run archives again.
+async fn expire_action(
+    db: &FirestoreDb,
+    action: &Action,
+    cutoff: DateTime<Utc>,
+    archive_dir: &Path,
+    run_started: DateTime<Utc>,
+) -> Result<ExpiredReadings, Error> {
+    let name = serde_json::to_value(action)?
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let mut expired = ExpiredReadings {
+        action: action.clone(),
+        cutoff,
+        expired: 0,
+        archives: Vec::new(),
+    };
+    loop {
+        let mut documents = fetch_expired(db, action, cutoff).await?;
+        if documents.is_empty() {
+            return Ok(expired);
+        }
+        let ids = documents
+            .iter_mut()
+            .map(|document| {
+                let id = take_document_id(document).unwrap_or_default();
+                document["id"] = Value::from(id.as_str());
+                id
+            })
+            .collect::<Vec<_>>();
+
+        let path = archive_dir.join(&name).join(format!(
+            "{}-{:04}.jsonl.gz",
+            run_started.format("%Y%m%dT%H%M%SZ"),
+            expired.archives.len()
+        ));
+        write_archive(&path, &documents)?;
+        delete_readings(db, &ids).await?;
+        tracing::info!(
+            action = %name,
+            readings = ids.len(),
+            archive = %path.display(),
+            "Archived expired readings"
+        );
+
+        expired.expired += ids.len();
+        expired.archives.push(path);
+    }
+}
+
+/// Archives readings older than their action's retention to `archive_dir` and
+/// deletes them from `libra`. Only readings behind the aggregation watermark
+/// are touched; before the first aggregation run nothing is. A dry run only
+/// counts them.
+#[tracing::instrument(name = "retention", skip(db, policy))]
+pub async fn apply_retention(
+    db: &FirestoreDb,
+    policy: &RetentionPolicy,
+    archive_dir: &Path,
+    dry_run: bool,
+) -> Result<Vec<ExpiredReadings>, Error> {
+    let Some(metadata) = fetch_metadata(db).await? else {
+        tracing::info!("Nothing has been aggregated yet, keeping every reading");
+        return Ok(Vec::new());
+    };
+    let now = Utc::now();
+
+    let mut report = Vec::with_capacity(policy.0.len());
+    for (action, keep_for) in &policy.0 {
+        let cutoff = cutoff(*keep_for, now, metadata.last_processed.timestamp);
+        let expired = if dry_run {
+            ExpiredReadings {
+                action: action.clone(),
+                cutoff,
+                expired: count_expired(db, action, cutoff).await?,
+                archives: Vec::new(),
+            }
+        } else {
+            expire_action(db, action, cutoff, archive_dir, now).await?
+        };
+        report.push(expired);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn it_parses_retention_per_action() {
+        let policy: RetentionPolicy = "Heartbeat=30, Served=730".parse().unwrap();
+        assert_eq!(
+            policy,
+            RetentionPolicy(vec![
+                (Action::Heartbeat, Duration::days(30)),
+                (Action::Served, Duration::days(730)),
+            ])
+        );
+        assert_eq!("".parse(), Ok(RetentionPolicy(Vec::new())));
+        assert!("Heartbeat".parse::<RetentionPolicy>().is_err());
+        assert!("Sneezed=30".parse::<RetentionPolicy>().is_err());
+        assert!("Heartbeat=0".parse::<RetentionPolicy>().is_err());
+    }
+
+    #[test]
+    fn it_never_expires_readings_past_the_watermark() {
+        let now = Utc::now();
+        let keep_for = Duration::days(30);
+
+        let caught_up = now - Duration::minutes(5);
+        assert_eq!(cutoff(keep_for, now, caught_up), now - keep_for);
+
+        let behind = now - Duration::days(45);
+        assert_eq!(cutoff(keep_for, now, behind), behind);
+    }
+
+    #[test]
+    fn it_writes_archives_as_gzipped_json_lines() {
+        let dir = std::env::temp_dir().join(format!("retention-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("Heartbeat").join("run-0000.jsonl.gz");
+        let documents = vec![json!({ "id": "a", "amount": 0.0 }), json!({ "id": "b" })];
+
+        write_archive(&path, &documents).unwrap();
+
+        let lines = BufReader::new(GzDecoder::new(File::open(&path).unwrap()))
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, documents);
+        assert!(!path.with_extension("partial").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
+
+/// Cooperative shutdown flag shared between the server, the signal listener and
+/// running aggregations. Also tracks background tasks so shutdown can wait for them.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Spawns a task that `drain` waits for before the process exits.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once `trigger` has been called, immediately if it already was.
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+/// Resolves on SIGTERM (sent by Cloud Run on scale-down) or Ctrl+C.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Drives `server` to completion. Once shutdown is triggered the server and any
+/// spawned background tasks have `deadline` to finish; returns `false` if they
+/// were cut off.
+pub async fn drain<F>(server: F, shutdown: &Shutdown, deadline: Duration) -> bool
+where
+    F: Future<Output = ()>,
+{
+    tokio::pin!(server);
+    let server_stopped = tokio::select! {
+        _ = &mut server => true,
+        _ = shutdown.triggered() => false,
+    };
+
+    shutdown.tasks.close();
+    let remaining = async {
+        if !server_stopped {
+            server.await;
+        }
+        shutdown.tasks.wait().await;
+    };
+    tokio::time::timeout(deadline, remaining).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shares_trigger_between_clones() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+
+        shutdown.trigger();
+        assert!(clone.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn it_notifies_waiters_on_trigger() {
+        let shutdown = Shutdown::new();
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter was not notified")
+            .unwrap();
+
+        // Late subscribers see the signal straight away
+        tokio::time::timeout(Duration::from_secs(1), shutdown.triggered())
+            .await
+            .expect("late waiter was not notified");
+    }
+
+    #[tokio::test]
+    async fn it_lets_in_flight_work_finish_within_deadline() {
+        let shutdown = Shutdown::new();
+        let server = async {
+            shutdown.triggered().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        shutdown.trigger();
+
+        assert!(drain(server, &shutdown, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn it_cuts_off_work_past_deadline() {
+        let shutdown = Shutdown::new();
+        let server = tokio::time::sleep(Duration::from_secs(60));
+        shutdown.trigger();
+
+        assert!(!drain(server, &shutdown, Duration::from_millis(50)).await);
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_background_tasks() {
+        let shutdown = Shutdown::new();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        shutdown.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send(()).unwrap();
+        });
+        shutdown.trigger();
+
+        assert!(drain(async {}, &shutdown, Duration::from_secs(1)).await);
+        assert!(receiver.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_cuts_off_background_tasks_past_deadline() {
+        let shutdown = Shutdown::new();
+        shutdown.spawn(tokio::time::sleep(Duration::from_secs(60)));
+        shutdown.trigger();
+
+        assert!(!drain(async {}, &shutdown, Duration::from_millis(50)).await);
+    }
+
+    #[tokio::test]
+    async fn it_runs_server_until_it_stops_on_its_own() {
+        let shutdown = Shutdown::new();
+        let server = tokio::time::sleep(Duration::from_millis(10));
+
+        assert!(drain(server, &shutdown, Duration::ZERO).await);
+        assert!(!shutdown.is_triggered());
+    }
+}
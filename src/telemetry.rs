@@ -0,0 +1,275 @@
+use crate::config::otlp_endpoint;
+use crate::error::Error;
+use chrono::{SecondsFormat, Utc};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const SERVICE_NAME: &str = "data-aggregation";
+
+// warp's own trace events fire before the request id is known, so keep them quiet
+// unless RUST_LOG asks for them
+const DEFAULT_FILTER: &str = "info,warp::filters::trace=warn";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Keeps the trace pipeline alive; call `shutdown` before exiting so buffered
+/// spans are exported.
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!(error = %e, "Failed to flush traces");
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber, writing one JSON object per line to stdout in
+/// the shape Cloud Logging understands. Verbosity is controlled by `RUST_LOG`.
+/// Spans are also exported over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() -> Result<Telemetry, Error> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let tracer_provider = otlp_endpoint()
+        .map(|endpoint| tracer_provider(&endpoint))
+        .transpose()?;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json_layer())
+        .with(tracer_provider.as_ref().map(otel_layer))
+        .init();
+
+    Ok(Telemetry { tracer_provider })
+}
+
+/// Batches spans and ships them to the collector at `endpoint`, e.g. `http://localhost:4318`.
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, Error> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build())
+}
+
+pub fn otel_layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+}
+
+fn json_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .fmt_fields(JsonFields::new())
+        .event_format(CloudLogging)
+}
+
+/// Span wrapping a single HTTP request; `request_id` is filled in by [`request_id`].
+pub fn request_span(info: warp::trace::Info<'_>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %info.method(),
+        path = %info.path(),
+        request_id = tracing::field::Empty,
+    )
+}
+
+/// Extracts the caller's `x-request-id`, or generates one, and records it on the
+/// current request span so every log line and Firestore call below carries it.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(REQUEST_ID_HEADER).map(|header: Option<String>| {
+        let id = resolve_request_id(header);
+        Span::current().record("request_id", id.as_str());
+        id
+    })
+}
+
+fn resolve_request_id(header: Option<String>) -> String {
+    header
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn severity(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARNING",
+        Level::INFO => "INFO",
+        Level::DEBUG | Level::TRACE => "DEBUG",
+    }
+}
+
+/// Formats events as flat JSON with a Cloud Logging `severity`, merging in the
+/// fields of every enclosing span.
+struct CloudLogging;
+
+impl<S, N> FormatEvent<S, N> for CloudLogging
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut entry = Map::new();
+        entry.insert("severity".into(), severity(metadata.level()).into());
+        entry.insert(
+            "time".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        entry.insert("target".into(), metadata.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                spans.push(span.name());
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str(&fields.fields) {
+                        entry.extend(fields);
+                    }
+                }
+            }
+            entry.insert("spans".into(), spans.join(":").into());
+        }
+
+        event.record(&mut JsonVisitor(&mut entry));
+        writeln!(writer, "{}", Value::Object(entry))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(f: impl FnOnce()) -> Vec<Value> {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(CloudLogging)
+                .with_writer(move || writer.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_writes_json_with_severity_and_span_fields() {
+        let lines = capture(|| {
+            let span = tracing::info_span!("request", request_id = tracing::field::Empty);
+            let _entered = span.enter();
+            span.record("request_id", "abc-123");
+            tracing::warn!(entries = 3, "Something looks off");
+        });
+
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!(line["severity"], "WARNING");
+        assert_eq!(line["message"], "Something looks off");
+        assert_eq!(line["entries"], 3);
+        assert_eq!(line["request_id"], "abc-123");
+        assert_eq!(line["spans"], "request");
+    }
+
+    #[tokio::test]
+    async fn it_keeps_caller_request_ids() {
+        let id = warp::test::request()
+            .header(REQUEST_ID_HEADER, "abc")
+            .filter(&request_id())
+            .await
+            .unwrap();
+        assert_eq!(id, "abc");
+    }
+
+    #[test]
+    fn it_generates_missing_or_unusable_request_ids() {
+        let generated = resolve_request_id(None);
+        assert!(Uuid::parse_str(&generated).is_ok());
+
+        let too_long = "x".repeat(MAX_REQUEST_ID_LEN + 1);
+        assert_ne!(resolve_request_id(Some(too_long.clone())), too_long);
+        assert_ne!(resolve_request_id(Some(String::new())), "");
+    }
+}
@@ -1,12 +1,42 @@
+// The seed helpers print the unit results of their inserts
+#![allow(clippy::let_unit_value)]
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use data_aggregation::anomalies;
+use data_aggregation::devices::{check_stale_devices, device_statuses};
 use data_aggregation::error::Error;
 use data_aggregation::firestore::client::{
-    process_aggregations, read_locations, FirestoreDevice, LocationData,
+    process_aggregations, read_locations, AggregationProgress, FirestoreDevice, FirestoreLibraData,
+    LocationData,
+};
+use data_aggregation::firestore::clocks::{device_skew, DeviceSkew};
+use data_aggregation::firestore::jobs::JobState;
+use data_aggregation::firestore::lease::{acquire_named_lease, release_named_lease, LeaseGuard};
+use data_aggregation::firestore::metadata::fetch_metadata;
+use data_aggregation::firestore::quarantine::{fetch_quarantined, list_quarantined};
+use data_aggregation::import::{import, read_rows, ColumnMapping, Format, ImportOptions};
+use data_aggregation::ingest::{ingest, RecordStatus};
+use data_aggregation::jobs::{get_job, start_aggregation_job};
+use data_aggregation::migrate::migrate_libra;
+use data_aggregation::processing::anomaly::Granularity;
+use data_aggregation::processing::compliance::PortionFilter;
+use data_aggregation::processing::rollup::Period;
+use data_aggregation::pubsub::{handle_push, handle_push_body, PushEnvelope, PushStatus};
+use data_aggregation::quarantine::{fix, replay};
+use data_aggregation::query::{
+    AnomalyQuery, DataQuery, HeatmapQuery, InventoryQuery, OrderBy, PortionQuery, RollupQuery,
+    StockoutQuery, UptimeQuery,
 };
+use data_aggregation::shutdown::Shutdown;
 use firestore::FirestoreDb;
 use menu::action::Action;
 use menu::device::{Device, Model};
 use menu::libra_data::LibraData;
+use serde_json::json;
+use std::time::Duration;
 use time::OffsetDateTime;
+use tokio::sync::watch;
 
 async fn seed_libra_data(db: &FirestoreDb) -> Result<(), Error> {
     let data = vec![
@@ -72,6 +102,68 @@ async fn seed_libra_data(db: &FirestoreDb) -> Result<(), Error> {
     Ok(())
 }
 
+/// Aggregation runs, jobs, imports and replays all take the aggregation lease,
+/// so tests doing so take turns rather than fail with `Error::LeaseHeld`. They
+/// hold their turn for the whole test, so no other run moves the watermark
+/// between writing their readings and checking the results.
+static LEASE_TURN: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Runs one aggregation under a lease of its own; the caller holds `LEASE_TURN`.
+async fn run_aggregation(
+    db: &FirestoreDb,
+    shutdown: &Shutdown,
+) -> Result<AggregationProgress, Error> {
+    let lease = LeaseGuard::acquire(db, &format!("test-{}", uuid::Uuid::new_v4())).await?;
+    let (progress, _) = watch::channel(AggregationProgress::default());
+    let result = process_aggregations(db, &lease, shutdown, &progress).await;
+    lease.release(db).await?;
+    result
+}
+
+fn reading(
+    serial_number: &str,
+    location: &str,
+    ingredient: &str,
+    data_action: Action,
+    amount: f64,
+    timestamp: OffsetDateTime,
+) -> LibraData {
+    LibraData {
+        device: Device {
+            model: Model::LibraV0,
+            serial_number: serial_number.into(),
+        },
+        location: location.into(),
+        ingredient: ingredient.into(),
+        data_action,
+        amount,
+        timestamp,
+    }
+}
+
+/// Writes `readings` to `libra` under their content ids, as ingest would, with
+/// an arrival time of now when `received` is set.
+async fn insert_readings(
+    db: &FirestoreDb,
+    readings: impl IntoIterator<Item = LibraData>,
+    received: bool,
+) -> Result<(), Error> {
+    for reading in readings {
+        let mut reading = FirestoreLibraData::from(reading);
+        if received {
+            reading.received_at = Some(chrono::Utc::now());
+        }
+        db.fluent()
+            .insert()
+            .into("libra")
+            .document_id(reading.content_id())
+            .object(&reading)
+            .execute::<()>()
+            .await?;
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_aggregation() -> Result<(), Error> {
     // Try to load .env and see if it succeeds
@@ -89,8 +181,136 @@ async fn test_aggregation() -> Result<(), Error> {
 
     let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
 
+    let _turn = LEASE_TURN.lock().await;
+    seed_libra_data(&db).await?;
+    run_aggregation(&db, &Shutdown::new()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aggregation_job_reports_progress() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let _turn = LEASE_TURN.lock().await;
+    seed_libra_data(&db).await?;
+    let job = start_aggregation_job(&db, &Shutdown::new()).await?;
+    assert_eq!(job.state, JobState::Queued);
+
+    let mut finished = None;
+    for _ in 0..50 {
+        let job = get_job(&db, &job.id).await?.expect("job record exists");
+        if job.is_finished() {
+            finished = Some(job);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let job = finished.expect("job finished");
+    assert_eq!(job.state, JobState::Succeeded, "{:?}", job.error);
+    assert!(job.progress.entries_fetched >= 4);
+    assert_eq!(
+        job.progress.entries_processed,
+        job.progress.entries_fetched - job.progress.duplicates_dropped
+    );
+    assert!(job.duration_ms.is_some());
+
+    // The job gives the lease up just after recording its result; wait for that
+    // before letting other tests take it
+    for _ in 0..50 {
+        match LeaseGuard::acquire(&db, "test-job-finished").await {
+            Ok(lease) => return lease.release(&db).await,
+            Err(Error::LeaseHeld { .. }) => tokio::time::sleep(Duration::from_millis(100)).await,
+            Err(e) => return Err(e),
+        }
+    }
+    panic!("job did not release the lease");
+}
+
+#[tokio::test]
+async fn test_aggregation_stops_at_safe_point_on_shutdown() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let _turn = LEASE_TURN.lock().await;
     seed_libra_data(&db).await?;
-    process_aggregations(&db).await?;
+    // A reading from a slow clock, so the run saves a clock estimate before the
+    // safe point
+    let serial_number = format!("shutdown-test-{}", uuid::Uuid::new_v4());
+    let slow = reading(
+        &serial_number,
+        "Caldo HQ",
+        "Popcorn",
+        Action::Served,
+        10.0,
+        OffsetDateTime::now_utc() - time::Duration::minutes(10),
+    );
+    insert_readings(&db, [slow], true).await?;
+    let before = fetch_metadata(&db)
+        .await?
+        .map(|metadata| metadata.last_processed.timestamp);
+
+    let shutdown = Shutdown::new();
+    shutdown.trigger();
+    let result = run_aggregation(&db, &shutdown).await;
+    assert!(matches!(result, Err(Error::ShuttingDown)));
+
+    // No aggregate was written, so the watermark must not have moved
+    let after = fetch_metadata(&db)
+        .await?
+        .map(|metadata| metadata.last_processed.timestamp);
+    assert_eq!(before, after);
+
+    // The next run saves the clock estimate again, which changes nothing
+    let estimate = |devices: Vec<DeviceSkew>| {
+        devices
+            .into_iter()
+            .find(|device| device.clock.serial_number == serial_number)
+            .map(|device| (device.clock.offset_ms, device.clock.samples))
+    };
+    let threshold = chrono::Duration::minutes(5);
+    let saved = estimate(device_skew(&db, threshold).await?);
+    assert!(saved.is_some());
+    run_aggregation(&db, &Shutdown::new()).await?;
+    assert_eq!(estimate(device_skew(&db, threshold).await?), saved);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lease_rejects_competing_runs() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    // A lease of its own, so the test does not hold up aggregation runs
+    let document = format!("lease-test-{}", uuid::Uuid::new_v4());
+    let lease = acquire_named_lease(&db, &document, "test-run-a").await?;
+    let competing = acquire_named_lease(&db, &document, "test-run-b").await;
+    assert!(matches!(competing, Err(Error::LeaseHeld { ref owner, .. }) if owner == "test-run-a"));
+
+    // Renewing keeps the original acquisition time
+    let renewed = acquire_named_lease(&db, &document, "test-run-a").await?;
+    assert_eq!(renewed.acquired_at, lease.acquired_at);
+    assert!(renewed.expires_at >= lease.expires_at);
+
+    release_named_lease(&db, &document, "test-run-a").await?;
+    acquire_named_lease(&db, &document, "test-run-b").await?;
+    release_named_lease(&db, &document, "test-run-b").await?;
     Ok(())
 }
 
@@ -160,3 +380,935 @@ async fn test_locations() -> Result<(), Error> {
     read_locations(&db).await?;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_ingest_reports_per_record_results() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    seed_locations(&db).await?;
+    let reading = |serial_number: &str, amount: f64| {
+        json!({
+            "device": { "model": "LibraV0", "serial_number": serial_number },
+            "location": "Caldo HQ",
+            "ingredient": "Popcorn",
+            "data_action": "Served",
+            "amount": amount,
+            "timestamp": "2025-06-01T12:00:00Z",
+        })
+    };
+    let readings = vec![
+        reading("000-0", 12.0),
+        reading("unregistered", 12.0),
+        reading("000-1", -3.0),
+    ];
+
+    let response = ingest(&db, readings, chrono::Duration::minutes(5)).await?;
+    // The emulator may already hold the valid reading from an earlier run
+    assert_eq!(response.accepted + response.duplicates, 1);
+    assert_eq!((response.rejected, response.failed), (2, 0));
+    assert!(response.results[0].id.is_some());
+    assert_eq!(response.results[1].status, RecordStatus::Rejected);
+    assert_eq!(response.results[2].status, RecordStatus::Rejected);
+
+    // Retrying the same reading lands on the same content-derived document
+    let retried = ingest(
+        &db,
+        vec![reading("000-0", 12.0)],
+        chrono::Duration::minutes(5),
+    )
+    .await?;
+    assert_eq!(retried.results[0].status, RecordStatus::Duplicate);
+    assert_eq!(retried.results[0].id, response.results[0].id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_filters_by_serial_number_and_action() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let serial_number = format!("data-filter-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    for (serial_number, action, minutes_ago) in [
+        (serial_number.as_str(), Action::Served, 3),
+        (serial_number.as_str(), Action::Refilled, 2),
+        (serial_number.as_str(), Action::Served, 1),
+        ("data-filter-other", Action::Served, 1),
+    ] {
+        let reading = FirestoreLibraData::from(LibraData {
+            device: Device {
+                model: Model::LibraV0,
+                serial_number: serial_number.into(),
+            },
+            location: "Caldo HQ".into(),
+            ingredient: "Popcorn".into(),
+            data_action: action,
+            amount: 30.0,
+            timestamp: now - time::Duration::minutes(minutes_ago),
+        });
+        db.fluent()
+            .insert()
+            .into("libra")
+            .document_id(reading.content_id())
+            .object(&reading)
+            .execute::<()>()
+            .await?;
+    }
+
+    // Filters go by the stored field paths, `device.serialNumber` and `dataAction`
+    let query = DataQuery {
+        location: None,
+        serial_number: Some(serial_number.clone()),
+        ingredient: None,
+        action: Some(Action::Served),
+        order_by: None,
+        start_date: None,
+        end_date: None,
+        limit: None,
+        portion: None,
+    };
+    let served = query.run_query(&db, &Default::default()).await?;
+    assert_eq!(served.len(), 2);
+    assert!(served.iter().all(|entry| {
+        entry.device.serial_number == serial_number && entry.data_action == Action::Served
+    }));
+    Ok(())
+}
+
+fn pubsub_fixture(name: &str) -> PushEnvelope {
+    let path = format!(
+        "{}/tests/fixtures/pubsub/{name}.json",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_pubsub_redelivery_is_idempotent() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    seed_locations(&db).await?;
+    let skew = chrono::Duration::minutes(5);
+
+    // The emulator may already hold this message from an earlier run
+    let first = handle_push(&db, pubsub_fixture("reading"), skew).await?;
+    assert!(matches!(
+        first.status,
+        PushStatus::Stored | PushStatus::Duplicate
+    ));
+    let redelivered = handle_push(&db, pubsub_fixture("reading"), skew).await?;
+    assert_eq!(redelivered.status, PushStatus::Duplicate);
+
+    let rejected = handle_push(&db, pubsub_fixture("malformed_data"), skew).await?;
+    assert_eq!(rejected.status, PushStatus::Rejected);
+
+    // Bodies that are not envelopes are acknowledged too, so they are not redelivered
+    let undecodable = handle_push_body(&db, b"not an envelope", skew).await?;
+    assert_eq!(undecodable.status, PushStatus::Rejected);
+    assert!(undecodable.message_id.is_none());
+    Ok(())
+}
+
+fn push_envelope(message_id: &str, reading: &serde_json::Value) -> PushEnvelope {
+    serde_json::from_value(json!({
+        "message": {
+            "data": STANDARD.encode(reading.to_string()),
+            "messageId": message_id,
+        },
+        "subscription": "projects/back-of-house-backend/subscriptions/libra-readings-push",
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_pubsub_counts_a_republished_reading_once() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    seed_locations(&db).await?;
+    let skew = chrono::Duration::minutes(5);
+    let location = format!("pubsub-test-{}", uuid::Uuid::new_v4());
+    let reading = json!({
+        "device": { "model": "LibraV0", "serial_number": "000-0" },
+        "location": location,
+        "ingredient": "Popcorn",
+        "data_action": "Served",
+        "amount": 42.5,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+    });
+
+    // The device publishes the same reading again in a new message after a run
+    let first = handle_push(&db, push_envelope(&format!("{location}-1"), &reading), skew).await?;
+    assert_eq!(first.status, PushStatus::Stored);
+    run_aggregation(&db, &Shutdown::new()).await?;
+    let second = handle_push(&db, push_envelope(&format!("{location}-2"), &reading), skew).await?;
+    assert_eq!(second.status, PushStatus::Duplicate);
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let query = RollupQuery {
+        period: Period::Day,
+        location: Some(location),
+        from: None,
+        to: None,
+    };
+    let rollups = query.run_query(&db).await?;
+    assert_eq!(rollups.iter().map(|rollup| rollup.served).sum::<usize>(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_documents_are_quarantined_fixed_and_replayed() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    seed_locations(&db).await?;
+    let mut document = json!({
+        "device": { "model": "LibraV0", "serialNumber": "000-0" },
+        "location": "Caldo HQ",
+        "ingredient": "Popcorn",
+        "dataAction": "Served",
+        "amount": "12.5g",
+        "timestamp": "2025-06-01T12:00:00Z",
+    });
+    // Arrived now, so the next aggregation run fetches it
+    #[derive(serde::Serialize)]
+    struct Arrived<'a> {
+        #[serde(flatten)]
+        document: &'a serde_json::Value,
+        #[serde(rename = "receivedAt", with = "firestore::serialize_as_timestamp")]
+        received_at: chrono::DateTime<chrono::Utc>,
+    }
+    db.fluent()
+        .update()
+        .in_col("libra")
+        .document_id("quarantine-test")
+        .object(&Arrived {
+            document: &document,
+            received_at: chrono::Utc::now(),
+        })
+        .execute::<()>()
+        .await?;
+
+    // The query still succeeds and leaves the bad document where it is
+    let query = DataQuery {
+        location: Some("Caldo HQ".to_string()),
+        serial_number: None,
+        ingredient: None,
+        action: None,
+        order_by: None,
+        start_date: None,
+        end_date: None,
+        limit: None,
+        portion: None,
+    };
+    query.run_query(&db, &Default::default()).await?;
+    assert!(fetch_quarantined(&db, "quarantine-test").await?.is_none());
+
+    // The aggregation run moves it out of libra
+    run_aggregation(&db, &Shutdown::new()).await?;
+    let record = fetch_quarantined(&db, "quarantine-test")
+        .await?
+        .expect("document was quarantined");
+    assert_eq!(record.source, "libra");
+    assert!(!record.error.is_empty());
+    assert!(list_quarantined(&db, 100)
+        .await?
+        .iter()
+        .any(|record| record.id == "quarantine-test"));
+
+    // Replaying before fixing is refused
+    assert!(matches!(
+        replay(&db, "quarantine-test").await,
+        Err(Error::InvalidDocument(_))
+    ));
+
+    document["amount"] = json!(12.5);
+    let fixed = fix(&db, "quarantine-test", document)
+        .await?
+        .expect("record exists");
+    assert!(fixed.fixed_at.is_some());
+
+    let replayed = replay(&db, "quarantine-test")
+        .await?
+        .expect("record exists");
+    assert_eq!(replayed.id.len(), 64);
+    assert!(fetch_quarantined(&db, "quarantine-test").await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migration_rewrites_legacy_documents() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let legacy = json!({
+        "serialNumber": "000-1",
+        "location": "Caldo HQ",
+        "ingredient": "Popcorn",
+        "action": "Served",
+        "amount": 8.0,
+        "timestamp": "2025-06-01T12:00:00Z",
+    });
+    db.fluent()
+        .update()
+        .in_col("libra")
+        .document_id("legacy-reading")
+        .object(&legacy)
+        .execute::<()>()
+        .await?;
+
+    let dry_run = migrate_libra(&db, 10, true).await?;
+    assert!(dry_run.migrated >= 1);
+
+    migrate_libra(&db, 10, false).await?;
+    let migrated: FirestoreLibraData = db
+        .fluent()
+        .select()
+        .by_id_in("libra")
+        .obj()
+        .one("legacy-reading")
+        .await?
+        .expect("document still exists");
+    assert_eq!(migrated.device.serial_number, "000-1");
+    assert_eq!(migrated.schema_version, 1);
+
+    let again = migrate_libra(&db, 10, true).await?;
+    assert_eq!(again.migrated, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_writes_new_readings_once() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    seed_locations(&db).await?;
+    let csv = "\
+serialNumber,location,ingredient,dataAction,amount,timestamp
+000-0,Caldo HQ,Popcorn,Served,7.5,2024-02-01 09:15:00
+000-0,Caldo HQ,Popcorn,Served,7.5,2024-02-01 09:15:00
+unregistered,Caldo HQ,Popcorn,Served,7.5,2024-02-01 09:15:00
+";
+    let mut options = ImportOptions {
+        mapping: ColumnMapping::default(),
+        timezone: chrono_tz::America::Los_Angeles,
+        batch_size: 10,
+        dry_run: true,
+        max_future_skew: chrono::Duration::minutes(5),
+    };
+
+    let dry_run = import(&db, read_rows(Format::Csv, csv.as_bytes())?, &options).await?;
+    assert_eq!(dry_run.rows, 3);
+    assert_eq!(dry_run.rejected.len(), 1);
+    assert_eq!(dry_run.rejected[0].line, 4);
+    // The emulator may already hold the reading from an earlier run
+    assert_eq!(dry_run.imported + dry_run.duplicates, 2);
+
+    options.dry_run = false;
+    import(&db, read_rows(Format::Csv, csv.as_bytes())?, &options).await?;
+    let again = import(&db, read_rows(Format::Csv, csv.as_bytes())?, &options).await?;
+    assert_eq!((again.imported, again.duplicates), (0, 2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_leaves_inventory_alone() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    seed_locations(&db).await?;
+    // Sets a watermark, so the imported reading is older than it
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let ingredient = format!("import-test-{}", uuid::Uuid::new_v4());
+    let csv = format!(
+        "\
+serialNumber,location,ingredient,dataAction,amount,timestamp
+000-0,Caldo HQ,{ingredient},Refilled,500,2024-02-01 09:20:00
+"
+    );
+    let options = ImportOptions {
+        mapping: ColumnMapping::default(),
+        timezone: chrono_tz::America::Los_Angeles,
+        batch_size: 10,
+        dry_run: false,
+        max_future_skew: chrono::Duration::minutes(5),
+    };
+    let report = import(&db, read_rows(Format::Csv, csv.as_bytes())?, &options).await?;
+    assert_eq!(report.backfilled, 1);
+
+    // A refill from before the scale's current level does not set it
+    let query = InventoryQuery {
+        location: None,
+        serial_number: Some("000-0".into()),
+        ingredient: Some(ingredient),
+        low: None,
+    };
+    assert!(query.run_query(&db, 0.2).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_devices_with_dead_clocks_are_flagged() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    // A scale whose RTC reset to 1970, with the arrival time the server recorded
+    let serial_number = format!("dead-rtc-{}", uuid::Uuid::new_v4());
+    let readings = [reading(
+        &serial_number,
+        "Caldo HQ",
+        "Popcorn",
+        Action::Served,
+        9.0,
+        OffsetDateTime::UNIX_EPOCH,
+    )];
+    insert_readings(&db, readings, true).await?;
+
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let devices = device_skew(&db, chrono::Duration::minutes(5)).await?;
+    let dead = devices
+        .iter()
+        .find(|device| device.clock.serial_number == serial_number)
+        .expect("estimated the clock offset");
+    assert!(dead.skewed);
+    assert!(dead.clock.offset_ms > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inventory_follows_refills_and_servings() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    let now = OffsetDateTime::now_utc();
+    let readings = [
+        (Action::Refilled, 800.0, 10),
+        (Action::Served, 150.0, 8),
+        (Action::Served, 50.0, 6),
+    ]
+    .map(|(action, amount, minutes_ago)| {
+        reading(
+            "inventory-test",
+            "Caldo HQ",
+            "Almonds",
+            action,
+            amount,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, true).await?;
+
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let query = InventoryQuery {
+        location: None,
+        serial_number: Some("inventory-test".into()),
+        ingredient: Some("Almonds".into()),
+        low: None,
+    };
+    let items = query.run_query(&db, 0.2).await?;
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].level.remaining, 600.0);
+    assert_eq!(items[0].level.refilled, 800.0);
+    assert!(!items[0].low);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uptime_counts_outages_and_recovery() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    // A fresh device each run, so earlier runs' intervals do not count
+    let serial_number = format!("uptime-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings = [
+        (Action::Starting, 12),
+        (Action::Offline, 10),
+        (Action::Starting, 4),
+    ]
+    .map(|(action, minutes_ago)| {
+        reading(
+            &serial_number,
+            "Caldo HQ",
+            "Almonds",
+            action,
+            0.0,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, true).await?;
+
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let query = UptimeQuery {
+        location: None,
+        serial_number: Some(serial_number),
+        from: None,
+        to: None,
+    };
+    let report = query.run_query(&db).await?;
+    let outages = report.devices.iter().map(|day| day.outages).sum::<usize>();
+    assert_eq!(outages, 1);
+    assert!(report
+        .devices
+        .iter()
+        .any(|day| day.mttr_secs == Some(360.0)));
+    assert!(report
+        .devices
+        .iter()
+        .all(|day| day.uptime_percent.is_some_and(|percent| percent < 100.0)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_device_check_records_heartbeats() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    seed_locations(&db).await?;
+
+    let heartbeat = reading(
+        "000-0",
+        "Caldo HQ",
+        "Almonds",
+        Action::Heartbeat,
+        0.0,
+        OffsetDateTime::now_utc(),
+    );
+    insert_readings(&db, [heartbeat], true).await?;
+
+    let report = check_stale_devices(&db, chrono::Duration::minutes(30), None).await?;
+    assert!(report.devices >= 4);
+    assert_eq!(report.alerts_sent, 0);
+
+    let live = device_statuses(&db, Some(false)).await?;
+    let status = live
+        .iter()
+        .find(|status| status.serial_number == "000-0")
+        .expect("device with a fresh heartbeat is live");
+    assert_eq!(status.location, "Caldo HQ");
+    assert!(status.last_heartbeat.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stockouts_resolve_across_runs() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    let location = format!("stockout-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    for (action, minutes_ago) in [(Action::RanOut, 30), (Action::Refilled, 10)] {
+        let ran_out = reading(
+            "stockout-test",
+            &location,
+            "Almonds",
+            action,
+            0.0,
+            now - time::Duration::minutes(minutes_ago),
+        );
+        insert_readings(&db, [ran_out], true).await?;
+
+        // One run per reading, so the stockout is left open by the first
+        run_aggregation(&db, &Shutdown::new()).await?;
+    }
+
+    let query = StockoutQuery {
+        location: Some(location),
+        ingredient: None,
+        from: None,
+        to: None,
+    };
+    let summary = query.run_query(&db, &Default::default()).await?;
+    assert_eq!(summary.len(), 1);
+    assert_eq!(summary[0].stockouts, 1);
+    assert_eq!(summary[0].unresolved, 0);
+    assert_eq!(summary[0].p50_minutes, Some(20.0));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_anomalies_flag_a_scale_that_stopped_serving() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    // Twenty servings in the same hour of each of the last three weeks, and none
+    // in that hour this week
+    let location = format!("anomaly-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let hour = (now - time::Duration::hours(2))
+        .replace_minute(0)
+        .unwrap()
+        .replace_second(0)
+        .unwrap()
+        .replace_nanosecond(0)
+        .unwrap();
+    let served = |timestamp| {
+        reading(
+            "anomaly-test",
+            &location,
+            "Almonds",
+            Action::Served,
+            30.0,
+            timestamp,
+        )
+    };
+    let readings = (1..=3).flat_map(|week| {
+        (0..20)
+            .map(move |minute| hour - time::Duration::weeks(week) + time::Duration::minutes(minute))
+    });
+    insert_readings(&db, readings.map(served), false).await?;
+
+    let report = anomalies::detect(&db).await?;
+    assert!(report.detected >= 1);
+
+    let query = AnomalyQuery {
+        location: Some(location.clone()),
+        granularity: None,
+        severity: None,
+        from: None,
+    };
+    let found = query.run_query(&db).await?;
+    assert!(found
+        .iter()
+        .any(|anomaly| anomaly.count == 0 && anomaly.baseline == 20.0 && anomaly.score < 0.0));
+    // Without a webhook nothing was delivered, so later runs still try
+    assert!(found.iter().all(|anomaly| !anomaly.alerted));
+
+    // The hour's servings arrive late, so its anomaly no longer holds
+    let readings = (0..20).map(|minute| hour + time::Duration::minutes(minute));
+    insert_readings(&db, readings.map(served), false).await?;
+    let report = anomalies::detect(&db).await?;
+    assert!(report.resolved >= 1);
+    let found = query.run_query(&db).await?;
+    let hourly = found
+        .iter()
+        .find(|anomaly| {
+            anomaly.granularity == Granularity::Hourly
+                && anomaly.start.timestamp() == hour.unix_timestamp()
+        })
+        .expect("the hour's anomaly is kept");
+    assert!(hourly.resolved);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heatmap_counts_serves_per_ingredient() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    let location = format!("heatmap-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings =
+        [("Popcorn", 3), ("Popcorn", 2), ("Almonds", 1)].map(|(ingredient, minutes_ago)| {
+            reading(
+                "heatmap-test",
+                &location,
+                ingredient,
+                Action::Served,
+                40.0,
+                now - time::Duration::minutes(minutes_ago),
+            )
+        });
+    insert_readings(&db, readings, true).await?;
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let query = HeatmapQuery {
+        location: Some(location.clone()),
+        ingredient: None,
+    };
+    let charts = query.run_query(&db).await?;
+    assert_eq!(charts.len(), 1);
+    assert_eq!(charts[0].values.len(), 7);
+    assert_eq!(charts[0].values.iter().flatten().sum::<usize>(), 3);
+
+    let query = HeatmapQuery {
+        location: Some(location),
+        ingredient: Some("Popcorn".into()),
+    };
+    let charts = query.run_query(&db).await?;
+    assert_eq!(charts.len(), 1);
+    assert_eq!(charts[0].ingredient.as_deref(), Some("Popcorn"));
+    assert_eq!(charts[0].values.iter().flatten().sum::<usize>(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rollups_sum_servings_per_period() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    let location = format!("rollup-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings = [(3, 40.0), (2, 60.0)].map(|(minutes_ago, amount)| {
+        reading(
+            "rollup-test",
+            &location,
+            "Popcorn",
+            Action::Served,
+            amount,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, true).await?;
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    // Weeks, months and years that start before `from` still overlap it
+    let first = (now - time::Duration::minutes(3)).date();
+    let first = chrono::NaiveDate::from_ymd_opt(
+        first.year(),
+        u8::from(first.month()).into(),
+        first.day().into(),
+    );
+    for period in Period::ALL {
+        for from in [None, first] {
+            let query = RollupQuery {
+                period,
+                location: Some(location.clone()),
+                from,
+                to: None,
+            };
+            let rollups = query.run_query(&db).await?;
+            assert_eq!(rollups.iter().map(|rollup| rollup.served).sum::<usize>(), 2);
+            assert_eq!(
+                rollups.iter().map(|rollup| rollup.amount).sum::<f64>(),
+                100.0
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_portions_report_distribution_per_ingredient() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+    let _turn = LEASE_TURN.lock().await;
+
+    let location = format!("portion-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings = [(4, 20.0), (3, 40.0), (2, 60.0)].map(|(minutes_ago, amount)| {
+        reading(
+            "portion-test",
+            &location,
+            "Popcorn",
+            Action::Served,
+            amount,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, true).await?;
+    run_aggregation(&db, &Shutdown::new()).await?;
+
+    let query = PortionQuery {
+        location: Some(location),
+        ingredient: None,
+    };
+    let report = query.run_query(&db, &"25,50".parse().unwrap()).await?;
+    assert_eq!(report.locations.len(), 1);
+    let popcorn = &report.ingredients[0];
+    assert_eq!(popcorn.count, 3);
+    assert_eq!(popcorn.mean, Some(40.0));
+    assert_eq!(popcorn.min, Some(20.0));
+    assert_eq!(
+        popcorn.bins.iter().map(|bin| bin.count).collect::<Vec<_>>(),
+        vec![1, 1, 1]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_filters_servings_outside_their_target() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let location = format!("compliance-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings = [(4, 50.0), (3, 70.0), (2, 30.0)].map(|(minutes_ago, amount)| {
+        reading(
+            "compliance-test",
+            &location,
+            "Popcorn",
+            Action::Served,
+            amount,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, false).await?;
+
+    let targets = "Popcorn=50:5".parse().unwrap();
+    let query = |portion| DataQuery {
+        location: Some(location.clone()),
+        serial_number: None,
+        ingredient: None,
+        action: None,
+        order_by: Some(OrderBy::Ascending),
+        start_date: None,
+        end_date: None,
+        limit: Some(1),
+        portion: Some(portion),
+    };
+    let over = query(PortionFilter::Over).run_query(&db, &targets).await?;
+    assert_eq!(over.len(), 1);
+    assert_eq!(over[0].amount, 70.0);
+    // The limit applies to the readings that match
+    let outside = query(PortionFilter::Outside)
+        .run_query(&db, &targets)
+        .await?;
+    assert_eq!(outside.len(), 1);
+    assert_eq!(outside[0].amount, 70.0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_filters_portions_by_serial_number() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let db = FirestoreDb::new("back-of-house-backend".to_string()).await?;
+
+    let location = format!("compliance-serial-test-{}", uuid::Uuid::new_v4());
+    let now = OffsetDateTime::now_utc();
+    let readings = [
+        ("compliance-serial-a", 3, 70.0),
+        ("compliance-serial-b", 2, 80.0),
+        ("compliance-serial-b", 1, 50.0),
+    ]
+    .map(|(serial_number, minutes_ago, amount)| {
+        reading(
+            serial_number,
+            &location,
+            "Popcorn",
+            Action::Served,
+            amount,
+            now - time::Duration::minutes(minutes_ago),
+        )
+    });
+    insert_readings(&db, readings, false).await?;
+
+    let query = DataQuery {
+        location: Some(location.clone()),
+        serial_number: Some("compliance-serial-b".into()),
+        ingredient: None,
+        action: None,
+        order_by: None,
+        start_date: None,
+        end_date: None,
+        limit: None,
+        portion: Some(PortionFilter::Outside),
+    };
+    let outside = query
+        .run_query(&db, &"Popcorn=50:5".parse().unwrap())
+        .await?;
+    assert_eq!(outside.len(), 1);
+    assert_eq!(outside[0].device.serial_number, "compliance-serial-b");
+    assert_eq!(outside[0].amount, 80.0);
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use data_aggregation::error::Error;
+use data_aggregation::telemetry::{otel_layer, tracer_provider};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing_subscriber::layer::SubscriberExt;
+use warp::Filter;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spans_are_exported_to_collector() -> Result<(), Error> {
+    // Stand-in for a local OTLP/HTTP collector that records each export request
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let collector = warp::path!("v1" / "traces")
+        .and(warp::post())
+        .and(warp::header::<String>("content-type"))
+        .and(warp::body::bytes())
+        .map(
+            move |content_type: String, body: warp::hyper::body::Bytes| {
+                sender.send((content_type, body.len())).ok();
+                warp::reply()
+            },
+        );
+    let (address, server) = warp::serve(collector).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let provider = tracer_provider(&format!("http://{address}"))?;
+
+    // The exporter uses a blocking HTTP client, so keep it off the async workers
+    let flushed = tokio::task::spawn_blocking(move || {
+        let subscriber = tracing_subscriber::registry().with(otel_layer(&provider));
+        tracing::subscriber::with_default(subscriber, || {
+            let run = tracing::info_span!("aggregation_run", entries_processed = 4);
+            run.in_scope(|| {
+                tracing::info_span!("aggregate", aggregator = "actions").in_scope(|| {});
+            });
+        });
+        provider.force_flush()
+    })
+    .await
+    .expect("flush task panicked");
+    assert!(flushed.is_ok(), "flush failed: {flushed:?}");
+
+    let (content_type, size) = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .expect("collector received no spans")
+        .expect("collector channel closed");
+    assert_eq!(content_type, "application/x-protobuf");
+    assert!(size > 0);
+    Ok(())
+}